@@ -0,0 +1,270 @@
+use crate::{find_stop, FinishReason, InferenceRequest, InferenceResponse};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::io::BufRead;
+use std::path::Path;
+
+/// One line of a batch input file. A bare JSON string is shorthand for
+/// `{"prompt": "..."}`; unset fields fall back to the command-line settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BatchItem {
+    #[serde(default)]
+    pub id: Option<Value>,
+    pub prompt: String,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub repetition_penalty: Option<f32>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub eos_token_id: Option<i64>,
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+}
+
+impl BatchItem {
+    pub fn request(&self, base: &InferenceRequest, prompt: String) -> InferenceRequest {
+        InferenceRequest {
+            prompt,
+            max_tokens: self.max_tokens.unwrap_or(base.max_tokens),
+            temperature: self.temperature.unwrap_or(base.temperature),
+            top_k: self.top_k.or(base.top_k),
+            top_p: self.top_p.or(base.top_p),
+            repetition_penalty: self.repetition_penalty.unwrap_or(base.repetition_penalty),
+            seed: self.seed.or(base.seed),
+            eos_token_id: self.eos_token_id.or(base.eos_token_id),
+            stop: self.stop.clone().unwrap_or_else(|| base.stop.clone()),
+            ..base.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub prompt_tokens: Option<usize>,
+    #[serde(default)]
+    pub completion_tokens: Option<usize>,
+    #[serde(default)]
+    pub cached_tokens: Option<usize>,
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    pub elapsed_ms: u64,
+    #[serde(default)]
+    pub tokens_per_second: Option<f64>,
+}
+
+impl BatchResult {
+    pub fn success(
+        index: usize,
+        item: &BatchItem,
+        text: String,
+        stop: &[String],
+        response: &InferenceResponse,
+        elapsed_ms: u64,
+    ) -> Self {
+        // Backends that cannot stop early still honour stop sequences here.
+        let text = match find_stop(&text, stop) {
+            Some(offset) => text[..offset].to_string(),
+            None => text,
+        };
+        let tokens_per_second = response
+            .completion_tokens
+            .filter(|_| elapsed_ms > 0)
+            .map(|tokens| tokens as f64 * 1000.0 / elapsed_ms as f64);
+        Self {
+            index,
+            id: item.id.clone(),
+            prompt: item.prompt.clone(),
+            text: Some(text),
+            error: None,
+            prompt_tokens: response.prompt_tokens,
+            completion_tokens: response.completion_tokens,
+            cached_tokens: response.cached_tokens,
+            finish_reason: response.finish_reason,
+            elapsed_ms,
+            tokens_per_second,
+        }
+    }
+
+    pub fn failure(index: usize, item: &BatchItem, error: String, elapsed_ms: u64) -> Self {
+        Self {
+            index,
+            id: item.id.clone(),
+            prompt: item.prompt.clone(),
+            text: None,
+            error: Some(error),
+            prompt_tokens: None,
+            completion_tokens: None,
+            cached_tokens: None,
+            finish_reason: None,
+            elapsed_ms,
+            tokens_per_second: None,
+        }
+    }
+}
+
+/// Reads batch items, skipping blank lines. Items are numbered in file
+/// order, which is the `index` written to results.
+pub fn read_batch_items(reader: impl BufRead) -> Result<Vec<BatchItem>> {
+    let mut items = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid JSON on line {}", number + 1))?;
+        let item = match value {
+            Value::String(prompt) => BatchItem {
+                prompt,
+                ..BatchItem::default()
+            },
+            Value::Object(_) => serde_json::from_value(value)
+                .with_context(|| format!("Invalid batch item on line {}", number + 1))?,
+            _ => bail!("Line {} must be a JSON string or object", number + 1),
+        };
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// What an earlier run already wrote to a batch output file.
+#[derive(Debug, Default)]
+pub struct BatchProgress {
+    /// Indices with a successful result. Failed items, and items cut short
+    /// by Ctrl-C or `--max-time`, are left out so they are retried.
+    pub completed: HashSet<usize>,
+    /// Length of the file up to its last complete line. Anything after it is
+    /// a partial line from an interrupted run.
+    pub complete_len: u64,
+    pub file_len: u64,
+}
+
+impl BatchProgress {
+    pub fn has_partial_line(&self) -> bool {
+        self.complete_len < self.file_len
+    }
+}
+
+/// Reads the results already in `path` without modifying it. A missing file
+/// means nothing is done yet.
+pub fn read_progress(path: &Path) -> Result<BatchProgress> {
+    if !path.exists() {
+        return Ok(BatchProgress::default());
+    }
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let complete_len = data.rfind('\n').map_or(0, |pos| pos + 1);
+    let mut completed = HashSet::new();
+    for (number, line) in data[..complete_len].lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result: BatchResult = serde_json::from_str(line).with_context(|| {
+            format!("Line {} of {} is not a batch result", number + 1, path.display())
+        })?;
+        let interrupted = matches!(
+            result.finish_reason,
+            Some(FinishReason::Cancelled | FinishReason::Timeout)
+        );
+        if result.error.is_none() && !interrupted {
+            completed.insert(result.index);
+        }
+    }
+    Ok(BatchProgress {
+        completed,
+        complete_len: complete_len as u64,
+        file_len: data.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn output_file(contents: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "llm-toy-batch-{}-{}.jsonl",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn line(index: usize, extra: &str) -> String {
+        format!("{{\"index\":{index},\"prompt\":\"p\",\"elapsed_ms\":1{extra}}}\n")
+    }
+
+    #[test]
+    fn missing_file_has_nothing_done() {
+        let path = std::env::temp_dir().join("llm-toy-batch-missing.jsonl");
+        let progress = read_progress(&path).unwrap();
+        assert!(progress.completed.is_empty());
+        assert!(!progress.has_partial_line());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn partial_trailing_line_is_ignored_and_kept() {
+        let done = line(0, ",\"text\":\"ok\",\"finish_reason\":\"stop\"");
+        let contents = format!("{done}{{\"index\":1,\"pro");
+        let path = output_file(&contents);
+        let progress = read_progress(&path).unwrap();
+        assert_eq!(progress.completed, HashSet::from([0]));
+        assert!(progress.has_partial_line());
+        assert_eq!(progress.complete_len, done.len() as u64);
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failed_items_are_retried() {
+        let contents = line(0, ",\"text\":\"ok\"") + &line(1, ",\"error\":\"boom\"");
+        let path = output_file(&contents);
+        let progress = read_progress(&path).unwrap();
+        assert_eq!(progress.completed, HashSet::from([0]));
+        assert!(!progress.has_partial_line());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn cancelled_and_timed_out_items_are_retried() {
+        let contents = line(0, ",\"text\":\"a\",\"finish_reason\":\"length\"")
+            + &line(1, ",\"text\":\"b\",\"finish_reason\":\"cancelled\"")
+            + &line(2, ",\"text\":\"c\",\"finish_reason\":\"timeout\"");
+        let path = output_file(&contents);
+        let progress = read_progress(&path).unwrap();
+        assert_eq!(progress.completed, HashSet::from([0]));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_complete_lines_are_an_error() {
+        let path = output_file("not json\n");
+        assert!(read_progress(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use serde::Serialize;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Stats {
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    pub fn from_samples(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Some(Self {
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: percentile(&sorted, 0.50),
+            p95: percentile(&sorted, 0.95),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+// Nearest-rank percentile over already sorted samples.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MemoryUsage {
+    pub rss_bytes: u64,
+    pub peak_rss_bytes: u64,
+}
+
+/// Resident and peak memory of this process, where the OS exposes it
+/// (`/proc/self/status` on Linux).
+pub fn process_memory() -> Option<MemoryUsage> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|kb| kb.parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    Some(MemoryUsage {
+        rss_bytes: field("VmRSS:")?,
+        peak_rss_bytes: field("VmHWM:")?,
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchRow {
+    pub backend: String,
+    pub model: String,
+    pub threads: Option<usize>,
+    pub prompt_tokens: usize,
+    pub gen_tokens: usize,
+    pub repetitions: usize,
+    pub load_ms: f64,
+    pub prefill_tokens_per_sec: Option<Stats>,
+    pub decode_tokens_per_sec: Option<Stats>,
+    pub memory: Option<MemoryUsage>,
+}
+
+pub fn render_table(rows: &[BenchRow]) -> String {
+    let stats = |stats: Option<Stats>| match stats {
+        Some(stats) => format!("{:.1} / {:.1} / {:.1}", stats.mean, stats.p50, stats.p95),
+        None => "-".to_string(),
+    };
+    let mb = |bytes: u64| format!("{:.0}", bytes as f64 / (1024.0 * 1024.0));
+    let header = [
+        "backend",
+        "threads",
+        "prompt",
+        "gen",
+        "prefill tok/s (mean/p50/p95)",
+        "decode tok/s (mean/p50/p95)",
+        "rss MB",
+        "peak MB",
+    ];
+    let body: Vec<[String; 8]> = rows
+        .iter()
+        .map(|row| {
+            [
+                row.backend.clone(),
+                row.threads
+                    .map(|threads| threads.to_string())
+                    .unwrap_or_else(|| "default".to_string()),
+                row.prompt_tokens.to_string(),
+                row.gen_tokens.to_string(),
+                stats(row.prefill_tokens_per_sec),
+                stats(row.decode_tokens_per_sec),
+                row.memory.map(|m| mb(m.rss_bytes)).unwrap_or_else(|| "-".to_string()),
+                row.memory
+                    .map(|m| mb(m.peak_rss_bytes))
+                    .unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &body {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut out = String::new();
+    let mut line = |cells: &[&str]| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        let _ = writeln!(out, "{}", padded.join("  ").trim_end());
+    };
+    line(&header);
+    for row in &body {
+        line(&row.each_ref().map(String::as_str));
+    }
+    out
+}
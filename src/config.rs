@@ -0,0 +1,314 @@
+use crate::embedding::Pooling;
+use crate::memory::TruncationStrategy;
+use crate::OptimizationLevel;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const PROJECT_CONFIG_FILE: &str = "llm-toy.toml";
+pub const PROFILE_ENV: &str = "LLM_TOY_PROFILE";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(skip)]
+    pub name: Option<String>,
+    pub model: Option<String>,
+    pub model_url: Option<String>,
+    pub tokenizer: Option<String>,
+    pub tokenizer_url: Option<String>,
+    pub backend: Option<String>,
+    pub backend_preference: Option<Vec<String>>,
+    pub input_name: Option<String>,
+    pub output_name: Option<String>,
+    pub eos_token_id: Option<i64>,
+    pub chat_template: Option<String>,
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub seed: Option<u64>,
+    pub stop: Option<Vec<String>>,
+    /// Seconds.
+    pub max_time: Option<f64>,
+    pub context_length: Option<usize>,
+    pub truncation: Option<TruncationStrategy>,
+    pub summarize: Option<bool>,
+    pub summary_tokens: Option<usize>,
+    pub retrieval: Option<bool>,
+    pub embedding_model: Option<String>,
+    pub embedding_tokenizer: Option<String>,
+    pub embedding_pooling: Option<Pooling>,
+    pub retrieval_top_k: Option<usize>,
+    pub intra_threads: Option<usize>,
+    pub inter_threads: Option<usize>,
+    pub optimization_level: Option<OptimizationLevel>,
+    pub optimized_model_path: Option<String>,
+    pub memory_pattern: Option<bool>,
+    pub cpu_arena: Option<bool>,
+    pub mmap: Option<bool>,
+    pub execution_providers: Option<Vec<String>>,
+    pub prefix_cache_mb: Option<usize>,
+}
+
+impl Profile {
+    fn merge(&mut self, other: Profile) {
+        macro_rules! take {
+            ($($field:ident),*) => {
+                $(if other.$field.is_some() {
+                    self.$field = other.$field;
+                })*
+            };
+        }
+        take!(
+            model,
+            model_url,
+            tokenizer,
+            tokenizer_url,
+            backend,
+            backend_preference,
+            input_name,
+            output_name,
+            eos_token_id,
+            chat_template,
+            max_tokens,
+            temperature,
+            top_k,
+            top_p,
+            repetition_penalty,
+            seed,
+            stop,
+            max_time,
+            context_length,
+            truncation,
+            summarize,
+            summary_tokens,
+            retrieval,
+            embedding_model,
+            embedding_tokenizer,
+            embedding_pooling,
+            retrieval_top_k,
+            intra_threads,
+            inter_threads,
+            optimization_level,
+            optimized_model_path,
+            memory_pattern,
+            cpu_arena,
+            mmap,
+            execution_providers,
+            prefix_cache_mb
+        );
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        let paths = [
+            &mut self.model,
+            &mut self.tokenizer,
+            &mut self.embedding_model,
+            &mut self.embedding_tokenizer,
+        ];
+        for path in paths.into_iter().flatten() {
+            if path.contains("://") {
+                continue;
+            }
+            if Path::new(path.as_str()).is_relative() {
+                *path = base.join(path.as_str()).to_string_lossy().to_string();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    pub fn user_config_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("llm-toy").join("config.toml"))
+    }
+
+    pub fn project_config_path() -> PathBuf {
+        PathBuf::from(PROJECT_CONFIG_FILE)
+    }
+
+    /// Loads the user config and then the project config from the working
+    /// directory, with project profiles overriding user profiles field by field.
+    pub fn load() -> Result<Self> {
+        let mut paths = Vec::new();
+        if let Some(path) = Self::user_config_path() {
+            paths.push(path);
+        }
+        paths.push(Self::project_config_path());
+        Self::load_from(&paths)
+    }
+
+    pub fn load_from(paths: &[PathBuf]) -> Result<Self> {
+        let mut config = Config::default();
+        for path in paths {
+            if path.exists() {
+                config.merge(Self::from_file(path)?);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        let mut config: Config = toml::from_str(&data)
+            .with_context(|| format!("Failed to parse config {}", path.display()))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for profile in config.profiles.values_mut() {
+            profile.resolve_paths(base);
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, other: Config) {
+        if other.default_profile.is_some() {
+            self.default_profile = other.default_profile;
+        }
+        for (name, profile) in other.profiles {
+            self.profiles.entry(name).or_default().merge(profile);
+        }
+    }
+
+    /// Picks the named profile, falling back to `LLM_TOY_PROFILE` and then
+    /// `default_profile`. Without any of those an empty profile is returned.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        self.select_profile(name, std::env::var(PROFILE_ENV).ok().as_deref())
+    }
+
+    fn select_profile(&self, name: Option<&str>, env_name: Option<&str>) -> Result<Profile> {
+        let name = name.or(env_name).or(self.default_profile.as_deref());
+
+        let Some(name) = name else {
+            return Ok(Profile::default());
+        };
+
+        match self.profiles.get(name) {
+            Some(profile) => Ok(Profile {
+                name: Some(name.to_string()),
+                ..profile.clone()
+            }),
+            None if self.profiles.is_empty() => {
+                bail!("Unknown profile '{name}': no profiles are configured")
+            }
+            None => bail!(
+                "Unknown profile '{name}' (available: {})",
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
+pub fn apply_chat_template(template: &str, prompt: &str) -> String {
+    template.replace("{prompt}", prompt)
+}
+
+/// The reply in `answer`: the text after the last `Assistant:` marker, with
+/// an echoed `original_prompt` removed.
+pub fn clean_answer(original_prompt: &str, answer: &str) -> String {
+    let mut out = answer.trim().to_string();
+    if let Some(idx) = out.rfind("Assistant:") {
+        out = out[idx + "Assistant:".len()..].trim_start().to_string();
+    }
+    if out.starts_with(original_prompt) {
+        out = out[original_prompt.len()..].trim_start().to_string();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("llm-toy-config-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn layered(dir: &TempDir) -> Config {
+        let user = dir.write(
+            "user/config.toml",
+            r#"
+            default_profile = "laptop"
+
+            [profiles.laptop]
+            backend = "cpu"
+            intra_threads = 4
+            max_tokens = 64
+
+            [profiles.npu]
+            backend = "amd-xdna"
+            "#,
+        );
+        let project = dir.write(
+            "project/llm-toy.toml",
+            r#"
+            [profiles.laptop]
+            intra_threads = 2
+            model = "models/tiny.onnx"
+            "#,
+        );
+        Config::load_from(&[user, project]).unwrap()
+    }
+
+    #[test]
+    fn project_config_overrides_user_config_field_by_field() {
+        let dir = TempDir::new("layers");
+        let profile = layered(&dir).select_profile(None, None).unwrap();
+        assert_eq!(profile.name.as_deref(), Some("laptop"));
+        assert_eq!(profile.backend.as_deref(), Some("cpu"));
+        assert_eq!(profile.max_tokens, Some(64));
+        assert_eq!(profile.intra_threads, Some(2));
+        let model = dir.0.join("project").join("models/tiny.onnx");
+        assert_eq!(profile.model, Some(model.to_string_lossy().to_string()));
+    }
+
+    #[test]
+    fn environment_overrides_default_profile_and_flag_overrides_both() {
+        let dir = TempDir::new("selection");
+        let config = layered(&dir);
+        let from_env = config.select_profile(None, Some("npu")).unwrap();
+        assert_eq!(from_env.backend.as_deref(), Some("amd-xdna"));
+        let from_flag = config.select_profile(Some("laptop"), Some("npu")).unwrap();
+        assert_eq!(from_flag.backend.as_deref(), Some("cpu"));
+    }
+
+    #[test]
+    fn unknown_profiles_list_the_available_ones() {
+        let dir = TempDir::new("unknown");
+        let error = layered(&dir).select_profile(Some("gpu"), None).unwrap_err();
+        assert!(error.to_string().contains("available: laptop, npu"), "{error}");
+        let error = Config::default().select_profile(None, Some("gpu")).unwrap_err();
+        assert!(error.to_string().contains("no profiles are configured"), "{error}");
+    }
+}
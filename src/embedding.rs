@@ -0,0 +1,621 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::SessionOptions;
+
+#[cfg(feature = "cpu")]
+use crate::{build_session, load_tokenizer, model_files::check_model_files, runtime, CpuBackend};
+#[cfg(feature = "cpu")]
+use ndarray::{s, ArrayView3, Axis};
+#[cfg(feature = "cpu")]
+use ort::{session::Session, value::DynValue};
+#[cfg(feature = "cpu")]
+use tokenizers::Tokenizer;
+
+const INDEX_MAGIC: &[u8; 4] = b"LTVI";
+const INDEX_VERSION: u32 = 1;
+
+#[cfg(feature = "cpu")]
+const HIDDEN_STATE_OUTPUTS: [&str; 2] = ["last_hidden_state", "token_embeddings"];
+#[cfg(feature = "cpu")]
+const POOLED_OUTPUTS: [&str; 2] = ["sentence_embedding", "pooler_output"];
+
+pub trait Embedder {
+    fn embed(&mut self, texts: &[&str]) -> Result<Vec<Vec<f32>>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pooling {
+    #[default]
+    Mean,
+    Cls,
+}
+
+impl FromStr for Pooling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mean" => Ok(Self::Mean),
+            "cls" => Ok(Self::Cls),
+            _ => bail!("Unknown pooling '{s}' (expected mean or cls)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddingOptions {
+    pub pooling: Pooling,
+    pub normalize: bool,
+    pub max_length: usize,
+}
+
+impl Default for EmbeddingOptions {
+    fn default() -> Self {
+        Self {
+            pooling: Pooling::Mean,
+            normalize: true,
+            max_length: 512,
+        }
+    }
+}
+
+/// Sentence embeddings from an ONNX encoder export (e.g. sentence-transformers).
+#[cfg(feature = "cpu")]
+pub struct OnnxEmbedder {
+    session: Session,
+    tokenizer: Tokenizer,
+    options: EmbeddingOptions,
+}
+
+#[cfg(feature = "cpu")]
+impl OnnxEmbedder {
+    pub fn load(model_path: &Path, tokenizer_path: &Path) -> Result<Self> {
+        Self::load_with(model_path, tokenizer_path, EmbeddingOptions::default())
+    }
+
+    pub fn load_with(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        options: EmbeddingOptions,
+    ) -> Result<Self> {
+        Self::load_with_session(model_path, tokenizer_path, options, &SessionOptions::default())
+    }
+
+    /// Builds the session with the same threads, providers and optimization
+    /// level as the CPU backend. `optimized_model_path` is ignored, since it
+    /// names the generator's optimized graph.
+    pub fn load_with_session(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        options: EmbeddingOptions,
+        session: &SessionOptions,
+    ) -> Result<Self> {
+        check_model_files(model_path)?;
+        runtime::init(runtime::CPU_ORT_DLL)?;
+        let session = SessionOptions {
+            optimized_model_path: None,
+            ..session.clone()
+        };
+        let session = build_session(model_path, &session)?;
+        let tokenizer = load_tokenizer(tokenizer_path)?;
+        Ok(Self {
+            session,
+            tokenizer,
+            options,
+        })
+    }
+
+    pub fn options(&self) -> EmbeddingOptions {
+        self.options
+    }
+
+    fn output_name(&self) -> Result<String> {
+        let outputs = self.session.outputs();
+        let find = |names: &[&str]| {
+            outputs
+                .iter()
+                .map(|outlet| outlet.name())
+                .find(|name| names.contains(name))
+        };
+        // Hidden states come first so the requested pooling is honoured; an
+        // already-pooled output is only used when the export has nothing else.
+        find(&HIDDEN_STATE_OUTPUTS)
+            .or_else(|| find(&POOLED_OUTPUTS))
+            .or_else(|| outputs.first().map(|outlet| outlet.name()))
+            .map(str::to_string)
+            .context("Embedding model has no outputs")
+    }
+}
+
+#[cfg(feature = "cpu")]
+impl OnnxEmbedder {
+    /// Inputs for a batch of token rows. Encoders count positions from the
+    /// first token and have no cache, so rows are right-padded and the
+    /// padding is masked out through `attention_mask`.
+    fn batch_inputs(&self, rows: &[Vec<i64>]) -> Result<Vec<(String, DynValue)>> {
+        let batch = rows.len();
+        let seq_len = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let pad_id = self.tokenizer.get_padding().map_or(0, |padding| i64::from(padding.pad_id));
+        // `real` gives the value at each token position, `pad` fills the rest.
+        let rows_of = |real: &dyn Fn(&[i64], usize) -> i64, pad: i64| {
+            rows.iter()
+                .flat_map(|row| (0..seq_len).map(move |i| if i < row.len() { real(row, i) } else { pad }))
+                .collect::<Vec<i64>>()
+        };
+        let mut inputs = Vec::new();
+        for outlet in self.session.inputs() {
+            let name = outlet.name();
+            let Some((ty, shape)) = CpuBackend::tensor_meta(outlet.dtype()) else {
+                continue;
+            };
+            let data = if name == "input_ids" {
+                rows_of(&|row, i| row[i], pad_id)
+            } else if name.contains("attention_mask") {
+                rows_of(&|_, _| 1, 0)
+            } else if name.contains("position_ids") {
+                rows_of(&|_, i| i as i64, 0)
+            } else if name.contains("token_type_ids") {
+                rows_of(&|_, _| 0, 0)
+            } else {
+                continue;
+            };
+            let shape = CpuBackend::token_shape(name, &shape, batch, seq_len)?;
+            inputs.push((name.to_string(), CpuBackend::build_int_tensor(name, ty, shape, data)?));
+        }
+        Ok(inputs)
+    }
+
+    // One forward pass over `rows`, pooled into one vector per row.
+    fn embed_rows(&mut self, rows: &[Vec<i64>], output_name: &str) -> Result<Vec<Vec<f32>>> {
+        let inputs = self.batch_inputs(rows)?;
+        let outputs = self.session.run(inputs)?;
+        let hidden = outputs[output_name].try_extract_array::<f32>()?;
+        let lengths: Vec<usize> = rows.iter().map(Vec::len).collect();
+        let mut vectors = match hidden.ndim() {
+            3 => pool(hidden.into_dimensionality()?, &lengths, self.options.pooling)?,
+            2 if self.options.pooling == Pooling::Cls => bail!(
+                "Embedding output '{output_name}' is already pooled, so CLS pooling cannot be \
+                 applied; use mean pooling for this model"
+            ),
+            2 => hidden.outer_iter().map(|row| row.iter().copied().collect()).collect(),
+            rank => bail!("Unsupported embedding output rank {rank}"),
+        };
+        if vectors.len() != rows.len() {
+            bail!("Embedding model returned {} vectors for {} texts", vectors.len(), rows.len());
+        }
+        if self.options.normalize {
+            vectors.iter_mut().for_each(|vector| normalize(vector));
+        }
+        Ok(vectors)
+    }
+}
+
+#[cfg(feature = "cpu")]
+impl Embedder for OnnxEmbedder {
+    /// Embeds `texts` in one forward pass. Models without an
+    /// `attention_mask` input cannot mask padding, so they get one pass
+    /// per text.
+    fn embed(&mut self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let output_name = self.output_name()?;
+        let mut rows = Vec::with_capacity(texts.len());
+        for text in texts {
+            let encoding = self
+                .tokenizer
+                .encode(*text, true)
+                .map_err(|e| anyhow::anyhow!("Failed to tokenize text for embedding: {e}"))?;
+            let mut ids: Vec<i64> = encoding.get_ids().iter().map(|id| *id as i64).collect();
+            ids.truncate(self.options.max_length);
+            if ids.is_empty() {
+                bail!("Cannot embed empty text");
+            }
+            rows.push(ids);
+        }
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let masks = self
+            .session
+            .inputs()
+            .iter()
+            .any(|input| input.name().contains("attention_mask"));
+        if masks {
+            return self.embed_rows(&rows, &output_name);
+        }
+        let mut vectors = Vec::with_capacity(rows.len());
+        for row in rows {
+            vectors.extend(self.embed_rows(std::slice::from_ref(&row), &output_name)?);
+        }
+        Ok(vectors)
+    }
+}
+
+/// Pools `[batch, seq, hidden]` states into one vector per row, looking only
+/// at the first `lengths[row]` positions so right padding is ignored.
+#[cfg(feature = "cpu")]
+fn pool(hidden: ArrayView3<f32>, lengths: &[usize], pooling: Pooling) -> Result<Vec<Vec<f32>>> {
+    hidden
+        .outer_iter()
+        .zip(lengths)
+        .map(|(states, &len)| {
+            let states = states.slice(s![..len.min(states.len_of(Axis(0))), ..]);
+            let vector = match pooling {
+                Pooling::Mean => states.mean_axis(Axis(0)).context("Embedding output has no tokens")?,
+                Pooling::Cls => states
+                    .outer_iter()
+                    .next()
+                    .context("Embedding output has no tokens")?
+                    .to_owned(),
+            };
+            Ok(vector.to_vec())
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "cpu"))]
+pub struct OnnxEmbedder;
+
+#[cfg(not(feature = "cpu"))]
+impl OnnxEmbedder {
+    pub fn load(_model_path: &Path, _tokenizer_path: &Path) -> Result<Self> {
+        bail!("embedding models require the 'cpu' feature")
+    }
+
+    pub fn load_with(
+        _model_path: &Path,
+        _tokenizer_path: &Path,
+        _options: EmbeddingOptions,
+    ) -> Result<Self> {
+        bail!("embedding models require the 'cpu' feature")
+    }
+
+    pub fn load_with_session(
+        _model_path: &Path,
+        _tokenizer_path: &Path,
+        _options: EmbeddingOptions,
+        _session: &SessionOptions,
+    ) -> Result<Self> {
+        bail!("embedding models require the 'cpu' feature")
+    }
+
+    pub fn options(&self) -> EmbeddingOptions {
+        EmbeddingOptions::default()
+    }
+}
+
+#[cfg(not(feature = "cpu"))]
+impl Embedder for OnnxEmbedder {
+    fn embed(&mut self, _texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        bail!("embedding models require the 'cpu' feature")
+    }
+}
+
+pub fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for v in vector.iter_mut() {
+            *v /= norm;
+        }
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+// FNV-1a, used to notice when the text behind a stored vector has changed.
+pub fn text_hash(text: &str) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in text.as_bytes() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedVector {
+    pub id: u64,
+    pub text_hash: u64,
+    pub vector: Vec<f32>,
+}
+
+/// File-backed flat vector index. Vectors are keyed by a caller-chosen id
+/// (the memory entry position for conversation memory).
+#[derive(Debug, Clone, Default)]
+pub struct VectorIndex {
+    pub model: String,
+    pub dim: usize,
+    pub entries: Vec<IndexedVector>,
+}
+
+impl VectorIndex {
+    pub fn new(model: impl Into<String>) -> Self {
+        Self {
+            model: model.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&IndexedVector> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    pub fn insert(&mut self, id: u64, text_hash: u64, vector: Vec<f32>) -> Result<()> {
+        if self.dim == 0 {
+            self.dim = vector.len();
+        } else if vector.len() != self.dim {
+            bail!(
+                "Embedding has {} dimensions but the index uses {}",
+                vector.len(),
+                self.dim
+            );
+        }
+        let entry = IndexedVector {
+            id,
+            text_hash,
+            vector,
+        };
+        match self.entries.iter_mut().find(|existing| existing.id == id) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+        Ok(())
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&IndexedVector) -> bool) {
+        self.entries.retain(f);
+    }
+
+    /// Returns up to `k` `(id, score)` pairs, most similar first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(u64, f32)> {
+        let mut scored: Vec<(u64, f32)> = self
+            .entries
+            .iter()
+            .map(|entry| (entry.id, cosine_similarity(query, &entry.vector)))
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(k);
+        scored
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = fs::File::open(path)
+            .with_context(|| format!("Failed to open vector index {}", path.display()))?;
+        let len = file.metadata()?.len();
+        let index = Self::read_from(&mut BufReader::new(file), len)
+            .with_context(|| format!("Vector index {} is corrupt", path.display()))?;
+        Ok(Some(index))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        let mut writer = BufWriter::new(fs::File::create(&tmp_path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to write vector index {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Reads an index from `reader`, which holds `len` bytes. Every size in
+    /// the header is checked against `len` before anything is allocated.
+    fn read_from(reader: &mut impl Read, len: u64) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            bail!("Not a vector index");
+        }
+        let version = read_u32(reader)?;
+        if version != INDEX_VERSION {
+            bail!("Unsupported vector index version {version}");
+        }
+        let model_len = u64::from(read_u32(reader)?);
+        // Magic, version, model name length, dimension and count.
+        let header_len = 4 + 4 + 4 + model_len + 4 + 8;
+        if header_len > len {
+            bail!("Header is longer than the file");
+        }
+        let mut model = vec![0u8; model_len as usize];
+        reader.read_exact(&mut model)?;
+        let dim = u64::from(read_u32(reader)?);
+        let count = read_u64(reader)?;
+        let entries_len = dim
+            .checked_mul(4)
+            .and_then(|vector| vector.checked_add(16))
+            .and_then(|entry| entry.checked_mul(count));
+        if entries_len != Some(len - header_len) {
+            bail!("{count} vectors of dimension {dim} do not match the file size");
+        }
+        let (dim, count) = (dim as usize, count as usize);
+
+        let mut entries = Vec::with_capacity(count);
+        let mut buf = vec![0u8; dim * 4];
+        for _ in 0..count {
+            let id = read_u64(reader)?;
+            let text_hash = read_u64(reader)?;
+            reader.read_exact(&mut buf)?;
+            let vector = buf
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect();
+            entries.push(IndexedVector {
+                id,
+                text_hash,
+                vector,
+            });
+        }
+
+        Ok(Self {
+            model: String::from_utf8_lossy(&model).into_owned(),
+            dim,
+            entries,
+        })
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_all(&INDEX_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.model.len() as u32).to_le_bytes())?;
+        writer.write_all(self.model.as_bytes())?;
+        writer.write_all(&(self.dim as u32).to_le_bytes())?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for entry in &self.entries {
+            writer.write_all(&entry.id.to_le_bytes())?;
+            writer.write_all(&entry.text_hash.to_le_bytes())?;
+            for value in &entry.vector {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes vectors as a little-endian float32 NumPy array of shape
+/// `(vectors.len(), dim)`.
+pub fn write_npy(writer: &mut impl Write, vectors: &[Vec<f32>]) -> Result<()> {
+    let dim = vectors.first().map_or(0, Vec::len);
+    if vectors.iter().any(|vector| vector.len() != dim) {
+        bail!("Cannot write .npy: embeddings have different dimensions");
+    }
+
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        vectors.len(),
+        dim
+    );
+    // Magic (6) + version (2) + header length (2) + header must be a
+    // multiple of 64, with the header ending in a newline.
+    let unpadded = 10 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+    header.push('\n');
+
+    writer.write_all(b"\x93NUMPY")?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in vectors.iter().flatten() {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn index() -> VectorIndex {
+        let mut index = VectorIndex::new("encoder.onnx");
+        index.insert(7, 70, vec![1.0, 0.0, 0.0]).unwrap();
+        index.insert(8, 80, vec![0.0, 1.0, 0.0]).unwrap();
+        index.insert(9, 90, vec![0.6, 0.8, 0.0]).unwrap();
+        index
+    }
+
+    fn bytes(index: &VectorIndex) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        index.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> Result<VectorIndex> {
+        VectorIndex::read_from(&mut Cursor::new(bytes), bytes.len() as u64)
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let path = std::env::temp_dir().join(format!("llm-toy-vectors-{}.bin", std::process::id()));
+        index().save(&path).unwrap();
+        let loaded = VectorIndex::load(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.model, "encoder.onnx");
+        assert_eq!(loaded.dim, 3);
+        assert_eq!(loaded.entries, index().entries);
+        assert_eq!(loaded.search(&[1.0, 0.0, 0.0], 2), [(7, 1.0), (9, 0.6)]);
+        assert!(VectorIndex::load(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_lengths_that_do_not_match_the_file() {
+        let good = bytes(&index());
+        assert!(read(&good).is_ok());
+
+        let truncated = &good[..good.len() - 1];
+        assert!(read(truncated).is_err());
+
+        let mut extended = good.clone();
+        extended.extend_from_slice(&[0; 4]);
+        assert!(read(&extended).is_err());
+
+        // A model name longer than the file.
+        let mut long_name = good.clone();
+        long_name[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read(&long_name).is_err());
+
+        // A vector count whose size overflows.
+        let count_at = 12 + "encoder.onnx".len() + 4;
+        let mut many = good.clone();
+        many[count_at..count_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read(&many).unwrap_err().to_string().contains("do not match the file size"));
+
+        let mut magic = good;
+        magic[0] = b'X';
+        assert!(read(&magic).is_err());
+    }
+
+    #[test]
+    fn rejects_vectors_of_another_dimension() {
+        assert!(index().insert(10, 100, vec![1.0, 0.0]).is_err());
+    }
+
+    #[cfg(feature = "cpu")]
+    #[test]
+    fn pooling_ignores_right_padding() {
+        // Two rows of three positions; the second has one real token.
+        let hidden = ndarray::Array3::from_shape_vec(
+            (2, 3, 2),
+            vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 10.0, 20.0, 99.0, 99.0, 99.0, 99.0],
+        )
+        .unwrap();
+        assert_eq!(
+            pool(hidden.view(), &[3, 1], Pooling::Mean).unwrap(),
+            [vec![3.0, 4.0], vec![10.0, 20.0]]
+        );
+        assert_eq!(
+            pool(hidden.view(), &[3, 1], Pooling::Cls).unwrap(),
+            [vec![1.0, 2.0], vec![10.0, 20.0]]
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Errors returned by the public backend API. Library functions return the
+//! typed variants directly; failures in internal `anyhow` helpers that have
+//! no variant of their own surface as `Other`.
+
+use crate::SkippedBackend;
+use std::path::PathBuf;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Model file not found: {}", path.display())]
+    ModelNotFound { path: PathBuf },
+
+    #[error("Model {} is missing companion files: {}", path.display(), join_paths(missing))]
+    MissingModelFiles { path: PathBuf, missing: Vec<PathBuf> },
+
+    #[error("{}", unknown_backend_message(name, suggestions, available))]
+    UnknownBackend {
+        name: String,
+        suggestions: Vec<String>,
+        available: Vec<String>,
+    },
+
+    #[error("NPU backend '{backend}' is not available{}", reasons_suffix(reasons))]
+    BackendUnavailable { backend: String, reasons: Vec<String> },
+
+    #[error("NPU backend '{backend}' cannot load {}{}", path.display(), reason_suffix(reason))]
+    UnsupportedModel {
+        backend: String,
+        path: PathBuf,
+        reason: Option<String>,
+    },
+
+    #[error("No backend could load {}:{}", path.display(), skipped_list(skipped))]
+    NoBackend {
+        path: PathBuf,
+        skipped: Vec<SkippedBackend>,
+    },
+
+    #[error("Backend '{backend}' does not support {operation}")]
+    Unsupported {
+        backend: String,
+        operation: &'static str,
+    },
+
+    #[error("{backend} backend requires a tokenizer when input ids are not given")]
+    TokenizerMissing { backend: String },
+
+    #[error("Backend '{backend}' has no model loaded")]
+    ModelNotLoaded { backend: String },
+
+    #[error("Invalid request: {reason}")]
+    InvalidRequest { reason: String },
+
+    #[error("Model output '{name}' {reason}")]
+    InvalidOutput { name: String, reason: String },
+
+    #[error("Failed to tokenize the prompt")]
+    Tokenize {
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("Failed to load tokenizer from {}", path.display())]
+    Tokenizer {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("Unsupported tensor type {element_type} for '{name}'")]
+    UnsupportedTensorType { name: String, element_type: String },
+
+    #[error("Prompt is {tokens} tokens but the model context length is {limit}")]
+    ContextOverflow { tokens: usize, limit: usize },
+
+    #[error("The request queue is full ({capacity} requests waiting)")]
+    QueueFull { capacity: usize },
+
+    #[error("The model pool has shut down")]
+    PoolClosed,
+
+    #[error("Backend '{backend}' panicked while generating")]
+    BackendPanicked { backend: String },
+
+    /// A forward pass failed; every request in the batch gets its message.
+    #[error("Batched generation failed: {message}")]
+    BatchFailed { message: String },
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => Self::Other(err),
+        }
+    }
+}
+
+#[cfg(any(feature = "cpu", feature = "ryzen-ai"))]
+impl From<ort::Error> for Error {
+    fn from(err: ort::Error) -> Self {
+        Self::Other(err.into())
+    }
+}
+
+fn join_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn unknown_backend_message(name: &str, suggestions: &[String], available: &[String]) -> String {
+    let available = available.join(", ");
+    if suggestions.is_empty() {
+        return format!("Unknown backend '{name}' (available: {available})");
+    }
+    let suggestions: Vec<String> = suggestions.iter().map(|name| format!("'{name}'")).collect();
+    format!(
+        "Unknown backend '{name}'; did you mean {}? (available: {available})",
+        suggestions.join(" or ")
+    )
+}
+
+fn reasons_suffix(reasons: &[String]) -> String {
+    if reasons.is_empty() {
+        String::new()
+    } else {
+        format!(": {}", reasons.join("; "))
+    }
+}
+
+fn reason_suffix(reason: &Option<String>) -> String {
+    reason.as_ref().map(|reason| format!(": {reason}")).unwrap_or_default()
+}
+
+fn skipped_list(skipped: &[SkippedBackend]) -> String {
+    skipped
+        .iter()
+        .map(|skip| format!("\n  {} {}", skip.name, skip.reason))
+        .collect()
+}
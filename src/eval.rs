@@ -0,0 +1,301 @@
+use crate::{InferenceRequest, NpuBackend};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::LN_2;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PerplexityReport {
+    pub tokens: usize,
+    pub bytes: usize,
+    pub windows: usize,
+    pub window: usize,
+    pub stride: usize,
+    pub negative_log_likelihood: f64,
+    pub perplexity: f64,
+    pub bits_per_byte: f64,
+}
+
+/// Sliding-window perplexity over `ids`. Each window of up to `window`
+/// tokens starts `stride` tokens after the previous one, and only tokens
+/// not scored by an earlier window count, so every token after the first is
+/// scored exactly once with up to `window - 1` tokens of context. Stops
+/// between windows once `base` is cancelled.
+pub fn perplexity(
+    backend: &mut dyn NpuBackend,
+    base: &InferenceRequest,
+    ids: &[i64],
+    bytes: usize,
+    window: usize,
+    stride: usize,
+) -> Result<PerplexityReport> {
+    if window < 2 {
+        bail!("Perplexity window must be at least 2 tokens");
+    }
+    if stride == 0 || stride > window {
+        bail!("Stride must be between 1 and the window size ({window})");
+    }
+    if ids.len() < 2 {
+        bail!("Need at least 2 tokens to compute perplexity");
+    }
+
+    let mut nll = 0.0f64;
+    let mut scored = 0usize;
+    let mut windows = 0usize;
+    let mut begin = 0usize;
+    // Index of the first token no window has scored yet.
+    let mut next_unscored = 1usize;
+    while next_unscored < ids.len() {
+        if base.is_cancelled() {
+            bail!("Perplexity interrupted after {windows} windows");
+        }
+        let end = (begin + window).min(ids.len());
+        let logprobs = backend.token_logprobs(&InferenceRequest {
+            input_ids: Some(ids[begin..end].to_vec()),
+            ..base.clone()
+        })?;
+        check_logprobs(&logprobs, end - begin)?;
+        // logprobs[i] scores ids[begin + i + 1].
+        let skip = next_unscored - begin - 1;
+        for logprob in &logprobs[skip..] {
+            nll -= f64::from(*logprob);
+            scored += 1;
+        }
+        windows += 1;
+        next_unscored = end;
+        // Keep at least one token of overlap so the first new token has context.
+        begin = (begin + stride).min(next_unscored - 1);
+    }
+
+    Ok(PerplexityReport {
+        tokens: scored,
+        bytes,
+        windows,
+        window,
+        stride,
+        negative_log_likelihood: nll,
+        perplexity: (nll / scored as f64).exp(),
+        bits_per_byte: if bytes > 0 {
+            nll / LN_2 / bytes as f64
+        } else {
+            0.0
+        },
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionScore {
+    pub completion: String,
+    pub logprob: f64,
+    pub tokens: usize,
+    pub mean_logprob: f64,
+}
+
+/// Log-likelihood of `completion_ids` following `prompt_ids`.
+pub fn score_completion(
+    backend: &mut dyn NpuBackend,
+    base: &InferenceRequest,
+    prompt_ids: &[i64],
+    completion: &str,
+    completion_ids: &[i64],
+) -> Result<CompletionScore> {
+    if prompt_ids.is_empty() {
+        bail!("Scoring needs a non-empty prompt");
+    }
+    if completion_ids.is_empty() {
+        bail!("Completion '{completion}' has no tokens");
+    }
+    if base.is_cancelled() {
+        bail!("Scoring interrupted");
+    }
+    let mut ids = prompt_ids.to_vec();
+    ids.extend_from_slice(completion_ids);
+    if let Some(limit) = base.context_length {
+        if ids.len() > limit {
+            bail!(
+                "Prompt and completion are {} tokens but the model context length is {limit}",
+                ids.len()
+            );
+        }
+    }
+    let tokens = ids.len();
+    let logprobs = backend.token_logprobs(&InferenceRequest {
+        input_ids: Some(ids),
+        ..base.clone()
+    })?;
+    check_logprobs(&logprobs, tokens)?;
+    let logprob: f64 = logprobs[prompt_ids.len() - 1..]
+        .iter()
+        .map(|logprob| f64::from(*logprob))
+        .sum();
+    Ok(CompletionScore {
+        completion: completion.to_string(),
+        logprob,
+        tokens: completion_ids.len(),
+        mean_logprob: logprob / completion_ids.len() as f64,
+    })
+}
+
+// `token_logprobs` scores every token after the first.
+fn check_logprobs(logprobs: &[f32], tokens: usize) -> Result<()> {
+    if logprobs.len() != tokens - 1 {
+        bail!(
+            "Backend returned {} log-probabilities for {tokens} tokens; expected {}",
+            logprobs.len(),
+            tokens - 1
+        );
+    }
+    Ok(())
+}
+
+/// One multiple-choice question: the completions are scored against the
+/// prompt, and `answer` (an index into `completions`) is checked if given.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScoreItem {
+    #[serde(default)]
+    pub id: Option<serde_json::Value>,
+    pub prompt: String,
+    pub completions: Vec<String>,
+    #[serde(default)]
+    pub answer: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoreResult {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    pub scores: Vec<CompletionScore>,
+    /// Completion with the highest total log-probability.
+    pub best: usize,
+    /// Completion with the highest per-token log-probability.
+    pub best_normalized: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub answer: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correct: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correct_normalized: Option<bool>,
+}
+
+impl ScoreResult {
+    pub fn new(index: usize, item: &ScoreItem, scores: Vec<CompletionScore>) -> Self {
+        let argmax = |key: fn(&CompletionScore) -> f64| {
+            scores
+                .iter()
+                .enumerate()
+                .max_by(|a, b| key(a.1).total_cmp(&key(b.1)))
+                .map_or(0, |(index, _)| index)
+        };
+        let best = argmax(|score| score.logprob);
+        let best_normalized = argmax(|score| score.mean_logprob);
+        Self {
+            index,
+            id: item.id.clone(),
+            best,
+            best_normalized,
+            answer: item.answer,
+            correct: item.answer.map(|answer| answer == best),
+            correct_normalized: item.answer.map(|answer| answer == best_normalized),
+            scores,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InferenceResponse;
+    use std::path::Path;
+
+    /// Token ids are positions in the text, and the log-probability of
+    /// token `t` is `-2^t`, so the total tells exactly which tokens were
+    /// scored and how often.
+    #[derive(Default)]
+    struct Stub {
+        windows: Vec<Vec<i64>>,
+        /// Log-probabilities left off the end of every answer.
+        short_by: usize,
+    }
+
+    impl NpuBackend for Stub {
+        fn name(&self) -> &str {
+            "stub"
+        }
+
+        fn is_available(&self) -> bool {
+            true
+        }
+
+        fn load_model(&mut self, _model_path: &Path) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn run(&mut self, _request: &InferenceRequest) -> crate::Result<InferenceResponse> {
+            Ok(InferenceResponse::default())
+        }
+
+        fn token_logprobs(&mut self, request: &InferenceRequest) -> crate::Result<Vec<f32>> {
+            let ids = request.input_ids.clone().unwrap();
+            let keep = ids.len() - 1 - self.short_by;
+            let logprobs = ids[1..].iter().take(keep).map(|&id| -(2f32.powi(id as i32))).collect();
+            self.windows.push(ids);
+            Ok(logprobs)
+        }
+    }
+
+    fn text(tokens: usize) -> Vec<i64> {
+        (0..tokens as i64).collect()
+    }
+
+    /// Runs perplexity over `tokens` and checks every token after the first
+    /// was scored exactly once; returns the windows the backend saw.
+    fn windows(tokens: usize, window: usize, stride: usize) -> Vec<Vec<i64>> {
+        let mut stub = Stub::default();
+        let report = perplexity(&mut stub, &InferenceRequest::default(), &text(tokens), 0, window, stride).unwrap();
+        assert_eq!(report.tokens, tokens - 1);
+        // 2^1 + ... + 2^(tokens - 1)
+        assert_eq!(report.negative_log_likelihood, 2f64.powi(tokens as i32) - 2.0);
+        assert_eq!(report.windows, stub.windows.len());
+        stub.windows
+    }
+
+    #[test]
+    fn overlapping_windows_score_each_token_once() {
+        assert_eq!(windows(8, 4, 2), [text(4), (2..6).collect(), (4..8).collect()]);
+        assert_eq!(windows(6, 3, 1).len(), 4);
+    }
+
+    #[test]
+    fn stride_equal_to_window_keeps_one_token_of_context() {
+        assert_eq!(windows(10, 4, 4), [text(4), (3..7).collect(), (6..10).collect()]);
+    }
+
+    #[test]
+    fn the_last_window_may_be_short() {
+        let last = windows(11, 4, 4).pop().unwrap();
+        assert_eq!(last, [9, 10]);
+        assert_eq!(windows(3, 8, 8), [text(3)]);
+    }
+
+    #[test]
+    fn scores_only_the_completion() {
+        let mut stub = Stub::default();
+        let score = score_completion(&mut stub, &InferenceRequest::default(), &[0, 1, 2], " yes", &[3, 4]).unwrap();
+        assert_eq!(score.logprob, -(8.0 + 16.0));
+        assert_eq!(score.tokens, 2);
+        assert_eq!(score.mean_logprob, -12.0);
+        assert_eq!(stub.windows, [text(5)]);
+    }
+
+    #[test]
+    fn short_logprobs_are_an_error() {
+        let mut stub = Stub {
+            short_by: 1,
+            ..Stub::default()
+        };
+        let base = InferenceRequest::default();
+        let err = score_completion(&mut stub, &base, &[0], " no", &[1]).unwrap_err();
+        assert!(err.to_string().contains("0 log-probabilities for 2 tokens"), "{err}");
+        assert!(perplexity(&mut stub, &base, &text(6), 0, 4, 2).is_err());
+    }
+}
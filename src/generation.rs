@@ -0,0 +1,265 @@
+//! Sampling defaults and a builder for `InferenceRequest`. The defaults are
+//! the ones the CLI uses; a model's `generation_config.json` overrides them.
+
+use crate::{CancellationToken, InferenceRequest};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const GENERATION_CONFIG_FILE: &str = "generation_config.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenerationConfig {
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub repetition_penalty: f32,
+    pub eos_token_id: Option<i64>,
+    pub seed: Option<u64>,
+    pub stop: Vec<String>,
+    pub max_time: Option<Duration>,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 128,
+            temperature: 0.5,
+            top_k: Some(20),
+            top_p: Some(0.85),
+            repetition_penalty: 1.2,
+            eos_token_id: None,
+            seed: None,
+            stop: Vec::new(),
+            max_time: None,
+        }
+    }
+}
+
+// The subset of Hugging Face's generation_config.json that maps onto
+// `GenerationConfig`.
+#[derive(Deserialize)]
+struct HfGenerationConfig {
+    #[serde(default)]
+    do_sample: Option<bool>,
+    #[serde(default)]
+    max_new_tokens: Option<usize>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    top_k: Option<usize>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    repetition_penalty: Option<f32>,
+    #[serde(default)]
+    eos_token_id: Option<EosTokenId>,
+    /// Seconds.
+    #[serde(default)]
+    max_time: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EosTokenId {
+    One(i64),
+    Many(Vec<i64>),
+}
+
+impl GenerationConfig {
+    /// Reads a Hugging Face `generation_config.json`; settings it leaves out
+    /// keep their defaults. `do_sample: false` becomes greedy decoding
+    /// (`top_k` 1), and a list of EOS ids uses the first one.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let hf: HfGenerationConfig = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        let mut config = Self::default();
+        if let Some(max_tokens) = hf.max_new_tokens {
+            config.max_tokens = max_tokens;
+        }
+        if let Some(temperature) = hf.temperature {
+            config.temperature = temperature;
+        }
+        if let Some(top_k) = hf.top_k {
+            config.top_k = (top_k > 0).then_some(top_k);
+        }
+        if let Some(top_p) = hf.top_p {
+            config.top_p = Some(top_p);
+        }
+        if let Some(penalty) = hf.repetition_penalty {
+            config.repetition_penalty = penalty;
+        }
+        config.eos_token_id = match hf.eos_token_id {
+            Some(EosTokenId::One(id)) => Some(id),
+            Some(EosTokenId::Many(ids)) => ids.first().copied(),
+            None => None,
+        };
+        config.max_time = hf
+            .max_time
+            .filter(|seconds| *seconds > 0.0)
+            .map(Duration::from_secs_f64);
+        if hf.do_sample == Some(false) {
+            config.top_k = Some(1);
+        }
+        Ok(config)
+    }
+
+    /// The `generation_config.json` next to `model_path`, or the defaults
+    /// when the model has none.
+    pub fn for_model(model_path: &Path) -> Result<Self> {
+        let path = model_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(GENERATION_CONFIG_FILE);
+        if path.is_file() {
+            Self::from_file(&path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    pub fn request(&self, prompt: impl Into<String>) -> InferenceRequest {
+        InferenceRequest {
+            prompt: prompt.into(),
+            max_tokens: self.max_tokens,
+            input_ids: None,
+            input_name: None,
+            output_name: None,
+            tokenizer_path: None,
+            eos_token_id: self.eos_token_id,
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            repetition_penalty: self.repetition_penalty,
+            seed: self.seed,
+            context_length: None,
+            stop: self.stop.clone(),
+            cancel: None,
+            max_time: self.max_time,
+            deadline: None,
+        }
+    }
+}
+
+/// Fluent construction of an `InferenceRequest`, starting from
+/// `GenerationConfig::default()` unless `generation` replaces it.
+#[derive(Debug, Clone, Default)]
+pub struct InferenceRequestBuilder {
+    request: InferenceRequest,
+}
+
+impl InferenceRequestBuilder {
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            request: InferenceRequest {
+                prompt: prompt.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Replaces every sampling setting with `config`'s.
+    pub fn generation(mut self, config: &GenerationConfig) -> Self {
+        let prompt = std::mem::take(&mut self.request.prompt);
+        self.request = InferenceRequest {
+            input_ids: self.request.input_ids.take(),
+            input_name: self.request.input_name.take(),
+            output_name: self.request.output_name.take(),
+            tokenizer_path: self.request.tokenizer_path.take(),
+            context_length: self.request.context_length,
+            cancel: self.request.cancel.take(),
+            deadline: self.request.deadline,
+            ..config.request(prompt)
+        };
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.request.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn input_ids(mut self, ids: Vec<i64>) -> Self {
+        self.request.input_ids = Some(ids);
+        self
+    }
+
+    pub fn input_name(mut self, name: impl Into<String>) -> Self {
+        self.request.input_name = Some(name.into());
+        self
+    }
+
+    pub fn output_name(mut self, name: impl Into<String>) -> Self {
+        self.request.output_name = Some(name.into());
+        self
+    }
+
+    pub fn tokenizer_path(mut self, path: impl Into<String>) -> Self {
+        self.request.tokenizer_path = Some(path.into());
+        self
+    }
+
+    pub fn eos_token_id(mut self, id: i64) -> Self {
+        self.request.eos_token_id = Some(id);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.request.temperature = temperature;
+        self
+    }
+
+    pub fn top_k(mut self, top_k: Option<usize>) -> Self {
+        self.request.top_k = top_k;
+        self
+    }
+
+    pub fn top_p(mut self, top_p: Option<f32>) -> Self {
+        self.request.top_p = top_p;
+        self
+    }
+
+    pub fn repetition_penalty(mut self, penalty: f32) -> Self {
+        self.request.repetition_penalty = penalty;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.request.seed = Some(seed);
+        self
+    }
+
+    pub fn context_length(mut self, context_length: usize) -> Self {
+        self.request.context_length = Some(context_length);
+        self
+    }
+
+    pub fn stop(mut self, sequence: impl Into<String>) -> Self {
+        self.request.stop.push(sequence.into());
+        self
+    }
+
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.request.cancel = Some(token);
+        self
+    }
+
+    pub fn max_time(mut self, max_time: Duration) -> Self {
+        self.request.max_time = Some(max_time);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.request.deadline = Some(deadline);
+        self
+    }
+
+    pub fn build(self) -> InferenceRequest {
+        self.request
+    }
+}
@@ -1,14 +1,121 @@
-use anyhow::{bail, Context, Result};
+use anyhow::bail;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub mod batch;
+pub mod bench;
+pub mod cancel;
+pub mod config;
+pub mod embedding;
+pub mod error;
+pub mod eval;
+pub mod generation;
+pub mod memory;
+pub mod model;
+pub mod model_files;
+pub mod pool;
+mod queue;
+pub mod prefix_cache;
+pub mod rag;
+pub mod registry;
+pub mod runtime;
+pub mod scheduler;
+pub mod sequence;
+pub mod system;
+pub mod xdna;
+
+pub use cancel::CancellationToken;
+pub use error::{Error, Result};
+pub use generation::{GenerationConfig, InferenceRequestBuilder};
+pub use model::Model;
+pub use pool::{ModelPool, PendingResponse};
+pub use scheduler::Scheduler;
+pub use sequence::{KvCache, Sequence};
+use model_files::check_model_files;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelConfig {
     pub name: String,
     pub path: String,
     pub npu_backend: String,
+    #[serde(default)]
+    pub tokenizer_path: Option<String>,
+    #[serde(default)]
+    pub chat_template: Option<String>,
+    #[serde(default)]
+    pub context_length: Option<usize>,
+    #[serde(default)]
+    pub session: SessionOptions,
+    /// Backends `auto` tries, in order; empty means `DEFAULT_BACKEND_PREFERENCE`.
+    #[serde(default)]
+    pub backend_preference: Vec<String>,
+}
+
+/// Runtime settings for backends that build an ONNX Runtime session.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionOptions {
+    #[serde(default)]
+    pub intra_threads: Option<usize>,
+    #[serde(default)]
+    pub inter_threads: Option<usize>,
+    #[serde(default)]
+    pub optimization_level: OptimizationLevel,
+    /// Where to save the optimized graph. When the file is already there and
+    /// newer than the model, it is loaded instead and optimization is skipped.
+    #[serde(default)]
+    pub optimized_model_path: Option<String>,
+    #[serde(default)]
+    pub memory_pattern: Option<bool>,
+    #[serde(default)]
+    pub cpu_arena: Option<bool>,
+    /// Whether ONNX Runtime may memory-map the model file. Disabling it
+    /// reads the whole model into memory before building the session.
+    #[serde(default)]
+    pub mmap: Option<bool>,
+    /// Execution providers to try in order; ONNX Runtime falls back to the
+    /// CPU provider for anything they cannot run.
+    #[serde(default)]
+    pub execution_providers: Vec<String>,
+    /// Memory cap in MiB for reusing the KV cache of shared prompt prefixes
+    /// across requests (`prefix_cache::DEFAULT_PREFIX_CACHE_MB` when unset,
+    /// 0 disables it).
+    #[serde(default)]
+    pub prefix_cache_mb: Option<usize>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OptimizationLevel {
+    Disable,
+    #[default]
+    Basic,
+    Extended,
+    Layout,
+    All,
+}
+
+impl std::str::FromStr for OptimizationLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "disable" | "none" | "0" => Ok(Self::Disable),
+            "basic" | "1" => Ok(Self::Basic),
+            "extended" | "2" => Ok(Self::Extended),
+            "layout" | "3" => Ok(Self::Layout),
+            "all" => Ok(Self::All),
+            _ => bail!(
+                "Unknown optimization level '{s}' (expected disable, basic, extended, layout or all)"
+            ),
+        }
+    }
+}
+
+pub const EXECUTION_PROVIDERS: [&str; 9] = [
+    "cpu", "cuda", "tensorrt", "directml", "rocm", "openvino", "coreml", "xnnpack", "vitis",
+];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceRequest {
     pub prompt: String,
@@ -23,18 +130,188 @@ pub struct InferenceRequest {
     pub top_p: Option<f32>,
     pub repetition_penalty: f32,
     pub seed: Option<u64>,
+    #[serde(default)]
+    pub context_length: Option<usize>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Checked between decode steps; once cancelled, generation ends with
+    /// `FinishReason::Cancelled` and the text produced so far.
+    #[serde(skip)]
+    pub cancel: Option<CancellationToken>,
+    /// Wall-clock budget, counted from when the backend starts the request.
+    #[serde(default)]
+    pub max_time: Option<Duration>,
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Default for InferenceRequest {
+    fn default() -> Self {
+        GenerationConfig::default().request(String::new())
+    }
+}
+
+impl InferenceRequest {
+    pub fn builder(prompt: impl Into<String>) -> InferenceRequestBuilder {
+        InferenceRequestBuilder::new(prompt)
+    }
+
+    /// The earlier of `deadline` and `max_time` after `start`.
+    pub fn deadline_from(&self, start: Instant) -> Option<Instant> {
+        let budget = self.max_time.map(|max_time| start + max_time);
+        match (self.deadline, budget) {
+            (Some(deadline), Some(budget)) => Some(deadline.min(budget)),
+            (deadline, budget) => deadline.or(budget),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Whether the two requests can share forward passes: the model inputs
+    /// and outputs they name, and their tokenizer, must match.
+    pub fn can_batch_with(&self, other: &InferenceRequest) -> bool {
+        self.input_name == other.input_name
+            && self.output_name == other.output_name
+            && self.tokenizer_path == other.tokenizer_path
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Stop,
+    Length,
+    Cancelled,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct InferenceResponse {
+    /// The generated text. Backends that decode tokens return only the
+    /// completion; the placeholders echo the prompt.
     pub text: String,
+    #[serde(default)]
+    pub prompt_tokens: Option<usize>,
+    #[serde(default)]
+    pub completion_tokens: Option<usize>,
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    #[serde(default)]
+    pub timings: Option<GenerationTimings>,
+    /// Prompt tokens whose attention state came from the prefix cache.
+    #[serde(default)]
+    pub cached_tokens: Option<usize>,
+}
+
+/// Wall-clock split of a generation: the first forward pass over the
+/// prompt, and every step after it.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct GenerationTimings {
+    pub prefill_ms: f64,
+    pub decode_ms: f64,
+}
+
+pub fn load_tokenizer(path: &Path) -> Result<tokenizers::Tokenizer> {
+    tokenizers::Tokenizer::from_file(path).map_err(|source| Error::Tokenizer {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Byte offset of the earliest stop sequence in `text`, if any.
+pub fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|sequence| !sequence.is_empty())
+        .filter_map(|sequence| text.find(sequence.as_str()))
+        .min()
 }
 
-pub trait NpuBackend {
+/// Whether a backend can run on this machine, and why not if it cannot.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Availability {
+    pub available: bool,
+    pub reasons: Vec<String>,
+    /// What was found while checking, such as devices and driver versions.
+    pub details: Vec<String>,
+}
+
+/// A loaded model runtime. Backends are `Send` so a loaded model can move to
+/// a worker thread; see `pool::ModelPool` for sharing one between callers.
+pub trait NpuBackend: Send {
     fn name(&self) -> &str;
     fn is_available(&self) -> bool;
     fn load_model(&mut self, model_path: &Path) -> Result<()>;
     fn run(&mut self, request: &InferenceRequest) -> Result<InferenceResponse>;
+
+    fn context_length(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether `load_model` understands this model's format. Only the
+    /// placeholder backend takes GGUF, so the default accepts everything else.
+    fn supports_model(&self, model_path: &Path) -> bool {
+        !model_files::is_gguf(model_path)
+    }
+
+    /// Detailed form of `is_available`, for diagnostics and error messages.
+    fn availability(&self) -> Availability {
+        Availability {
+            available: self.is_available(),
+            ..Default::default()
+        }
+    }
+
+    /// Applies model-level settings before `load_model`.
+    fn configure(&mut self, _config: &ModelConfig) -> Result<()> {
+        Ok(())
+    }
+
+    /// Log-probability of each token of `request.input_ids` given the tokens
+    /// before it, so the result has one entry fewer than the input.
+    fn token_logprobs(&mut self, _request: &InferenceRequest) -> Result<Vec<f32>> {
+        Err(Error::Unsupported {
+            backend: self.name().to_string(),
+            operation: "scoring",
+        })
+    }
+
+    /// Runs several requests together. Backends without batching support
+    /// run them one after another.
+    fn run_batch(&mut self, requests: &[InferenceRequest]) -> Result<Vec<InferenceResponse>> {
+        requests.iter().map(|request| self.run(request)).collect()
+    }
+
+    /// Whether the step-wise methods below are implemented, letting
+    /// `scheduler::Scheduler` add and retire requests between forward passes.
+    fn supports_steps(&self) -> bool {
+        false
+    }
+
+    /// Tokenizes `request` into a sequence for `step`.
+    fn start_sequence(&mut self, _request: &InferenceRequest) -> Result<Sequence> {
+        Err(Error::Unsupported {
+            backend: self.name().to_string(),
+            operation: "step-wise decoding",
+        })
+    }
+
+    /// Runs one forward pass over the sequences that are not done and adds
+    /// a token to each. The sequences must satisfy `Sequence::can_batch_with`.
+    fn step(&mut self, _sequences: &mut [&mut Sequence]) -> Result<()> {
+        Err(Error::Unsupported {
+            backend: self.name().to_string(),
+            operation: "step-wise decoding",
+        })
+    }
+
+    fn finish_sequence(&mut self, _sequence: Sequence) -> Result<InferenceResponse> {
+        Err(Error::Unsupported {
+            backend: self.name().to_string(),
+            operation: "step-wise decoding",
+        })
+    }
 }
 
 pub struct PlaceholderNpuBackend {
@@ -58,10 +335,14 @@ impl NpuBackend for PlaceholderNpuBackend {
         true
     }
 
+    /// It never reads the weights, so any format works, split GGUF
+    /// included, as long as every shard is present.
+    fn supports_model(&self, _model_path: &Path) -> bool {
+        true
+    }
+
     fn load_model(&mut self, model_path: &Path) -> Result<()> {
-        if !model_path.exists() {
-            bail!("Model file not found: {}", model_path.display());
-        }
+        check_model_files(model_path)?;
         Ok(())
     }
 
@@ -70,20 +351,40 @@ impl NpuBackend for PlaceholderNpuBackend {
             "[placeholder:{}] {}",
             self.backend_name, request.prompt
         );
-        Ok(InferenceResponse { text })
+        Ok(InferenceResponse {
+            text,
+            ..Default::default()
+        })
     }
 }
 
 pub struct AmdXdnaBackend {
     backend_name: String,
+    probe_paths: xdna::ProbePaths,
 }
 
 impl AmdXdnaBackend {
     pub fn new() -> Self {
+        Self::with_probe_paths(xdna::ProbePaths::default())
+    }
+
+    /// Probes the given device and sysfs locations instead of the system's.
+    pub fn with_probe_paths(probe_paths: xdna::ProbePaths) -> Self {
         Self {
             backend_name: "amd-xdna".to_string(),
+            probe_paths,
         }
     }
+
+    pub fn probe(&self) -> xdna::XdnaReport {
+        xdna::probe(&self.probe_paths)
+    }
+}
+
+impl Default for AmdXdnaBackend {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NpuBackend for AmdXdnaBackend {
@@ -92,19 +393,28 @@ impl NpuBackend for AmdXdnaBackend {
     }
 
     fn is_available(&self) -> bool {
-        true
+        self.probe().available
+    }
+
+    fn availability(&self) -> Availability {
+        self.probe().availability()
+    }
+
+    fn supports_model(&self, model_path: &Path) -> bool {
+        model_files::is_onnx(model_path)
     }
 
     fn load_model(&mut self, model_path: &Path) -> Result<()> {
-        if !model_path.exists() {
-            bail!("Model file not found: {}", model_path.display());
-        }
+        check_model_files(model_path)?;
         Ok(())
     }
 
     fn run(&mut self, request: &InferenceRequest) -> Result<InferenceResponse> {
         let text = format!("[amd-xdna:placeholder] {}", request.prompt);
-        Ok(InferenceResponse { text })
+        Ok(InferenceResponse {
+            text,
+            ..Default::default()
+        })
     }
 }
 
@@ -114,12 +424,140 @@ use ort::{
     tensor::{Shape, TensorElementType},
     value::{DynTensor, DynValue, Tensor, ValueType},
 };
+#[cfg(any(feature = "cpu", all(windows, feature = "ryzen-ai")))]
+use anyhow::Context;
 #[cfg(feature = "cpu")]
 use tokenizers::Tokenizer;
 #[cfg(feature = "cpu")]
 use ndarray::Axis;
 #[cfg(feature = "cpu")]
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand::Rng;
+#[cfg(feature = "cpu")]
+use prefix_cache::PrefixCache;
+#[cfg(feature = "cpu")]
+use sequence::KvTensor;
+
+/// Whether ONNX Runtime loads from the library `env_var` selects and, if
+/// `provider` is given, was built with that execution provider.
+#[cfg(any(feature = "cpu", all(windows, feature = "ryzen-ai")))]
+fn runtime_availability(env_var: &str, provider: Option<&str>) -> Availability {
+    let unavailable = |reason: String| Availability {
+        available: false,
+        reasons: vec![reason],
+        details: Vec::new(),
+    };
+    // Only a runtime that is already loaded is inspected; otherwise finding
+    // the library file has to do, and `load_model` loads it.
+    let Some(info) = runtime::loaded() else {
+        let library = match runtime::resolve_library(env_var) {
+            Ok(library) => library,
+            Err(err) => return unavailable(format!("{err:#}")),
+        };
+        let Some(path) = runtime::find_library(&library) else {
+            return unavailable(runtime::not_found_reason(&library, env_var));
+        };
+        let mut details = vec![format!("ONNX Runtime library at {} (not loaded yet)", path.display())];
+        if let Some(provider) = provider {
+            details.push(format!("the {provider} execution provider is checked when it loads"));
+        }
+        return Availability {
+            available: true,
+            reasons: Vec::new(),
+            details,
+        };
+    };
+    if let Err(err) = runtime::init(env_var) {
+        return unavailable(format!("{err:#}"));
+    }
+    let mut reasons = Vec::new();
+    if let Some(provider) = provider {
+        if !info.execution_providers.iter().any(|name| name == provider) {
+            reasons.push(format!(
+                "ONNX Runtime from {} was not built with the {provider} execution provider",
+                info.library.describe()
+            ));
+        }
+    }
+    Availability {
+        available: reasons.is_empty(),
+        reasons,
+        details: vec![format!(
+            "ONNX Runtime {} from {}",
+            info.version,
+            info.library.describe()
+        )],
+    }
+}
+
+#[cfg(feature = "cpu")]
+pub(crate) fn build_session(model_path: &Path, options: &SessionOptions) -> anyhow::Result<Session> {
+    let optimized = options
+        .optimized_model_path
+        .as_deref()
+        .map(Path::new)
+        .filter(|path| is_newer(path, model_path));
+    let level = match (optimized, options.optimization_level) {
+        (Some(_), _) | (None, OptimizationLevel::Disable) => GraphOptimizationLevel::Disable,
+        (None, OptimizationLevel::Basic) => GraphOptimizationLevel::Level1,
+        (None, OptimizationLevel::Extended) => GraphOptimizationLevel::Level2,
+        (None, OptimizationLevel::Layout) => GraphOptimizationLevel::Level3,
+        (None, OptimizationLevel::All) => GraphOptimizationLevel::All,
+    };
+
+    let mut builder = Session::builder()?.with_optimization_level(level)?;
+    if let Some(threads) = options.intra_threads {
+        builder = builder.with_intra_threads(threads)?;
+    }
+    if let Some(threads) = options.inter_threads {
+        builder = builder.with_inter_threads(threads)?;
+    }
+    if let Some(enable) = options.memory_pattern {
+        builder = builder.with_memory_pattern(enable)?;
+    }
+    let mut providers = options
+        .execution_providers
+        .iter()
+        .map(|name| runtime::execution_provider(&name.to_ascii_lowercase(), options))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if options.cpu_arena.is_some() && !options
+            .execution_providers
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case("cpu")) {
+        providers.push(runtime::execution_provider("cpu", options)?);
+    }
+    if !providers.is_empty() {
+        builder = builder.with_execution_providers(providers)?;
+    }
+    if optimized.is_none() {
+        if let Some(path) = options.optimized_model_path.as_deref() {
+            builder = builder.with_optimized_model_path(path)?;
+        }
+    }
+
+    let source = optimized.unwrap_or(model_path);
+    if options.mmap == Some(false) {
+        if !model_files::companion_files(source)?.is_empty() {
+            bail!(
+                "{} keeps its weights in external data files, which must be memory-mapped",
+                source.display()
+            );
+        }
+        let bytes = std::fs::read(source)
+            .with_context(|| format!("Failed to read {}", source.display()))?;
+        return Ok(builder.commit_from_memory(&bytes)?);
+    }
+    Ok(builder.commit_from_file(source)?)
+}
+
+// True when `path` exists and was modified no earlier than `than`.
+#[cfg(feature = "cpu")]
+fn is_newer(path: &Path, than: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    match (modified(path), modified(than)) {
+        (Some(path), Some(than)) => path >= than,
+        _ => false,
+    }
+}
 
 #[cfg(feature = "cpu")]
 pub struct CpuBackend {
@@ -127,6 +565,10 @@ pub struct CpuBackend {
     session: Option<Session>,
     tokenizer: Option<tokenizers::Tokenizer>,
     tokenizer_path: Option<String>,
+    context_length: Option<usize>,
+    options: SessionOptions,
+    kv_layout: Option<KvLayout>,
+    prefix_cache: Option<PrefixCache>,
 }
 
 #[cfg(feature = "cpu")]
@@ -137,19 +579,14 @@ impl CpuBackend {
             session: None,
             tokenizer: None,
             tokenizer_path: None,
+            context_length: None,
+            kv_layout: None,
+            prefix_cache: None,
+            options: SessionOptions::default(),
         }
     }
 
-    fn init_environment() -> Result<()> {
-        if let Ok(path) = std::env::var("CPU_ORT_DLL") {
-            ort::init_from(path)?.with_name("llm-toy").commit();
-        } else {
-            ort::init().with_name("llm-toy").commit();
-        }
-        Ok(())
-    }
-
-    fn resolve_dynamic_shape(name: &str, shape: &Shape, seq_len: usize) -> Shape {
+    fn resolve_dynamic_shape(name: &str, shape: &Shape, batch: usize, seq_len: usize) -> Shape {
         let mut resolved = Vec::with_capacity(shape.len());
         let mut used_batch = false;
         let is_past = name.contains("past_key_values") || name.contains("past");
@@ -162,13 +599,13 @@ impl CpuBackend {
 
             if is_past {
                 if !used_batch {
-                    resolved.push(1);
+                    resolved.push(batch as i64);
                     used_batch = true;
                 } else {
                     resolved.push(0);
                 }
             } else if !used_batch {
-                resolved.push(1);
+                resolved.push(batch as i64);
                 used_batch = true;
             } else {
                 resolved.push(seq_len as i64);
@@ -178,14 +615,16 @@ impl CpuBackend {
         Shape::from(resolved)
     }
 
-    fn token_shape(shape: &Shape, seq_len: usize) -> Shape {
+    fn token_shape(name: &str, shape: &Shape, batch: usize, seq_len: usize) -> anyhow::Result<Shape> {
         match shape.len() {
-            1 => Shape::from([seq_len as i64]),
-            _ => Shape::from([1_i64, seq_len as i64]),
+            1 if batch > 1 => bail!("Input '{name}' has no batch dimension"),
+            1 => Ok(Shape::from([seq_len as i64])),
+            _ => Ok(Shape::from([batch as i64, seq_len as i64])),
         }
     }
 
     fn build_int_tensor(
+        name: &str,
         ty: TensorElementType,
         shape: Shape,
         data: Vec<i64>
@@ -216,7 +655,10 @@ impl CpuBackend {
                 let data: Vec<u64> = data.into_iter().map(|v| v as u64).collect();
                 Ok(Tensor::from_array((shape, data))?.into_dyn())
             }
-            _ => bail!("Unsupported integer tensor type: {ty}"),
+            _ => Err(Error::UnsupportedTensorType {
+                name: name.to_string(),
+                element_type: ty.to_string(),
+            }),
         }
     }
 
@@ -231,11 +673,16 @@ impl CpuBackend {
         }
     }
 
-    fn ensure_tokenizer(&mut self, path: &str) -> Result<&Tokenizer> {
+    fn decode(tokenizer: &Tokenizer, ids: &[i64]) -> anyhow::Result<String> {
+        let ids: Vec<u32> = ids.iter().map(|v| *v as u32).collect();
+        tokenizer
+            .decode(&ids, true)
+            .map_err(|e| anyhow::anyhow!("Failed to decode tokens: {e}"))
+    }
+
+    fn ensure_tokenizer(&mut self, path: &str) -> anyhow::Result<&Tokenizer> {
         if self.tokenizer_path.as_deref() != Some(path) {
-            let tokenizer = Tokenizer::from_file(path)
-                .map_err(|e| anyhow::anyhow!("Failed to load tokenizer from {path}: {e}"))?;
-            self.tokenizer = Some(tokenizer);
+            self.tokenizer = Some(load_tokenizer(Path::new(path))?);
             self.tokenizer_path = Some(path.to_string());
         }
 
@@ -244,12 +691,141 @@ impl CpuBackend {
             .context("Tokenizer is not loaded")
     }
 
+    /// One forward pass over `active`, sampling a token for each row.
+    fn step_rows(&mut self, active: &mut [&mut Sequence]) -> anyhow::Result<()> {
+        let Some(first) = active.first() else {
+            return Ok(());
+        };
+        let input_name = first.request.input_name.clone().unwrap_or_else(|| "input_ids".to_string());
+        let output_name = first.request.output_name.clone().unwrap_or_else(|| "logits".to_string());
+        let pad_id = first.request.eos_token_id.unwrap_or(0);
+        let tokenizer = first.request.tokenizer_path.as_ref().and(self.tokenizer.as_ref());
+        let session = self
+            .session
+            .as_mut()
+            .context("Model is not loaded")?;
+        let kv_layout = self.kv_layout.as_ref();
+
+        let step_start = Instant::now();
+        let rows: Vec<(&[i64], Option<&KvCache>)> = active
+            .iter()
+            .map(|sequence| {
+                let cache = kv_layout.and(sequence.cache.as_ref());
+                (&sequence.ids[cache.map_or(0, |cache| cache.len)..], cache)
+            })
+            .collect();
+        let inputs = Self::build_batch_inputs(session, &rows, &input_name, pad_id, kv_layout)?;
+        let outputs = session.run(inputs)?;
+        let mut caches = match kv_layout {
+            Some(layout) => Self::split_present(&outputs, &rows, layout)?
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => vec![None; rows.len()],
+        };
+        drop(rows);
+        let output = outputs[output_name.as_str()].try_extract_array::<f32>()?;
+        let shape = output
+            .shape()
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join("x");
+        let first_value = output.iter().next().copied().unwrap_or(0.0);
+        let batch = active.len();
+        let elapsed_ms = step_start.elapsed().as_secs_f64() * 1000.0;
+
+        for (position, sequence) in active.iter_mut().enumerate() {
+            let logits = Self::last_logits(&output, position, batch)?;
+            let request = &sequence.request;
+            let next_id = Self::pick_next_token(
+                logits,
+                &sequence.ids,
+                request.temperature,
+                request.top_k,
+                request.top_p,
+                request.repetition_penalty,
+                &mut sequence.rng,
+            )?;
+            sequence.last_output = Some((shape.clone(), first_value));
+            sequence.cache = caches[position].take();
+            if let (Some(prefix_cache), Some(cache), 0) =
+                (self.prefix_cache.as_mut(), sequence.cache.as_ref(), sequence.steps)
+            {
+                // The prompt alone, so requests that share it but continue
+                // differently can start from it.
+                prefix_cache.insert(&sequence.ids, cache);
+            }
+            sequence.ids.push(next_id);
+            sequence.record_step(elapsed_ms);
+
+            let request = &sequence.request;
+            if request.eos_token_id == Some(next_id) {
+                sequence.done = true;
+                sequence.finish_reason = FinishReason::Stop;
+            } else if let (Some(tokenizer), false) = (tokenizer, request.stop.is_empty()) {
+                let completion = Self::decode(tokenizer, &sequence.ids[sequence.prompt_len..])?;
+                if find_stop(&completion, &request.stop).is_some() {
+                    sequence.done = true;
+                    sequence.finish_reason = FinishReason::Stop;
+                }
+            }
+            if sequence.ids.len() - sequence.prompt_len >= sequence.request.max_tokens
+                || sequence.context_length.is_some_and(|limit| sequence.ids.len() >= limit)
+            {
+                sequence.done = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the model takes the inputs that keep left-padding out of a
+    /// batched pass: an attention mask, and position ids that skip it.
+    fn masks_padding(session: &Session) -> bool {
+        let has = |part: &str| session.inputs().iter().any(|input| input.name().contains(part));
+        has("attention_mask") && has("position_ids")
+    }
+
     fn build_inputs(
         session: &Session,
         input_ids: &[i64],
         input_name: &str,
-    ) -> Result<Vec<(String, DynValue)>> {
-        let seq_len = input_ids.len();
+    ) -> anyhow::Result<Vec<(String, DynValue)>> {
+        Self::build_batch_inputs(session, &[(input_ids, None)], input_name, 0, None)
+    }
+
+    /// Builds inputs for a batch of rows, each a run of new tokens after an
+    /// optional KV cache. Shorter rows are left-padded with `pad_id` and
+    /// shorter caches with zeros; padding is masked out of `attention_mask`
+    /// and does not advance `position_ids`, so each row sees the same
+    /// positions it would see on its own.
+    fn build_batch_inputs(
+        session: &Session,
+        rows: &[(&[i64], Option<&KvCache>)],
+        input_name: &str,
+        pad_id: i64,
+        kv_layout: Option<&KvLayout>,
+    ) -> anyhow::Result<Vec<(String, DynValue)>> {
+        let batch = rows.len();
+        let past_len = |cache: Option<&KvCache>| cache.map_or(0, |cache| cache.len);
+        let max_past = rows.iter().map(|(_, cache)| past_len(*cache)).max().unwrap_or(0);
+        let seq_len = rows.iter().map(|(row, _)| row.len()).max().unwrap_or(0);
+        let padded = rows
+            .iter()
+            .any(|(row, cache)| row.len() < seq_len || past_len(*cache) < max_past);
+        if padded && !Self::masks_padding(session) {
+            bail!("The model has no attention_mask and position_ids inputs to batch rows of different lengths");
+        }
+        let padding = |row: &[i64]| seq_len - row.len();
+        let per_row = |real: &dyn Fn(usize, usize) -> i64| {
+            rows.iter()
+                .flat_map(|(row, cache)| {
+                    let pad_len = padding(row);
+                    let past = past_len(*cache);
+                    (0..seq_len).map(move |i| if i < pad_len { 0 } else { real(past, i - pad_len) })
+                })
+                .collect::<Vec<i64>>()
+        };
         let mut inputs: Vec<(String, DynValue)> = Vec::new();
 
         for outlet in session.inputs() {
@@ -258,38 +834,76 @@ impl CpuBackend {
                 continue;
             };
 
-            if name == input_name {
-                let token_shape = Self::token_shape(&shape, seq_len);
-                let tensor = Self::build_int_tensor(ty, token_shape, input_ids.to_vec())?;
-                inputs.push((name.to_string(), tensor));
+            let data = if name == input_name {
+                Some(
+                    rows.iter()
+                        .flat_map(|(row, _)| {
+                            std::iter::repeat_n(pad_id, padding(row)).chain(row.iter().copied())
+                        })
+                        .collect(),
+                )
+            } else if name.contains("attention_mask") {
+                // Covers the cached positions followed by the new ones.
+                let mask = rows
+                    .iter()
+                    .flat_map(|(row, cache)| {
+                        let past = past_len(*cache);
+                        std::iter::repeat_n(0, max_past - past)
+                            .chain(std::iter::repeat_n(1, past))
+                            .chain(std::iter::repeat_n(0, padding(row)))
+                            .chain(std::iter::repeat_n(1, row.len()))
+                    })
+                    .collect();
+                let mask_shape = Self::token_shape(name, &shape, batch, max_past + seq_len)?;
+                inputs.push((name.to_string(), Self::build_int_tensor(name, ty, mask_shape, mask)?));
                 continue;
-            }
+            } else if name.contains("position_ids") {
+                Some(per_row(&|past, i| (past + i) as i64))
+            } else if name.contains("token_type_ids") {
+                Some(per_row(&|_, _| 0))
+            } else {
+                None
+            };
 
-            if name.contains("attention_mask") {
-                let token_shape = Self::token_shape(&shape, seq_len);
-                let data = vec![1_i64; seq_len];
-                let tensor = Self::build_int_tensor(ty, token_shape, data)?;
+            if let Some(data) = data {
+                let token_shape = Self::token_shape(name, &shape, batch, seq_len)?;
+                let tensor = Self::build_int_tensor(name, ty, token_shape, data)?;
                 inputs.push((name.to_string(), tensor));
                 continue;
             }
 
-            if name.contains("position_ids") {
-                let token_shape = Self::token_shape(&shape, seq_len);
-                let data = (0..seq_len as i64).collect::<Vec<_>>();
-                let tensor = Self::build_int_tensor(ty, token_shape, data)?;
-                inputs.push((name.to_string(), tensor));
+            if name == "use_cache_branch" {
+                let tensor = Tensor::from_array((Shape::from([1i64]), vec![max_past > 0]))?;
+                inputs.push((name.to_string(), tensor.into_dyn()));
                 continue;
             }
 
-            if name.contains("token_type_ids") {
-                let token_shape = Self::token_shape(&shape, seq_len);
-                let data = vec![0_i64; seq_len];
-                let tensor = Self::build_int_tensor(ty, token_shape, data)?;
-                inputs.push((name.to_string(), tensor));
+            let layer = kv_layout.and_then(|layout| layout.position(name));
+            let cached = layer.and_then(|layer| {
+                rows.iter()
+                    .find_map(|(_, cache)| cache.map(|cache| &cache.tensors[layer]))
+                    .map(|tensor| (layer, tensor.heads, tensor.head_dim))
+            });
+            if let Some((layer, heads, head_dim)) = cached.filter(|_| max_past > 0) {
+                let mut data = Vec::with_capacity(batch * heads * max_past * head_dim);
+                for (_, cache) in rows {
+                    match cache {
+                        Some(cache) => cache.tensors[layer].write_padded(cache.len, max_past, &mut data),
+                        None => data.extend(std::iter::repeat_n(0.0, heads * max_past * head_dim)),
+                    }
+                }
+                let past_shape = Shape::from([
+                    batch as i64,
+                    heads as i64,
+                    max_past as i64,
+                    head_dim as i64,
+                ]);
+                let tensor = Tensor::from_array((past_shape, data))?;
+                inputs.push((name.to_string(), tensor.into_dyn()));
                 continue;
             }
 
-            let resolved = Self::resolve_dynamic_shape(name, &shape, seq_len);
+            let resolved = Self::resolve_dynamic_shape(name, &shape, batch, seq_len);
             let tensor = DynTensor::new(session.allocator(), ty, resolved)?;
             inputs.push((name.to_string(), tensor.into_dyn()));
         }
@@ -297,43 +911,82 @@ impl CpuBackend {
         Ok(inputs)
     }
 
+    /// Each row's KV cache after a forward pass: its previous positions and
+    /// its new tokens, taken from the `present` outputs with padding dropped.
+    fn split_present(
+        outputs: &ort::session::SessionOutputs<'_>,
+        rows: &[(&[i64], Option<&KvCache>)],
+        kv_layout: &KvLayout,
+    ) -> anyhow::Result<Vec<KvCache>> {
+        let past_len = |cache: Option<&KvCache>| cache.map_or(0, |cache| cache.len);
+        let max_past = rows.iter().map(|(_, cache)| past_len(*cache)).max().unwrap_or(0);
+        let seq_len = rows.iter().map(|(row, _)| row.len()).max().unwrap_or(0);
+        let mut caches: Vec<KvCache> = rows
+            .iter()
+            .map(|(row, cache)| KvCache {
+                len: past_len(*cache) + row.len(),
+                tensors: Vec::with_capacity(kv_layout.layers.len()),
+            })
+            .collect();
+
+        for (_, present) in &kv_layout.layers {
+            let output = outputs[present.as_str()].try_extract_array::<f32>()?;
+            if output.ndim() != 4 {
+                bail!("Expected '{present}' to have 4 dimensions, got {}", output.ndim());
+            }
+            for (position, ((row, cache), kv)) in rows.iter().zip(&mut caches).enumerate() {
+                let past = past_len(*cache);
+                let kept: Vec<usize> = (max_past - past..max_past)
+                    .chain(max_past + seq_len - row.len()..max_past + seq_len)
+                    .collect();
+                let selected = output.index_axis(Axis(0), position).select(Axis(1), &kept);
+                kv.tensors.push(KvTensor {
+                    heads: selected.len_of(Axis(0)),
+                    head_dim: selected.len_of(Axis(2)),
+                    data: selected.iter().copied().collect(),
+                });
+            }
+        }
+        Ok(caches)
+    }
+
+    fn last_logits(output: &ndarray::ArrayViewD<'_, f32>, row: usize, batch: usize) -> anyhow::Result<Vec<f32>> {
+        match output.ndim() {
+            3 => {
+                let row = output.index_axis(Axis(0), row);
+                let seq = row.len_of(Axis(0));
+                Ok(row
+                    .index_axis(Axis(0), seq.saturating_sub(1))
+                    .iter()
+                    .copied()
+                    .collect())
+            }
+            2 if batch == 1 => {
+                let seq = output.len_of(Axis(0));
+                Ok(output
+                    .index_axis(Axis(0), seq.saturating_sub(1))
+                    .iter()
+                    .copied()
+                    .collect())
+            }
+            rank => bail!("Unsupported logits rank {rank} for a batch of {batch}"),
+        }
+    }
+
     fn pick_next_token(
-        output: ndarray::ArrayViewD<'_, f32>,
+        logits: Vec<f32>,
         history: &[i64],
         temperature: f32,
         top_k: Option<usize>,
         top_p: Option<f32>,
         repetition_penalty: f32,
         rng: &mut impl Rng,
-    ) -> Result<i64> {
-        let logits: Vec<f32> = if output.ndim() == 3 {
-            let batch = output.index_axis(Axis(0), 0);
-            let seq = batch.len_of(Axis(0));
-            batch
-                .index_axis(Axis(0), seq.saturating_sub(1))
-                .iter()
-                .copied()
-                .collect()
-        } else if output.ndim() == 2 {
-            let seq = output.len_of(Axis(0));
-            output
-                .index_axis(Axis(0), seq.saturating_sub(1))
-                .iter()
-                .copied()
-                .collect()
-        } else {
-            bail!("Unsupported logits rank {}", output.ndim());
-        };
-
-        let mut scores: Vec<(usize, f32)> = logits
-            .into_iter()
-            .enumerate()
-            .map(|(idx, v)| (idx, v))
-            .collect();
+    ) -> anyhow::Result<i64> {
+        let mut scores: Vec<(usize, f32)> = logits.into_iter().enumerate().collect();
 
         if repetition_penalty > 1.0 && !history.is_empty() {
             for (idx, score) in &mut scores {
-                if history.iter().any(|&t| t == *idx as i64) {
+                if history.contains(&(*idx as i64)) {
                     if *score > 0.0 {
                         *score /= repetition_penalty;
                     } else {
@@ -404,6 +1057,13 @@ impl CpuBackend {
     }
 }
 
+#[cfg(feature = "cpu")]
+impl Default for CpuBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(feature = "cpu")]
 impl NpuBackend for CpuBackend {
     fn name(&self) -> &str {
@@ -411,115 +1071,269 @@ impl NpuBackend for CpuBackend {
     }
 
     fn is_available(&self) -> bool {
-        true
+        self.availability().available
     }
 
-    fn load_model(&mut self, model_path: &Path) -> Result<()> {
-        if !model_path.exists() {
-            bail!("Model file not found: {}", model_path.display());
-        }
+    fn availability(&self) -> Availability {
+        runtime_availability(runtime::CPU_ORT_DLL, None)
+    }
 
-        Self::init_environment()?;
-        let session = Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level1)?
-            .commit_from_file(model_path)?;
+    fn supports_model(&self, model_path: &Path) -> bool {
+        model_files::is_onnx(model_path)
+    }
+
+    fn load_model(&mut self, model_path: &Path) -> Result<()> {
+        check_model_files(model_path)?;
 
+        runtime::init(runtime::CPU_ORT_DLL)?;
+        let session = build_session(model_path, &self.options)?;
+        self.kv_layout = KvLayout::detect(&session);
+        self.prefix_cache = self
+            .kv_layout
+            .as_ref()
+            .and_then(|_| PrefixCache::with_limit_mb(self.options.prefix_cache_mb));
         self.session = Some(session);
+        self.context_length = model_files::context_length(model_path);
         Ok(())
     }
 
-    fn run(&mut self, request: &InferenceRequest) -> Result<InferenceResponse> {
-        let input_name = request.input_name.as_deref().unwrap_or("input_ids");
-        let output_name = request.output_name.as_deref().unwrap_or("logits");
+    fn context_length(&self) -> Option<usize> {
+        self.context_length
+    }
 
-        let tokenizer = if let Some(path) = request.tokenizer_path.as_deref() {
-            self.ensure_tokenizer(path)?;
-            self.tokenizer.clone()
-        } else {
-            None
-        };
+    fn configure(&mut self, config: &ModelConfig) -> Result<()> {
+        self.options = config.session.clone();
+        Ok(())
+    }
 
+    fn token_logprobs(&mut self, request: &InferenceRequest) -> Result<Vec<f32>> {
+        let ids = request
+            .input_ids
+            .as_deref()
+            .ok_or_else(|| Error::InvalidRequest {
+                reason: "scoring requires input_ids".to_string(),
+            })?;
+        let input_name = request.input_name.as_deref().unwrap_or("input_ids");
+        let output_name = request.output_name.as_deref().unwrap_or("logits");
         let session = self
             .session
             .as_mut()
-            .context("Model is not loaded")?;
+            .ok_or_else(|| Error::ModelNotLoaded {
+                backend: self.backend_name.clone(),
+            })?;
 
-        let mut all_ids = if let Some(ids) = request.input_ids.as_ref() {
+        let inputs = Self::build_inputs(session, ids, input_name)?;
+        let outputs = session.run(inputs)?;
+        let output = outputs[output_name].try_extract_array::<f32>()?;
+        let logits = match output.ndim() {
+            3 => output.index_axis(Axis(0), 0),
+            2 => output.view(),
+            rank => {
+                return Err(Error::InvalidOutput {
+                    name: output_name.to_string(),
+                    reason: format!("has rank {rank}; expected 2 or 3"),
+                })
+            }
+        };
+        if logits.len_of(Axis(0)) != ids.len() {
+            return Err(Error::InvalidOutput {
+                name: output_name.to_string(),
+                reason: format!(
+                    "has logits for {} positions but {} tokens were scored",
+                    logits.len_of(Axis(0)),
+                    ids.len()
+                ),
+            });
+        }
+
+        let mut logprobs = Vec::with_capacity(ids.len().saturating_sub(1));
+        for (position, next) in ids.iter().skip(1).enumerate() {
+            let row = logits.index_axis(Axis(0), position);
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let log_sum = row.iter().map(|v| (v - max).exp()).sum::<f32>().ln() + max;
+            let logit = row.get(*next as usize).copied().ok_or_else(|| Error::InvalidRequest {
+                reason: format!("token id {next} is outside the vocabulary"),
+            })?;
+            logprobs.push(logit - log_sum);
+        }
+        Ok(logprobs)
+    }
+
+    fn run(&mut self, request: &InferenceRequest) -> Result<InferenceResponse> {
+        Ok(self
+            .run_batch(std::slice::from_ref(request))?
+            .pop()
+            .expect("run_batch returns one response per request"))
+    }
+
+    /// Generates for every request in one forward pass per step. Rows that
+    /// hit their stop condition leave the batch; the rest are re-padded.
+    fn run_batch(&mut self, requests: &[InferenceRequest]) -> Result<Vec<InferenceResponse>> {
+        let mut sequences = Vec::with_capacity(requests.len());
+        for request in requests {
+            let sequence = self.start_sequence(request)?;
+            if !sequences.first().is_none_or(|first: &Sequence| first.can_batch_with(request)) {
+                return Err(Error::InvalidRequest {
+                    reason: "batched requests must share input_name, output_name and tokenizer_path"
+                        .to_string(),
+                });
+            }
+            sequences.push(sequence);
+        }
+        loop {
+            let mut active: Vec<&mut Sequence> =
+                sequences.iter_mut().filter(|sequence| !sequence.done).collect();
+            if active.is_empty() {
+                break;
+            }
+            self.step(&mut active)?;
+        }
+        sequences
+            .into_iter()
+            .map(|sequence| self.finish_sequence(sequence))
+            .collect()
+    }
+
+    fn supports_steps(&self) -> bool {
+        true
+    }
+
+    fn start_sequence(&mut self, request: &InferenceRequest) -> Result<Sequence> {
+        if let Some(path) = request.tokenizer_path.as_deref() {
+            self.ensure_tokenizer(path)?;
+        }
+        let context_length = request.context_length.or(self.context_length);
+        let ids: Vec<i64> = if let Some(ids) = request.input_ids.as_ref() {
             ids.clone()
         } else {
-            let tokenizer = tokenizer
+            let tokenizer = request
+                .tokenizer_path
                 .as_ref()
-                .context("cpu backend requires a tokenizer when --input-ids is omitted")?;
+                .and(self.tokenizer.as_ref())
+                .ok_or_else(|| Error::TokenizerMissing {
+                    backend: self.backend_name.clone(),
+                })?;
             let encoding = tokenizer
                 .encode(request.prompt.as_str(), true)
-                .map_err(|e| anyhow::anyhow!("Failed to tokenize prompt: {e}"))?;
+                .map_err(|source| Error::Tokenize { source })?;
             encoding.get_ids().iter().map(|id| *id as i64).collect()
         };
 
-        let mut last_shape_first: Option<(String, f32)> = None;
-        if request.max_tokens == 0 {
-            if let Some(tokenizer) = tokenizer.as_ref() {
-                let text = tokenizer
-                    .decode(&all_ids.iter().map(|v| *v as u32).collect::<Vec<u32>>(), true)
-                    .map_err(|e| anyhow::anyhow!("Failed to decode tokens: {e}"))?;
-                return Ok(InferenceResponse { text });
+        if let Some(limit) = context_length {
+            if ids.len() >= limit {
+                return Err(Error::ContextOverflow {
+                    tokens: ids.len(),
+                    limit,
+                });
             }
-            return Ok(InferenceResponse { text: request.prompt.clone() });
         }
+        let cache = self.prefix_cache.as_mut().and_then(|cache| cache.lookup(&ids));
+        let sequence = Sequence::new(request, ids, context_length);
+        Ok(match cache {
+            Some(cache) => sequence.with_cache(cache),
+            None => sequence,
+        })
+    }
 
-        let mut rng = if let Some(seed) = request.seed {
-            StdRng::seed_from_u64(seed)
+    /// Feeds each sequence the tokens its KV cache does not cover yet (the
+    /// whole sequence when the model has no past key/value inputs), so
+    /// sequences in prefill and in decode can share a pass.
+    fn step(&mut self, sequences: &mut [&mut Sequence]) -> Result<()> {
+        let now = Instant::now();
+        for sequence in sequences.iter_mut() {
+            sequence.check_interrupted(now);
+        }
+        let mut active: Vec<&mut Sequence> = sequences
+            .iter_mut()
+            .filter(|sequence| !sequence.done)
+            .map(|sequence| &mut **sequence)
+            .collect();
+        let session = self.session.as_ref().ok_or_else(|| Error::ModelNotLoaded {
+            backend: self.backend_name.clone(),
+        })?;
+        let cached = |sequence: &Sequence| if self.kv_layout.is_some() { sequence.cached_len() } else { 0 };
+        let shape = |sequence: &Sequence| (sequence.ids.len() - cached(sequence), cached(sequence));
+        let uniform = active.windows(2).all(|pair| shape(pair[0]) == shape(pair[1]));
+        if uniform || Self::masks_padding(session) {
+            self.step_rows(&mut active)?;
         } else {
-            StdRng::from_entropy()
-        };
-
-        for _ in 0..request.max_tokens {
-            let inputs = Self::build_inputs(session, &all_ids, input_name)?;
-            let outputs = session.run(inputs)?;
-            let output = outputs[output_name].try_extract_array::<f32>()?;
-            let next_id = Self::pick_next_token(
-                output.view(),
-                &all_ids,
-                request.temperature,
-                request.top_k,
-                request.top_p,
-                request.repetition_penalty,
-                &mut rng,
-            )?;
-            let shape = output
-                .shape()
-                .iter()
-                .map(|v| v.to_string())
-                .collect::<Vec<_>>()
-                .join("x");
-            let first = output.iter().next().copied().unwrap_or(0.0);
-            last_shape_first = Some((shape, first));
-            all_ids.push(next_id);
-
-            if let Some(eos) = request.eos_token_id {
-                if next_id == eos {
-                    break;
-                }
+            // Padding would be attended to, so rows of different lengths
+            // each get their own pass.
+            for sequence in active {
+                self.step_rows(&mut [sequence])?;
             }
         }
+        Ok(())
+    }
 
-        if let Some(tokenizer) = tokenizer.as_ref() {
-            let ids: Vec<u32> = all_ids.iter().map(|v| *v as u32).collect();
-            let text = tokenizer
-                .decode(&ids, true)
-                .map_err(|e| anyhow::anyhow!("Failed to decode tokens: {e}"))?;
-            return Ok(InferenceResponse { text });
+    fn finish_sequence(&mut self, sequence: Sequence) -> Result<InferenceResponse> {
+        let request = &sequence.request;
+        let mut response = InferenceResponse {
+            prompt_tokens: Some(sequence.prompt_len),
+            completion_tokens: Some(sequence.ids.len() - sequence.prompt_len),
+            finish_reason: Some(sequence.finish_reason),
+            timings: (sequence.steps > 0).then_some(sequence.timings),
+            cached_tokens: Some(sequence.cached_tokens),
+            ..Default::default()
+        };
+        if let (Some(prefix_cache), Some(cache)) = (self.prefix_cache.as_mut(), sequence.cache.as_ref()) {
+            prefix_cache.insert(&sequence.ids, cache);
         }
+        let tokenizer = request.tokenizer_path.as_ref().and(self.tokenizer.as_ref());
+        response.text = if let Some(tokenizer) = tokenizer {
+            // Only the completion: decoding the prompt too would leave the
+            // chat template for callers to strip.
+            let mut text = Self::decode(tokenizer, &sequence.ids[sequence.prompt_len..])?;
+            if let Some(offset) = find_stop(&text, &request.stop) {
+                text.truncate(offset);
+            }
+            text
+        } else if request.max_tokens == 0 {
+            request.prompt.clone()
+        } else if let Some((shape, first)) = sequence.last_output.as_ref() {
+            format!("[cpu] output shape={} first={}", shape, first)
+        } else {
+            "[cpu] no output".to_string()
+        };
+        Ok(response)
+    }
+}
 
-        if let Some((shape, first)) = last_shape_first {
-            return Ok(InferenceResponse {
-                text: format!("[cpu] output shape={} first={}", shape, first),
-            });
+/// The past key/value inputs of a decoder exported with its KV cache, and
+/// the `present` outputs that hold their next values.
+#[cfg(feature = "cpu")]
+struct KvLayout {
+    layers: Vec<(String, String)>,
+}
+
+#[cfg(feature = "cpu")]
+impl KvLayout {
+    /// `None` unless every `past_key_values` input is a 4-D f32 tensor with
+    /// a matching `present` output and the model takes an attention mask,
+    /// which padding between sequences of different lengths relies on.
+    fn detect(session: &Session) -> Option<Self> {
+        if !session.inputs().iter().any(|input| input.name().contains("attention_mask")) {
+            return None;
+        }
+        let mut layers = Vec::new();
+        for input in session.inputs() {
+            let name = input.name();
+            if !name.contains("past_key_values") {
+                continue;
+            }
+            let (ty, shape) = CpuBackend::tensor_meta(input.dtype())?;
+            if ty != TensorElementType::Float32 || shape.len() != 4 {
+                return None;
+            }
+            let present = name.replacen("past_key_values", "present", 1);
+            session.outputs().iter().find(|output| output.name() == present)?;
+            layers.push((name.to_string(), present));
         }
+        (!layers.is_empty()).then_some(Self { layers })
+    }
 
-        Ok(InferenceResponse {
-            text: "[cpu] no output".to_string(),
-        })
+    fn position(&self, input: &str) -> Option<usize> {
+        self.layers.iter().position(|(past, _)| past == input)
     }
 }
 
@@ -537,6 +1351,13 @@ impl CpuBackend {
     }
 }
 
+#[cfg(not(feature = "cpu"))]
+impl Default for CpuBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(not(feature = "cpu"))]
 impl NpuBackend for CpuBackend {
     fn name(&self) -> &str {
@@ -547,15 +1368,24 @@ impl NpuBackend for CpuBackend {
         false
     }
 
-    fn load_model(&mut self, model_path: &Path) -> Result<()> {
-        if !model_path.exists() {
-            bail!("Model file not found: {}", model_path.display());
+    fn availability(&self) -> Availability {
+        Availability {
+            available: false,
+            reasons: vec!["llm-toy was built without the cpu feature".to_string()],
+            details: Vec::new(),
         }
+    }
+
+    fn load_model(&mut self, model_path: &Path) -> Result<()> {
+        check_model_files(model_path)?;
         Ok(())
     }
 
     fn run(&mut self, _request: &InferenceRequest) -> Result<InferenceResponse> {
-        bail!("cpu backend requires the 'cpu' feature")
+        Err(Error::BackendUnavailable {
+            backend: self.backend_name.clone(),
+            reasons: self.availability().reasons,
+        })
     }
 }
 
@@ -579,14 +1409,12 @@ impl RyzenAiBackend {
             session: None
         }
     }
+}
 
-    fn init_environment() -> Result<()> {
-        if let Ok(path) = std::env::var("RYZEN_AI_ORT_DLL") {
-            ort::init_from(path)?.with_name("llm-toy").commit();
-        } else {
-            ort::init().with_name("llm-toy").commit();
-        }
-        Ok(())
+#[cfg(all(windows, feature = "ryzen-ai"))]
+impl Default for RyzenAiBackend {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -597,15 +1425,21 @@ impl NpuBackend for RyzenAiBackend {
     }
 
     fn is_available(&self) -> bool {
-        true
+        self.availability().available
+    }
+
+    fn availability(&self) -> Availability {
+        runtime_availability(runtime::RYZEN_AI_ORT_DLL, Some("vitis"))
+    }
+
+    fn supports_model(&self, model_path: &Path) -> bool {
+        model_files::is_onnx(model_path)
     }
 
     fn load_model(&mut self, model_path: &Path) -> Result<()> {
-        if !model_path.exists() {
-            bail!("Model file not found: {}", model_path.display());
-        }
+        check_model_files(model_path)?;
 
-        Self::init_environment()?;
+        runtime::init(runtime::RYZEN_AI_ORT_DLL)?;
         let session = Session::builder()?
             .with_optimization_level(GraphOptimizationLevel::Level1)?
             .commit_from_file(model_path)?;
@@ -618,12 +1452,16 @@ impl NpuBackend for RyzenAiBackend {
         let session = self
             .session
             .as_mut()
-            .context("Model is not loaded")?;
+            .ok_or_else(|| Error::ModelNotLoaded {
+                backend: self.backend_name.clone(),
+            })?;
 
         let input_ids = request
             .input_ids
             .as_ref()
-            .context("ryzen-ai backend requires --input-ids")?;
+            .ok_or_else(|| Error::InvalidRequest {
+                reason: "the ryzen-ai backend requires input ids (--input-ids)".to_string(),
+            })?;
 
         let input_name = request.input_name.as_deref().unwrap_or("input_ids");
         let output_name = request.output_name.as_deref().unwrap_or("logits");
@@ -631,8 +1469,7 @@ impl NpuBackend for RyzenAiBackend {
         let input_tensor = Tensor::from_array((
             [1usize, input_ids.len()],
             input_ids.clone(),
-        ))
-        .context("Failed to build input tensor")?;
+        ))?;
 
         let outputs = session.run(ort::inputs![input_name => input_tensor])?;
         let output = outputs[output_name].try_extract_array::<f32>()?;
@@ -645,7 +1482,8 @@ impl NpuBackend for RyzenAiBackend {
         let first = output.iter().next().copied().unwrap_or(0.0);
 
         Ok(InferenceResponse {
-            text: format!("[ryzen-ai] output shape={} first={}", shape, first)
+            text: format!("[ryzen-ai] output shape={} first={}", shape, first),
+            ..Default::default()
         })
     }
 }
@@ -664,6 +1502,13 @@ impl RyzenAiBackend {
     }
 }
 
+#[cfg(all(windows, not(feature = "ryzen-ai")))]
+impl Default for RyzenAiBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(all(windows, not(feature = "ryzen-ai")))]
 impl NpuBackend for RyzenAiBackend {
     fn name(&self) -> &str {
@@ -671,38 +1516,143 @@ impl NpuBackend for RyzenAiBackend {
     }
 
     fn is_available(&self) -> bool {
-        true
+        false
     }
 
-    fn load_model(&mut self, model_path: &Path) -> Result<()> {
-        if !model_path.exists() {
-            bail!("Model file not found: {}", model_path.display());
+    fn availability(&self) -> Availability {
+        Availability {
+            available: false,
+            reasons: vec!["llm-toy was built without the ryzen-ai feature".to_string()],
+            details: Vec::new(),
         }
+    }
+
+    fn load_model(&mut self, model_path: &Path) -> Result<()> {
+        check_model_files(model_path)?;
         Ok(())
     }
 
     fn run(&mut self, request: &InferenceRequest) -> Result<InferenceResponse> {
         let text = format!("[ryzen-ai:placeholder] {}", request.prompt);
-        Ok(InferenceResponse { text })
+        Ok(InferenceResponse {
+            text,
+            ..Default::default()
+        })
     }
 }
 
+/// Constructs the registered backend called `name`.
 pub fn load_backend(name: &str) -> Result<Box<dyn NpuBackend>> {
-    match name {
-        "cpu" => Ok(Box::new(CpuBackend::new())),
-        #[cfg(windows)]
-        "ryzen-ai" => Ok(Box::new(RyzenAiBackend::new())),
-        "amd-xdna" => Ok(Box::new(AmdXdnaBackend::new())),
-        _ => Ok(Box::new(PlaceholderNpuBackend::new(name))),
-    }
+    Ok(registry::find_backend(name)?.create())
 }
 
+/// Backend name that picks the first usable backend with `select_backend`.
+pub const AUTO_BACKEND: &str = "auto";
+pub const DEFAULT_BACKEND_PREFERENCE: [&str; 3] = ["amd-xdna", "ryzen-ai", "cpu"];
+
 pub fn load_model(config: &ModelConfig) -> Result<Box<dyn NpuBackend>> {
+    if config.npu_backend == AUTO_BACKEND {
+        return Ok(select_backend(config)?.backend);
+    }
     let mut backend = load_backend(&config.npu_backend)?;
+    check_backend(backend.as_ref(), config)?;
+    backend.configure(config)?;
+    backend.load_model(Path::new(&config.path))?;
+    Ok(backend)
+}
+
+fn check_backend(backend: &dyn NpuBackend, config: &ModelConfig) -> Result<()> {
+    let availability = backend.availability();
+    if !availability.available {
+        return Err(Error::BackendUnavailable {
+            backend: backend.name().to_string(),
+            reasons: availability.reasons,
+        });
+    }
     let model_path = Path::new(&config.path);
-    if !backend.is_available() {
-        bail!("NPU backend '{}' is not available", backend.name());
+    if !backend.supports_model(model_path) {
+        return Err(Error::UnsupportedModel {
+            backend: backend.name().to_string(),
+            path: model_path.to_path_buf(),
+            reason: model_files::is_gguf(model_path).then(|| {
+                "only the placeholder backend accepts GGUF models (split or not); use an ONNX export".to_string()
+            }),
+        });
     }
-    backend.load_model(model_path)?;
-    Ok(backend)
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedBackend {
+    pub name: String,
+    pub reason: String,
+}
+
+pub struct BackendSelection {
+    pub backend: Box<dyn NpuBackend>,
+    /// Backends tried before the chosen one, and why each was passed over.
+    pub skipped: Vec<SkippedBackend>,
+}
+
+impl BackendSelection {
+    pub fn summary(&self) -> String {
+        let mut summary = format!("Selected backend '{}'", self.backend.name());
+        for skip in &self.skipped {
+            summary.push_str(&format!("\n  skipped {} {}", skip.name, skip.reason));
+        }
+        summary
+    }
+}
+
+/// Loads the model with the first backend in `config.backend_preference`
+/// that is available, supports the model format and loads it successfully.
+/// Placeholder backends are never picked.
+pub fn select_backend(config: &ModelConfig) -> Result<BackendSelection> {
+    let preference: Vec<&str> = if config.backend_preference.is_empty() {
+        DEFAULT_BACKEND_PREFERENCE.to_vec()
+    } else {
+        config.backend_preference.iter().map(String::as_str).collect()
+    };
+    let mut skipped = Vec::new();
+    for name in preference {
+        if registry::find_backend(name).is_ok_and(|entry| entry.has(registry::Capability::Placeholder)) {
+            skipped.push(SkippedBackend {
+                name: name.to_string(),
+                reason: "is a placeholder that echoes the prompt".to_string(),
+            });
+            continue;
+        }
+        let checked = load_backend(name)
+            .and_then(|backend| check_backend(backend.as_ref(), config).map(|()| backend));
+        let reason = match checked {
+            Err(Error::BackendUnavailable { reasons, .. }) if reasons.is_empty() => {
+                "is not available".to_string()
+            }
+            Err(Error::BackendUnavailable { reasons, .. }) => {
+                format!("is not available: {}", reasons.join("; "))
+            }
+            Err(Error::UnsupportedModel { path, reason, .. }) => match reason {
+                Some(reason) => format!("cannot load {}: {reason}", path.display()),
+                None => format!("cannot load {}", path.display()),
+            },
+            Err(err) => format!("cannot be constructed: {err:#}"),
+            Ok(mut backend) => {
+                let loaded = backend
+                    .configure(config)
+                    .and_then(|()| backend.load_model(Path::new(&config.path)));
+                match loaded {
+                    Ok(()) => return Ok(BackendSelection { backend, skipped }),
+                    Err(err) => format!("failed to load the model: {err:#}"),
+                }
+            }
+        };
+        skipped.push(SkippedBackend {
+            name: name.to_string(),
+            reason,
+        });
+    }
+    Err(Error::NoBackend {
+        path: PathBuf::from(&config.path),
+        skipped,
+    })
 }
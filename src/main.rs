@@ -1,10 +1,33 @@
 use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
-use llm_toy::{load_model, InferenceRequest, ModelConfig};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use llm_toy::embedding::{text_hash, write_npy, Embedder, EmbeddingOptions, OnnxEmbedder, Pooling, VectorIndex};
+use llm_toy::config::{apply_chat_template, clean_answer, Config, Profile};
+use llm_toy::memory::{
+    plan_memory, retrieve, summarize_history, sync_vector_index, ApproxTokenCounter, ContextBudget, ExportFormat, MemoryEntry, MemorySession,
+    MemoryState, MemoryStore, TokenCounter, TruncationStrategy, DEFAULT_SESSION,
+};
+use llm_toy::bench::{process_memory, render_table, BenchRow, Stats};
+use llm_toy::batch::{read_batch_items, read_progress, BatchItem, BatchResult};
+use llm_toy::eval::{perplexity, score_completion, ScoreItem, ScoreResult};
+use llm_toy::registry::{backends, find_backend, Capability};
+use llm_toy::runtime::{self, RuntimeInfo};
+use llm_toy::system::{cpu_info, format_bytes, free_space, system_memory};
+use llm_toy::rag::{grounded_prompt, DocumentIndex, IndexManifest, DEFAULT_CHUNK_SIZE};
+use llm_toy::model_files::{
+    companion_files, context_length as context_length_from_metadata, model_files, total_size,
+};
+use llm_toy::{
+    load_backend, load_model, load_tokenizer, select_backend, CancellationToken, FinishReason,
+    GenerationConfig, InferenceRequest, ModelConfig, NpuBackend, OptimizationLevel, SessionOptions,
+    AUTO_BACKEND, EXECUTION_PROVIDERS,
+};
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokenizers::Tokenizer;
 
 #[derive(Parser, Debug)]
 #[command(name = "llm-toy", version, about = "Run downloaded LLM modules on a laptop NPU")]
@@ -13,58 +36,415 @@ struct Cli {
     command: Commands,
 }
 
+#[derive(Args, Debug)]
+struct ModelArgs {
+    #[arg(long)]
+    model: Option<PathBuf>,
+    #[arg(long)]
+    model_url: Option<String>,
+    #[arg(long)]
+    tokenizer: Option<PathBuf>,
+    #[arg(long)]
+    tokenizer_url: Option<String>,
+    #[arg(long)]
+    backend: Option<String>,
+    #[arg(long)]
+    profile: Option<String>,
+    #[arg(long)]
+    context_length: Option<usize>,
+    /// Backends `--backend auto` tries in order, e.g. amd-xdna,cpu
+    #[arg(long, value_delimiter = ',')]
+    backend_preference: Vec<String>,
+    #[command(flatten)]
+    session: SessionArgs,
+}
+
+// ONNX Runtime session settings; unset flags fall back to the profile. A
+// plain comment, since clap would show a doc comment as the about text of
+// every subcommand that flattens it.
+#[derive(Args, Debug)]
+struct SessionArgs {
+    #[arg(long)]
+    intra_threads: Option<usize>,
+    #[arg(long)]
+    inter_threads: Option<usize>,
+    /// disable, basic, extended, layout or all
+    #[arg(long)]
+    optimization_level: Option<OptimizationLevel>,
+    /// Save the optimized model here and load it on later runs
+    #[arg(long)]
+    optimized_model_path: Option<PathBuf>,
+    #[arg(long)]
+    memory_pattern: Option<bool>,
+    #[arg(long)]
+    cpu_arena: Option<bool>,
+    #[arg(long)]
+    mmap: Option<bool>,
+    /// Execution providers in priority order, e.g. cuda,cpu
+    #[arg(long, value_delimiter = ',')]
+    execution_providers: Vec<String>,
+    /// MiB of KV cache kept for reusing shared prompt prefixes; 0 disables it
+    #[arg(long)]
+    prefix_cache_mb: Option<usize>,
+}
+
+impl SessionArgs {
+    fn options(self, profile: &Profile) -> Result<SessionOptions> {
+        let execution_providers = if self.execution_providers.is_empty() {
+            profile.execution_providers.clone().unwrap_or_default()
+        } else {
+            self.execution_providers
+        };
+        let execution_providers: Vec<String> = execution_providers
+            .iter()
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        for name in &execution_providers {
+            if !EXECUTION_PROVIDERS.contains(&name.as_str()) {
+                bail!(
+                    "Unknown execution provider '{name}' (expected one of: {})",
+                    EXECUTION_PROVIDERS.join(", ")
+                );
+            }
+        }
+        Ok(SessionOptions {
+            intra_threads: self.intra_threads.or(profile.intra_threads),
+            inter_threads: self.inter_threads.or(profile.inter_threads),
+            optimization_level: self
+                .optimization_level
+                .or(profile.optimization_level)
+                .unwrap_or_default(),
+            optimized_model_path: self
+                .optimized_model_path
+                .map(|path| path.to_string_lossy().to_string())
+                .or_else(|| profile.optimized_model_path.clone()),
+            memory_pattern: self.memory_pattern.or(profile.memory_pattern),
+            cpu_arena: self.cpu_arena.or(profile.cpu_arena),
+            mmap: self.mmap.or(profile.mmap),
+            execution_providers,
+            prefix_cache_mb: self.prefix_cache_mb.or(profile.prefix_cache_mb),
+        })
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+struct SamplingArgs {
+    #[arg(long)]
+    input_name: Option<String>,
+    #[arg(long)]
+    output_name: Option<String>,
+    #[arg(long)]
+    max_tokens: Option<usize>,
+    #[arg(long)]
+    eos_token_id: Option<i64>,
+    #[arg(long)]
+    temperature: Option<f32>,
+    #[arg(long)]
+    top_k: Option<usize>,
+    #[arg(long)]
+    top_p: Option<f32>,
+    #[arg(long)]
+    repetition_penalty: Option<f32>,
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long)]
+    stop: Vec<String>,
+    /// Stop generating after this many seconds and keep what was produced.
+    #[arg(long, value_name = "SECONDS")]
+    max_time: Option<f64>,
+}
+
+#[derive(Args, Debug)]
+struct TokenizerArgs {
+    #[arg(long)]
+    tokenizer: Option<PathBuf>,
+    #[arg(long)]
+    tokenizer_url: Option<String>,
+    #[arg(long)]
+    profile: Option<String>,
+}
+
+impl TokenizerArgs {
+    fn load(self) -> Result<(Tokenizer, Profile)> {
+        let profile = Config::load()?.profile(self.profile.as_deref())?;
+        let backend = profile.backend.as_deref().unwrap_or(DEFAULT_BACKEND);
+        let path = resolve_tokenizer_path(self.tokenizer, self.tokenizer_url, backend, &profile, false)?
+            .context("A tokenizer is required: pass --tokenizer or --tokenizer-url (or use a profile tokenizer)")?;
+        Ok((load_tokenizer(&path)?, profile))
+    }
+}
+
+#[derive(Args, Debug)]
+struct IndexArgs {
+    dir: PathBuf,
+    #[arg(long)]
+    output: Option<PathBuf>,
+    #[arg(long)]
+    embedding_model: Option<String>,
+    #[arg(long)]
+    embedding_tokenizer: Option<String>,
+    #[arg(long)]
+    profile: Option<String>,
+    #[arg(long)]
+    pooling: Option<Pooling>,
+    #[arg(long)]
+    chunk_size: Option<usize>,
+    #[command(flatten)]
+    session: SessionArgs,
+}
+
+#[derive(Args, Debug)]
+struct AskArgs {
+    #[command(flatten)]
+    model: ModelArgs,
+    #[command(flatten)]
+    sampling: SamplingArgs,
+    #[arg(long)]
+    index: PathBuf,
+    #[arg(long)]
+    prompt: String,
+    #[arg(long)]
+    chunks: Option<usize>,
+}
+
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     Run {
+        #[command(flatten)]
+        model: ModelArgs,
+        #[command(flatten)]
+        sampling: SamplingArgs,
         #[arg(long)]
-        model: Option<PathBuf>,
+        prompt: String,
         #[arg(long)]
-        model_url: Option<String>,
+        input_ids: Option<String>,
+        #[arg(long, default_value_t = false)]
+        memory: bool,
         #[arg(long)]
-        tokenizer: Option<PathBuf>,
+        memory_file: Option<PathBuf>,
+        #[arg(long, default_value_t = false)]
+        memory_clear: bool,
         #[arg(long)]
-        tokenizer_url: Option<String>,
-        #[arg(long, default_value = "placeholder")]
-        backend: String,
+        session: Option<String>,
         #[arg(long)]
-        prompt: String,
+        system_prompt: Option<String>,
         #[arg(long)]
-        input_ids: Option<String>,
+        truncation: Option<TruncationStrategy>,
+        #[arg(long, default_value_t = false)]
+        summarize: bool,
+        #[arg(long)]
+        summary_tokens: Option<usize>,
+        #[arg(long, default_value_t = false)]
+        retrieval: bool,
+        #[arg(long)]
+        embedding_model: Option<String>,
+        #[arg(long)]
+        embedding_tokenizer: Option<String>,
+        #[arg(long)]
+        retrieval_top_k: Option<usize>,
+    },
+    Info {
+        #[command(flatten)]
+        model: ModelArgs,
+    },
+    Memory {
+        #[command(subcommand)]
+        command: MemoryCommands,
+    },
+    Index(IndexArgs),
+    Batch {
+        #[command(flatten)]
+        model: ModelArgs,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[arg(long)]
+        input: PathBuf,
+        #[arg(long)]
+        output: PathBuf,
+        #[arg(long, default_value_t = false)]
+        resume: bool,
+        #[arg(long, default_value_t = 1)]
+        workers: usize,
+        #[arg(long, default_value_t = 1)]
+        batch_size: usize,
+    },
+    Bench {
+        #[command(flatten)]
+        model: ModelArgs,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[arg(long, value_delimiter = ',', default_value = "128")]
+        prompt_lengths: Vec<usize>,
+        #[arg(long, value_delimiter = ',', default_value = "32")]
+        gen_lengths: Vec<usize>,
+        #[arg(long, value_delimiter = ',')]
+        threads: Vec<usize>,
+        #[arg(long, default_value_t = 3)]
+        repetitions: usize,
+        #[arg(long, default_value_t = 1)]
+        warmup: usize,
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
+    Perplexity {
+        #[command(flatten)]
+        model: ModelArgs,
+        #[arg(long)]
+        file: PathBuf,
+        #[arg(long)]
+        window: Option<usize>,
+        #[arg(long)]
+        stride: Option<usize>,
         #[arg(long)]
         input_name: Option<String>,
         #[arg(long)]
         output_name: Option<String>,
-        #[arg(long, default_value_t = 128)]
-        max_tokens: usize,
-        #[arg(long)]
-        eos_token_id: Option<i64>,
-        #[arg(long, default_value_t = 0.5)]
-        temperature: f32,
-        #[arg(long, default_value_t = 20)]
-        top_k: usize,
-        #[arg(long, default_value_t = 0.85)]
-        top_p: f32,
-        #[arg(long, default_value_t = 1.2)]
-        repetition_penalty: f32,
-        #[arg(long)]
-        seed: Option<u64>,
         #[arg(long, default_value_t = false)]
-        memory: bool,
+        json: bool,
+    },
+    Score {
+        #[command(flatten)]
+        model: ModelArgs,
         #[arg(long)]
-        memory_file: Option<PathBuf>,
+        prompt: Option<String>,
+        #[arg(long)]
+        completion: Vec<String>,
+        #[arg(long)]
+        input: Option<PathBuf>,
+        #[arg(long)]
+        output: Option<PathBuf>,
+        #[arg(long)]
+        input_name: Option<String>,
+        #[arg(long)]
+        output_name: Option<String>,
         #[arg(long, default_value_t = false)]
-        memory_clear: bool,
+        json: bool,
     },
-    Info {
+    Tokenize {
+        text: Option<String>,
+        #[arg(long)]
+        file: Vec<PathBuf>,
+        #[command(flatten)]
+        tokenizer: TokenizerArgs,
+        #[arg(long, default_value_t = false)]
+        no_special_tokens: bool,
+        #[arg(long, value_enum, default_value_t = TokenizeFormat::Table)]
+        format: TokenizeFormat,
+        #[arg(long)]
+        context_length: Option<usize>,
+    },
+    Detokenize {
+        ids: String,
+        #[command(flatten)]
+        tokenizer: TokenizerArgs,
+        #[arg(long, default_value_t = false)]
+        skip_special_tokens: bool,
+        #[arg(long, default_value_t = false)]
+        tokens: bool,
+    },
+    Ask(AskArgs),
+    Embed {
+        #[arg(long)]
+        model: Option<String>,
+        #[arg(long)]
+        tokenizer: Option<String>,
+        #[arg(long)]
+        profile: Option<String>,
+        #[arg(long)]
+        input: Option<PathBuf>,
+        #[arg(long, value_enum)]
+        input_format: Option<EmbedInputFormat>,
+        #[arg(long, default_value = "text")]
+        text_field: String,
+        #[arg(long)]
+        output: Option<PathBuf>,
+        #[arg(long, value_enum)]
+        output_format: Option<EmbedOutputFormat>,
+        #[arg(long)]
+        pooling: Option<Pooling>,
+        #[arg(long, default_value_t = false)]
+        no_normalize: bool,
         #[arg(long)]
-        model: Option<PathBuf>,
+        max_length: Option<usize>,
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+    /// Report the build and ONNX Runtime environment.
+    Doctor,
+    /// List registered backends and their capabilities.
+    Backends {
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum MemoryCommands {
+    List,
+    Delete {
+        session: String,
+    },
+    Export {
+        session: String,
+        #[arg(long, value_enum, default_value_t = ExportFormatArg::Json)]
+        format: ExportFormatArg,
         #[arg(long)]
-        model_url: Option<String>,
-        #[arg(long, default_value = "placeholder")]
-        backend: String,
+        output: Option<PathBuf>,
     },
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ExportFormatArg {
+    Json,
+    Markdown,
+}
+
+impl From<ExportFormatArg> for ExportFormat {
+    fn from(value: ExportFormatArg) -> Self {
+        match value {
+            ExportFormatArg::Json => ExportFormat::Json,
+            ExportFormatArg::Markdown => ExportFormat::Markdown,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum EmbedInputFormat {
+    Lines,
+    Jsonl,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum EmbedOutputFormat {
+    Jsonl,
+    Npy,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TokenizeFormat {
+    Table,
+    Json,
+    Ids,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ReportFormat {
+    Table,
+    Json,
+}
+
+const DEFAULT_BACKEND: &str = "placeholder";
+const DEFAULT_CONTEXT_LENGTH: usize = 2048;
+const DEFAULT_SUMMARY_TOKENS: usize = 256;
+// Summaries get their own time budget so they do not eat into `--max-time`.
+const SUMMARY_MAX_TIME: Duration = Duration::from_secs(120);
+const DEFAULT_RETRIEVAL_TOP_K: usize = 3;
+const EMBED_CHUNK_SIZE: usize = 32;
+const DEFAULT_ASK_CHUNKS: usize = 4;
+const DEFAULT_PERPLEXITY_WINDOW: usize = 512;
+const INDEX_DIR_NAME: &str = ".llm-toy-index";
+
 const DEFAULT_QWEN_URL: &str =
     "https://huggingface.co/Qwen/Qwen2.5-1.5B-Instruct-GGUF/resolve/main/qwen2.5-1.5b-instruct-q4_k_m.gguf";
 const DEFAULT_QWEN_FILENAME: &str = "qwen2.5-1.5b-instruct-q4_k_m.gguf";
@@ -74,32 +454,47 @@ fn default_cache_dir() -> Result<PathBuf> {
     Ok(base.join("llm-toy"))
 }
 
-fn ensure_qwen_model() -> Result<PathBuf> {
-    let cache_dir = default_cache_dir()?;
-    fs::create_dir_all(&cache_dir)?;
-    let model_path = cache_dir.join(DEFAULT_QWEN_FILENAME);
-
-    if model_path.exists() {
-        return Ok(model_path);
+fn download_file(url: &str, path: &Path, what: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
     }
 
-    println!("Downloading default Qwen model to {}", model_path.display());
+    println!("Downloading {what} to {}", path.display());
     let response = download_agent()?
-        .get(DEFAULT_QWEN_URL)
+        .get(url)
         .call()
-        .context("Failed to download Qwen model")?;
+        .with_context(|| format!("Failed to download {what}"))?;
 
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("download");
+    let partial_path = path.with_file_name(format!("{file_name}.part"));
     let mut reader = response.into_reader();
-    let mut file = fs::File::create(&model_path)?;
+    let mut file = fs::File::create(&partial_path)?;
     std::io::copy(&mut reader, &mut file)?;
     file.flush()?;
+    drop(file);
+    fs::rename(&partial_path, path)?;
+
+    Ok(())
+}
+
+fn ensure_qwen_model() -> Result<PathBuf> {
+    let cache_dir = default_cache_dir()?;
+    fs::create_dir_all(&cache_dir)?;
+    let model_path = cache_dir.join(DEFAULT_QWEN_FILENAME);
+
+    if !model_path.exists() {
+        download_file(DEFAULT_QWEN_URL, &model_path, "default Qwen model")?;
+    }
 
     Ok(model_path)
 }
 
 fn model_filename_from_url(model_url: &str) -> String {
     if let Ok(url) = url::Url::parse(model_url) {
-        if let Some(name) = url.path_segments().and_then(|segments| segments.last()) {
+        if let Some(name) = url.path_segments().and_then(|mut segments| segments.next_back()) {
             if !name.is_empty() {
                 return name.to_string();
             }
@@ -108,148 +503,226 @@ fn model_filename_from_url(model_url: &str) -> String {
     "model.onnx".to_string()
 }
 
+fn companion_url(model_url: &str, name: &str) -> Result<String> {
+    let base = url::Url::parse(model_url).context("Invalid model URL")?;
+    let mut url = base
+        .join(name)
+        .with_context(|| format!("Failed to build URL for companion file {name}"))?;
+    url.set_query(base.query());
+    Ok(url.to_string())
+}
+
+/// Cache directory for files downloaded from `url`, named after a hash of the
+/// remote directory. Companion files are fetched relative to the model URL,
+/// so each cached file has exactly one source, and two models that both ship
+/// `model.onnx_data` or `tokenizer.json` do not overwrite each other.
+fn url_cache_dir(url: &str) -> Result<PathBuf> {
+    let mut url = url::Url::parse(url).with_context(|| format!("Invalid URL {url}"))?;
+    url.set_query(None);
+    url.set_fragment(None);
+    let dir = url.join(".").unwrap_or(url);
+    Ok(default_cache_dir()?
+        .join("downloads")
+        .join(format!("{:016x}", text_hash(dir.as_str()))))
+}
+
 fn ensure_model_from_url(model_url: &str) -> Result<PathBuf> {
-    let cache_dir = default_cache_dir()?;
+    let cache_dir = url_cache_dir(model_url)?;
     fs::create_dir_all(&cache_dir)?;
 
     let filename = model_filename_from_url(model_url);
     let model_path = cache_dir.join(filename);
-    if model_path.exists() {
-        return Ok(model_path);
+    if !model_path.exists() {
+        download_file(model_url, &model_path, "model")?;
     }
 
-    println!("Downloading model to {}", model_path.display());
-    let response = download_agent()?
-        .get(model_url)
-        .call()
-        .context("Failed to download model")?;
-
-    let mut reader = response.into_reader();
-    let mut file = fs::File::create(&model_path)?;
-    std::io::copy(&mut reader, &mut file)?;
-    file.flush()?;
+    for name in companion_files(&model_path)? {
+        let path = cache_dir.join(&name);
+        if !path.exists() {
+            download_file(&companion_url(model_url, &name)?, &path, "model companion file")?;
+        }
+    }
 
-    Ok(model_path)
+    Ok(model_files(&model_path)?.remove(0))
 }
 
 fn ensure_tokenizer_from_url(tokenizer_url: &str) -> Result<PathBuf> {
-    let cache_dir = default_cache_dir()?;
+    let cache_dir = url_cache_dir(tokenizer_url)?;
     fs::create_dir_all(&cache_dir)?;
 
     let filename = model_filename_from_url(tokenizer_url);
     let tokenizer_path = cache_dir.join(filename);
-    if tokenizer_path.exists() {
-        return Ok(tokenizer_path);
+    if !tokenizer_path.exists() {
+        download_file(tokenizer_url, &tokenizer_path, "tokenizer")?;
     }
 
-    println!("Downloading tokenizer to {}", tokenizer_path.display());
-    let response = download_agent()?
-        .get(tokenizer_url)
-        .call()
-        .context("Failed to download tokenizer")?;
-
-    let mut reader = response.into_reader();
-    let mut file = fs::File::create(&tokenizer_path)?;
-    std::io::copy(&mut reader, &mut file)?;
-    file.flush()?;
-
     Ok(tokenizer_path)
 }
 
-fn default_memory_path() -> Result<PathBuf> {
+fn memory_store() -> Result<MemoryStore> {
     let cache_dir = default_cache_dir()?;
-    fs::create_dir_all(&cache_dir)?;
-    Ok(cache_dir.join("memory.json"))
-}
+    let store = MemoryStore::new(cache_dir.join("sessions"));
 
-#[derive(serde::Serialize, serde::Deserialize, Default, Clone)]
-struct MemoryEntry {
-    prompt: String,
-    response: String,
+    let legacy_path = cache_dir.join("memory.json");
+    let default_session = store.session(DEFAULT_SESSION)?;
+    if legacy_path.exists() && !default_session.exists() {
+        fs::create_dir_all(store.dir())?;
+        fs::rename(&legacy_path, default_session.path())?;
+    }
+    Ok(store)
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Default)]
-struct MemoryState {
-    last_prompt: Option<String>,
-    last_response: Option<String>,
-    #[serde(default)]
-    conversation_history: Vec<MemoryEntry>,
+struct RetrievalMemory {
+    embedder: OnnxEmbedder,
+    index: VectorIndex,
+    index_path: PathBuf,
 }
 
-fn load_memory(path: &PathBuf) -> Result<MemoryState> {
-    if !path.exists() {
-        return Ok(MemoryState::default());
+impl RetrievalMemory {
+    fn open(
+        session: &MemorySession,
+        model: &str,
+        tokenizer: &str,
+        pooling: Pooling,
+        session_options: &SessionOptions,
+    ) -> Result<Self> {
+        let model_path = resolve_local_or_url(model, FileKind::Model, "embedding model")?;
+        let tokenizer_path = resolve_local_or_url(tokenizer, FileKind::Tokenizer, "embedding tokenizer")?;
+        let options = EmbeddingOptions {
+            pooling,
+            ..EmbeddingOptions::default()
+        };
+        let embedder = OnnxEmbedder::load_with_session(&model_path, &tokenizer_path, options, session_options)?;
+
+        let index_path = session.vector_index_path();
+        // Vectors from different poolings are not comparable, so the pooling
+        // is part of the index identity.
+        let model_id = format!("{} ({pooling:?})", model_path.display());
+        let index = match VectorIndex::load(&index_path)? {
+            Some(index) if index.model == model_id => index,
+            _ => VectorIndex::new(model_id),
+        };
+        Ok(Self {
+            embedder,
+            index,
+            index_path,
+        })
+    }
+
+    fn sync(&mut self, state: &MemoryState) -> Result<()> {
+        if sync_vector_index(state, &mut self.index, &mut self.embedder)? {
+            self.index.save(&self.index_path)?;
+        }
+        Ok(())
+    }
+
+    fn query(&mut self, prompt: &str, top_k: usize) -> Result<Vec<usize>> {
+        if self.index.entries.is_empty() || top_k == 0 {
+            return Ok(Vec::new());
+        }
+        let query = self
+            .embedder
+            .embed(&[prompt])?
+            .pop()
+            .context("Embedding model returned no vector")?;
+        Ok(retrieve(&self.index, &query, top_k))
     }
-    let data = fs::read_to_string(path)?;
-    let state = serde_json::from_str(&data).unwrap_or_default();
-    Ok(state)
 }
 
-fn save_memory(path: &PathBuf, state: &MemoryState) -> Result<()> {
-    let data = serde_json::to_string_pretty(state)?;
-    fs::write(path, data)?;
-    Ok(())
+struct EmbedInput {
+    text: String,
+    record: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
-fn apply_memory(prompt: &str, memory: &MemoryState) -> String {
-    const MAX_MEMORY_CHARS: usize = 2000;
-    const MAX_MEMORY_LINES: usize = 20;
-    const MAX_HISTORY: usize = 3;
+fn read_embed_inputs(
+    input: Option<&Path>,
+    format: EmbedInputFormat,
+    text_field: &str,
+) -> Result<Vec<EmbedInput>> {
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => Box::new(BufReader::new(
+            fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        )),
+        None => Box::new(BufReader::new(std::io::stdin())),
+    };
 
-    fn clamp_text(text: &str) -> String {
-        let normalized = text.replace("\r\n", "\n").replace('\r', "\n");
-        let mut lines: Vec<&str> = normalized.lines().collect();
-        if lines.len() > MAX_MEMORY_LINES {
-            lines.truncate(MAX_MEMORY_LINES);
-            lines.push("[...]");
+    let mut inputs = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
         }
-        let mut s = lines.join("\n");
-        if s.len() > MAX_MEMORY_CHARS {
-            s.truncate(MAX_MEMORY_CHARS);
-            s.push_str("\n[...]");
+        if format == EmbedInputFormat::Lines {
+            inputs.push(EmbedInput {
+                text: line,
+                record: None,
+            });
+            continue;
         }
-        s
+        let value: serde_json::Value = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid JSON on line {}", number + 1))?;
+        let input = match value {
+            serde_json::Value::String(text) => EmbedInput { text, record: None },
+            serde_json::Value::Object(record) => {
+                let text = record
+                    .get(text_field)
+                    .and_then(|value| value.as_str())
+                    .with_context(|| {
+                        format!("Line {} has no string field '{text_field}'", number + 1)
+                    })?
+                    .to_string();
+                EmbedInput {
+                    text,
+                    record: Some(record),
+                }
+            }
+            _ => bail!("Line {} must be a JSON string or object", number + 1),
+        };
+        inputs.push(input);
     }
+    Ok(inputs)
+}
 
-    let mut combined = String::new();
-    if !memory.conversation_history.is_empty() || memory.last_prompt.is_some() || memory.last_response.is_some() {
-        combined.push_str("### Previous\n");
-        if !memory.conversation_history.is_empty() {
-            let start = memory.conversation_history.len().saturating_sub(MAX_HISTORY);
-            for entry in &memory.conversation_history[start..] {
-                combined.push_str("User:\n");
-                combined.push_str(&clamp_text(&entry.prompt));
-                combined.push_str("\n\nAssistant:\n");
-                combined.push_str(&clamp_text(&entry.response));
-                combined.push_str("\n\n");
-            }
-        }
-        if let Some(prev) = memory.last_prompt.as_ref() {
-            combined.push_str("User:\n");
-            combined.push_str(&clamp_text(prev));
-            combined.push_str("\n\n");
-        }
-        if let Some(resp) = memory.last_response.as_ref() {
-            combined.push_str("Assistant:\n");
-            combined.push_str(&clamp_text(resp));
-            combined.push_str("\n\n");
-        }
+fn write_embeddings_jsonl(
+    writer: &mut impl Write,
+    inputs: Vec<EmbedInput>,
+    vectors: Vec<Vec<f32>>,
+) -> Result<()> {
+    for (input, vector) in inputs.into_iter().zip(vectors) {
+        let mut record = input.record.unwrap_or_else(|| {
+            let mut record = serde_json::Map::new();
+            record.insert("text".to_string(), input.text.into());
+            record
+        });
+        record.insert("embedding".to_string(), vector.into());
+        serde_json::to_writer(&mut *writer, &record)?;
+        writer.write_all(b"\n")?;
     }
-    combined.push_str("### Current\nUser:\n");
-    combined.push_str(prompt);
-    combined.push_str("\n\nAssistant:");
-    combined
+    Ok(())
 }
 
-fn clean_answer(original_prompt: &str, answer: &str) -> String {
-    let mut out = answer.trim().to_string();
-    if let Some(idx) = out.rfind("Assistant:") {
-        out = out[idx + "Assistant:".len()..].trim_start().to_string();
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileKind {
+    Model,
+    Tokenizer,
+}
+
+fn resolve_local_or_url(value: &str, kind: FileKind, what: &str) -> Result<PathBuf> {
+    if !(value.starts_with("http://") || value.starts_with("https://")) {
+        return Ok(PathBuf::from(value));
     }
-    if out.starts_with(original_prompt) {
-        out = out[original_prompt.len()..].trim_start().to_string();
+    let path = match kind {
+        FileKind::Model => ensure_model_from_url(value),
+        FileKind::Tokenizer => ensure_tokenizer_from_url(value),
+    };
+    path.with_context(|| format!("Failed to fetch the {what}"))
+}
+
+fn memory_session(memory_file: Option<PathBuf>, session: Option<&str>) -> Result<MemorySession> {
+    match memory_file {
+        Some(path) => Ok(MemorySession::at(path)),
+        None => memory_store()?.session(session.unwrap_or(DEFAULT_SESSION)),
     }
-    out
 }
 
 fn download_agent() -> Result<ureq::Agent> {
@@ -259,31 +732,55 @@ fn download_agent() -> Result<ureq::Agent> {
         .build())
 }
 
-fn resolve_model_path(model: Option<PathBuf>, model_url: Option<String>, backend: &str) -> Result<PathBuf> {
-    match model {
-        Some(path) => Ok(path),
-        None => {
-            if backend == "ryzen-ai" {
-                let model_url = model_url
-                    .or_else(|| std::env::var("RYZEN_AI_MODEL_URL").ok())
-                    .context("ryzen-ai backend requires --model or --model-url (or RYZEN_AI_MODEL_URL)")?;
-                return ensure_model_from_url(&model_url);
-            }
-            if backend == "cpu" {
-                let model_url = model_url
-                    .or_else(|| std::env::var("CPU_MODEL_URL").ok())
-                    .context("cpu backend requires --model or --model-url (or CPU_MODEL_URL)")?;
-                return ensure_model_from_url(&model_url);
-            }
-            ensure_qwen_model()
+fn model_url_env(backend: &str) -> Option<&'static str> {
+    match backend {
+        "ryzen-ai" => Some("RYZEN_AI_MODEL_URL"),
+        "cpu" => Some("CPU_MODEL_URL"),
+        _ => None,
+    }
+}
+
+fn resolve_model_path(
+    model: Option<PathBuf>,
+    model_url: Option<String>,
+    backend: &str,
+    profile: &Profile,
+) -> Result<PathBuf> {
+    if let Some(path) = model {
+        if backend != AUTO_BACKEND {
+            find_backend(backend)?;
         }
+        return Ok(path);
+    }
+    // Catch unknown backends before downloading anything for them.
+    if backend != AUTO_BACKEND {
+        find_backend(backend)?;
+    }
+
+    let env_var = model_url_env(backend);
+    if let Some(url) = model_url.or_else(|| env_var.and_then(|var| std::env::var(var).ok())) {
+        return ensure_model_from_url(&url);
+    }
+    if let Some(path) = profile.model.as_ref() {
+        return Ok(PathBuf::from(path));
+    }
+    if let Some(url) = profile.model_url.as_ref() {
+        return ensure_model_from_url(url);
+    }
+    if let Some(var) = env_var {
+        bail!("{backend} backend requires --model or --model-url (or {var}, or a profile model)");
     }
+    if backend == AUTO_BACKEND {
+        bail!("--backend auto requires --model or --model-url (or a profile model)");
+    }
+    ensure_qwen_model()
 }
 
 fn resolve_tokenizer_path(
     tokenizer: Option<PathBuf>,
     tokenizer_url: Option<String>,
     backend: &str,
+    profile: &Profile,
     needs_tokenizer: bool,
 ) -> Result<Option<PathBuf>> {
     if let Some(path) = tokenizer {
@@ -301,14 +798,441 @@ fn resolve_tokenizer_path(
     if let Some(url) = tokenizer_url {
         return Ok(Some(ensure_tokenizer_from_url(&url)?));
     }
+    if let Some(path) = profile.tokenizer.as_ref() {
+        return Ok(Some(PathBuf::from(path)));
+    }
+    if let Some(url) = profile.tokenizer_url.as_ref() {
+        return Ok(Some(ensure_tokenizer_from_url(url)?));
+    }
 
-    if needs_tokenizer {
-        bail!("cpu backend requires --tokenizer or --tokenizer-url (or CPU_TOKENIZER_URL) when --input-ids is omitted");
+    let requires_tokenizer =
+        find_backend(backend).is_ok_and(|entry| entry.has(Capability::TokenizerRequired));
+    if needs_tokenizer && requires_tokenizer {
+        let env_hint = if backend == "cpu" { "CPU_TOKENIZER_URL, or " } else { "" };
+        bail!("{backend} backend requires --tokenizer or --tokenizer-url (or {env_hint}a profile tokenizer) when --input-ids is omitted");
     }
 
     Ok(None)
 }
 
+#[derive(serde::Serialize)]
+struct BackendSummary {
+    name: String,
+    description: String,
+    available: bool,
+    capabilities: Vec<Capability>,
+}
+
+struct ResolvedModel {
+    profile: Profile,
+    model: PathBuf,
+    config: ModelConfig,
+    generation: GenerationConfig,
+}
+
+impl ModelArgs {
+    fn resolve(self, needs_tokenizer: bool) -> Result<ResolvedModel> {
+        let profile = Config::load()?.profile(self.profile.as_deref())?;
+        let backend = self
+            .backend
+            .or_else(|| profile.backend.clone())
+            .unwrap_or_else(|| DEFAULT_BACKEND.to_string());
+        let model = resolve_model_path(self.model, self.model_url, &backend, &profile)?;
+        let tokenizer_path = resolve_tokenizer_path(
+            self.tokenizer,
+            self.tokenizer_url,
+            &backend,
+            &profile,
+            needs_tokenizer,
+        )?;
+        let mut config = model_config(&model, backend);
+        config.tokenizer_path = tokenizer_path.map(|path| path.to_string_lossy().to_string());
+        config.chat_template = profile.chat_template.clone();
+        config.context_length = self.context_length.or(profile.context_length);
+        config.session = self.session.options(&profile)?;
+        config.backend_preference = if self.backend_preference.is_empty() {
+            profile.backend_preference.clone().unwrap_or_default()
+        } else {
+            self.backend_preference
+        };
+        Ok(ResolvedModel {
+            generation: GenerationConfig::for_model(&model)?,
+            profile,
+            model,
+            config,
+        })
+    }
+}
+
+impl ResolvedModel {
+    fn load(&self) -> Result<Box<dyn NpuBackend>> {
+        load_reporting_selection(&self.config)
+    }
+
+    fn context_length(&self, backend: &dyn NpuBackend) -> Option<usize> {
+        self.config
+            .context_length
+            .or_else(|| backend.context_length())
+            .or_else(|| context_length_from_metadata(&self.model))
+    }
+
+    fn tokenizer(&self) -> Result<Option<Tokenizer>> {
+        self.config
+            .tokenizer_path
+            .as_deref()
+            .map(|path| load_tokenizer(Path::new(path)))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn require_tokenizer(&self, what: &str) -> Result<Tokenizer> {
+        self.tokenizer()?.with_context(|| {
+            format!("{what} requires --tokenizer or --tokenizer-url (or a profile tokenizer)")
+        })
+    }
+
+    fn token_counter(&self) -> Result<Box<dyn TokenCounter>> {
+        Ok(match self.tokenizer()? {
+            Some(tokenizer) => Box::new(tokenizer),
+            None => Box::new(ApproxTokenCounter),
+        })
+    }
+}
+
+fn encode_ids(tokenizer: &Tokenizer, text: &str, add_special_tokens: bool) -> Result<Vec<i64>> {
+    let encoding = tokenizer
+        .encode(text, add_special_tokens)
+        .map_err(|e| anyhow::anyhow!("Failed to tokenize text: {e}"))?;
+    Ok(encoding.get_ids().iter().map(|id| *id as i64).collect())
+}
+
+impl SamplingArgs {
+    /// Flags first, then the profile, then the model's generation config.
+    fn request(self, resolved: &ResolvedModel, context_length: Option<usize>) -> InferenceRequest {
+        let profile = &resolved.profile;
+        let generation = &resolved.generation;
+        InferenceRequest {
+            prompt: String::new(),
+            max_tokens: self.max_tokens.or(profile.max_tokens).unwrap_or(generation.max_tokens),
+            input_ids: None,
+            input_name: self.input_name.or(profile.input_name.clone()),
+            output_name: self.output_name.or(profile.output_name.clone()),
+            tokenizer_path: resolved.config.tokenizer_path.clone(),
+            eos_token_id: self
+                .eos_token_id
+                .or(profile.eos_token_id)
+                .or(generation.eos_token_id),
+            temperature: self
+                .temperature
+                .or(profile.temperature)
+                .unwrap_or(generation.temperature),
+            top_k: self.top_k.or(profile.top_k).or(generation.top_k),
+            top_p: self.top_p.or(profile.top_p).or(generation.top_p),
+            repetition_penalty: self
+                .repetition_penalty
+                .or(profile.repetition_penalty)
+                .unwrap_or(generation.repetition_penalty),
+            seed: self.seed.or(profile.seed).or(generation.seed),
+            context_length,
+            stop: if self.stop.is_empty() {
+                profile.stop.clone().unwrap_or_else(|| generation.stop.clone())
+            } else {
+                self.stop
+            },
+            cancel: Some(interrupt_token()),
+            max_time: self
+                .max_time
+                .or(profile.max_time)
+                .map(Duration::from_secs_f64)
+                .or(generation.max_time),
+            deadline: None,
+        }
+    }
+}
+
+/// Cancelled by the first Ctrl-C so generation stops and its output is
+/// still printed and saved; a second Ctrl-C exits immediately.
+fn interrupt_token() -> CancellationToken {
+    static TOKEN: OnceLock<CancellationToken> = OnceLock::new();
+    TOKEN
+        .get_or_init(|| {
+            let token = CancellationToken::new();
+            let handler_token = token.clone();
+            let installed = ctrlc::set_handler(move || {
+                if handler_token.is_cancelled() {
+                    std::process::exit(130);
+                }
+                handler_token.cancel();
+                eprintln!("\nStopping generation (press Ctrl-C again to quit)");
+            });
+            if let Err(err) = installed {
+                eprintln!("Warning: Ctrl-C will not stop generation gracefully: {err}");
+            }
+            token
+        })
+        .clone()
+}
+
+type BatchJob = Vec<(usize, BatchItem)>;
+
+/// Loads its own copy of the model and drains jobs until the queue is
+/// empty, sending one result per item.
+fn batch_worker(
+    resolved: &ResolvedModel,
+    sampling: SamplingArgs,
+    queue: &Mutex<VecDeque<BatchJob>>,
+    results: mpsc::Sender<BatchResult>,
+) -> Result<()> {
+    let mut backend = resolved.load()?;
+    let context_length = resolved.context_length(backend.as_ref());
+    let base = sampling.request(resolved, context_length);
+
+    loop {
+        // After Ctrl-C, leave the remaining items for `--resume`.
+        if base.is_cancelled() {
+            return Ok(());
+        }
+        let Some(job) = queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front() else {
+            return Ok(());
+        };
+        let requests: Vec<InferenceRequest> = job
+            .iter()
+            .map(|(_, item)| {
+                let prompt = match resolved.config.chat_template.as_deref() {
+                    Some(template) => apply_chat_template(template, &item.prompt),
+                    None => item.prompt.clone(),
+                };
+                item.request(&base, prompt)
+            })
+            .collect();
+
+        let start = Instant::now();
+        let responses = if requests.len() == 1 {
+            backend.run(&requests[0]).map(|response| vec![response])
+        } else {
+            backend.run_batch(&requests)
+        };
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+
+        for (position, (index, item)) in job.iter().enumerate() {
+            let result = match responses.as_ref() {
+                Ok(responses) => match responses.get(position) {
+                    Some(response) => BatchResult::success(
+                        *index,
+                        item,
+                        clean_answer(&item.prompt, &response.text),
+                        &requests[position].stop,
+                        response,
+                        elapsed_ms,
+                    ),
+                    None => BatchResult::failure(
+                        *index,
+                        item,
+                        "Backend returned too few responses".to_string(),
+                        elapsed_ms,
+                    ),
+                },
+                Err(err) => BatchResult::failure(*index, item, format!("{err:#}"), elapsed_ms),
+            };
+            if results.send(result).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+struct BenchSample {
+    prefill_tokens_per_sec: Option<f64>,
+    decode_tokens_per_sec: Option<f64>,
+}
+
+/// Times one generation. Backends that do not report a prefill/decode split
+/// are timed again with a single token to estimate the prefill share.
+fn bench_sample(backend: &mut dyn NpuBackend, request: &InferenceRequest) -> Result<BenchSample> {
+    let start = Instant::now();
+    let response = backend.run(request)?;
+    let total_ms = start.elapsed().as_secs_f64() * 1000.0;
+    if response.finish_reason == Some(FinishReason::Cancelled) {
+        bail!("Benchmark interrupted");
+    }
+
+    let timings = match response.timings {
+        Some(timings) => timings,
+        None => {
+            let start = Instant::now();
+            backend.run(&InferenceRequest {
+                max_tokens: 1,
+                ..request.clone()
+            })?;
+            let prefill_ms = start.elapsed().as_secs_f64() * 1000.0;
+            llm_toy::GenerationTimings {
+                prefill_ms,
+                decode_ms: (total_ms - prefill_ms).max(0.0),
+            }
+        }
+    };
+    let prompt_tokens = response
+        .prompt_tokens
+        .or(request.input_ids.as_ref().map(Vec::len))
+        .unwrap_or_else(|| request.prompt.split_whitespace().count());
+    let generated = response.completion_tokens.unwrap_or(request.max_tokens);
+    let rate = |tokens: usize, ms: f64| (tokens > 0 && ms > 0.0).then(|| tokens as f64 * 1000.0 / ms);
+    Ok(BenchSample {
+        prefill_tokens_per_sec: rate(prompt_tokens, timings.prefill_ms),
+        // The prefill pass already produced the first token.
+        decode_tokens_per_sec: rate(generated.saturating_sub(1), timings.decode_ms),
+    })
+}
+
+fn print_runtime(info: &RuntimeInfo) {
+    println!("ONNX Runtime: {}", info.version);
+    println!("  Library: {}", info.library.describe());
+    println!("  Execution providers: {}", info.execution_providers.join(", "));
+}
+
+/// Loads the runtime from the library `env_var` selects.
+#[cfg(any(feature = "cpu", feature = "ryzen-ai"))]
+fn probe_runtime(env_var: &str) -> Result<Option<RuntimeInfo>> {
+    runtime::init(env_var)?;
+    Ok(runtime::loaded())
+}
+
+#[cfg(not(any(feature = "cpu", feature = "ryzen-ai")))]
+fn probe_runtime(_env_var: &str) -> Result<Option<RuntimeInfo>> {
+    Ok(None)
+}
+
+fn doctor() {
+    println!("llm-toy {}", env!("CARGO_PKG_VERSION"));
+    let features: Vec<&str> = [
+        ("cpu", cfg!(feature = "cpu")),
+        ("ryzen-ai", cfg!(feature = "ryzen-ai")),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| name)
+    .collect();
+    if features.is_empty() {
+        println!("Features: none");
+    } else {
+        println!("Features: {}", features.join(", "));
+    }
+
+    println!("Backends:");
+    for entry in backends() {
+        let mut lines = backend_status(&entry.name).into_iter();
+        println!("  {:<12} {}", entry.name, lines.next().unwrap_or_default());
+        for line in lines {
+            println!("  {:<12}   {line}", "");
+        }
+    }
+    if !cfg!(all(windows, feature = "ryzen-ai")) {
+        println!("  {:<12} not built in (needs Windows and --features ryzen-ai)", "ryzen-ai");
+    }
+
+    println!("ONNX Runtime libraries:");
+    for env_var in [runtime::CPU_ORT_DLL, runtime::RYZEN_AI_ORT_DLL] {
+        match runtime::resolve_library(env_var) {
+            Ok(library) => match runtime::find_library(&library) {
+                Some(_) => println!("  {env_var}: {}", library.describe()),
+                None => println!("  {env_var}: {}", runtime::not_found_reason(&library, env_var)),
+            },
+            Err(err) => println!("  {env_var}: {err:#}"),
+        }
+    }
+    let env_var = if cfg!(feature = "cpu") {
+        runtime::CPU_ORT_DLL
+    } else {
+        runtime::RYZEN_AI_ORT_DLL
+    };
+    match probe_runtime(env_var) {
+        Ok(Some(info)) => print_runtime(&info),
+        Ok(None) => println!("ONNX Runtime: not built in (enable the cpu or ryzen-ai feature)"),
+        Err(err) => println!("ONNX Runtime: unavailable: {err:#}"),
+    }
+
+    let cpu = cpu_info();
+    let cores = cpu
+        .logical_cores
+        .map(|cores| format!(", {cores} logical core{}", if cores == 1 { "" } else { "s" }))
+        .unwrap_or_default();
+    println!(
+        "CPU: {} ({}{cores})",
+        cpu.model.as_deref().unwrap_or("unknown model"),
+        cpu.arch
+    );
+    if cpu.simd.is_empty() {
+        println!("  SIMD: none detected");
+    } else {
+        println!("  SIMD: {}", cpu.simd.join(" "));
+    }
+
+    match system_memory() {
+        Some(memory) => println!(
+            "Memory: {} available of {}",
+            format_bytes(memory.available_bytes),
+            format_bytes(memory.total_bytes)
+        ),
+        None => println!("Memory: unknown"),
+    }
+
+    match default_cache_dir() {
+        Ok(dir) => {
+            let free = free_space(&dir)
+                .map(|bytes| format!("{} free", format_bytes(bytes)))
+                .unwrap_or_else(|| "free space unknown".to_string());
+            let created = if dir.exists() { "" } else { ", not created yet" };
+            println!("Cache: {} ({free}{created})", dir.display());
+        }
+        Err(err) => println!("Cache: {err:#}"),
+    }
+}
+
+/// Whether `name` would actually run here, with the backend's reasons when
+/// it would not and anything it found while checking.
+fn backend_status(name: &str) -> Vec<String> {
+    let backend = match load_backend(name) {
+        Ok(backend) => backend,
+        Err(err) => return vec![format!("cannot be constructed: {err:#}")],
+    };
+    let availability = backend.availability();
+    let mut lines = vec![if availability.available {
+        "available".to_string()
+    } else {
+        "not available".to_string()
+    }];
+    lines.extend(availability.reasons.iter().map(|reason| format!("- {reason}")));
+    lines.extend(availability.details);
+    lines
+}
+
+/// `load_model`, telling the user which backend `auto` picked and why.
+fn load_reporting_selection(config: &ModelConfig) -> Result<Box<dyn NpuBackend>> {
+    if config.npu_backend != AUTO_BACKEND {
+        return Ok(load_model(config)?);
+    }
+    let selection = select_backend(config)?;
+    eprintln!("{}", selection.summary());
+    Ok(selection.backend)
+}
+
+fn model_config(model: &Path, backend: String) -> ModelConfig {
+    ModelConfig {
+        name: model
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("unknown")
+            .to_string(),
+        path: model.to_string_lossy().to_string(),
+        npu_backend: backend,
+        tokenizer_path: None,
+        chat_template: None,
+        context_length: None,
+        session: SessionOptions::default(),
+        backend_preference: Vec::new(),
+    }
+}
+
 fn parse_input_ids(value: Option<String>) -> Result<Option<Vec<i64>>> {
     let Some(raw) = value else {
         return Ok(None);
@@ -318,132 +1242,983 @@ fn parse_input_ids(value: Option<String>) -> Result<Option<Vec<i64>>> {
     }
 
     let mut ids = Vec::new();
-    for part in raw.split(',') {
-        let token = part.trim();
+    for token in raw.split(|c: char| c == ',' || c.is_whitespace()) {
         if token.is_empty() {
             continue;
         }
-        let id: i64 = token.parse().context("Invalid token id in --input-ids")?;
+        let id: i64 = token
+            .parse()
+            .with_context(|| format!("Invalid token id '{token}'"))?;
         ids.push(id);
     }
     Ok(Some(ids))
 }
 
+fn index_documents(args: IndexArgs) -> Result<()> {
+    let IndexArgs {
+        dir,
+        output,
+        embedding_model,
+        embedding_tokenizer,
+        profile,
+        pooling,
+        chunk_size,
+        session,
+    } = args;
+    let profile = Config::load()?.profile(profile.as_deref())?;
+    let session = session.options(&profile)?;
+    let model = embedding_model
+        .or(profile.embedding_model.clone())
+        .context("index requires --embedding-model (or a profile embedding_model)")?;
+    let tokenizer = embedding_tokenizer
+        .or(profile.embedding_tokenizer.clone())
+        .context("index requires --embedding-tokenizer (or a profile embedding_tokenizer)")?;
+    let output = match output {
+        Some(path) => path,
+        None if dir.is_file() => dir
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(INDEX_DIR_NAME),
+        None => dir.join(INDEX_DIR_NAME),
+    };
+    let pooling = pooling.or(profile.embedding_pooling).unwrap_or_default();
+    let model_path = resolve_local_or_url(&model, FileKind::Model, "embedding model")?;
+    let tokenizer_path = resolve_local_or_url(&tokenizer, FileKind::Tokenizer, "embedding tokenizer")?;
+    let options = EmbeddingOptions {
+        pooling,
+        ..EmbeddingOptions::default()
+    };
+    let mut embedder = OnnxEmbedder::load_with_session(&model_path, &tokenizer_path, options, &session)?;
+
+    let absolute = |path: &Path| {
+        fs::canonicalize(path)
+            .unwrap_or_else(|_| path.to_path_buf())
+            .to_string_lossy()
+            .to_string()
+    };
+    let previous = if DocumentIndex::exists(&output) {
+        Some(DocumentIndex::load(&output)?)
+    } else {
+        None
+    };
+    let manifest = IndexManifest {
+        root: absolute(&dir),
+        embedding_model: absolute(&model_path),
+        embedding_tokenizer: absolute(&tokenizer_path),
+        pooling,
+        chunk_size: chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+        created_at: 0,
+        chunks: Vec::new(),
+    };
+    let index = DocumentIndex::build(manifest, previous.as_ref(), &mut embedder)?;
+    if index.manifest.chunks.is_empty() {
+        bail!("No text or markdown documents found under {}", dir.display());
+    }
+    index.save(&output)?;
+    let documents = index
+        .manifest
+        .chunks
+        .iter()
+        .map(|chunk| chunk.source.as_str())
+        .collect::<std::collections::BTreeSet<_>>()
+        .len();
+    println!(
+        "Indexed {} chunks from {} documents into {}",
+        index.manifest.chunks.len(),
+        documents,
+        output.display()
+    );
+    Ok(())
+}
+
+fn ask(args: AskArgs) -> Result<()> {
+    let AskArgs {
+        model,
+        sampling,
+        index,
+        prompt,
+        chunks,
+    } = args;
+    let resolved = model.resolve(true)?;
+    let config = &resolved.config;
+    let index = DocumentIndex::load(&index)?;
+    let options = EmbeddingOptions {
+        pooling: index.manifest.pooling,
+        ..EmbeddingOptions::default()
+    };
+    let mut embedder = OnnxEmbedder::load_with_session(
+        Path::new(&index.manifest.embedding_model),
+        Path::new(&index.manifest.embedding_tokenizer),
+        options,
+        &config.session,
+    )?;
+    let query = embedder
+        .embed(&[prompt.as_str()])?
+        .pop()
+        .context("Embedding model returned no vector")?;
+    let hits = index.search(&query, chunks.unwrap_or(DEFAULT_ASK_CHUNKS));
+
+    let mut backend = resolved.load()?;
+    let context_length = resolved.context_length(backend.as_ref());
+    let request = sampling.request(&resolved, context_length);
+    let counter = resolved.token_counter()?;
+    let template_tokens = match config.chat_template.as_deref() {
+        Some(template) => counter.count_tokens(&apply_chat_template(template, ""))?,
+        None => 0,
+    };
+    let available = context_length
+        .unwrap_or(DEFAULT_CONTEXT_LENGTH)
+        .saturating_sub(request.max_tokens + template_tokens);
+    let (grounded, included) = grounded_prompt(&prompt, &hits, available, counter.as_ref())?;
+
+    let full_prompt = match config.chat_template.as_deref() {
+        Some(template) => apply_chat_template(template, &grounded),
+        None => grounded,
+    };
+    let request = InferenceRequest {
+        prompt: full_prompt,
+        ..request
+    };
+    let response = backend.run(&request)?;
+    println!("Q: {}", prompt);
+    // Backends that echo their input repeat the grounded prompt.
+    println!("A:\n{}", clean_answer(&request.prompt, &response.text));
+    if included > 0 {
+        println!("\nSources:");
+        for (number, (chunk, score)) in hits.iter().take(included).enumerate() {
+            println!("[{}] {} (score {:.3})", number + 1, chunk.citation(), score);
+        }
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
         Commands::Run {
             model,
-            model_url,
-            tokenizer,
-            tokenizer_url,
-            backend,
+            sampling,
             prompt,
             input_ids,
-            input_name,
-            output_name,
-            max_tokens,
-            eos_token_id,
-            temperature,
-            top_k,
-            top_p,
-            repetition_penalty,
-            seed,
             memory,
             memory_file,
             memory_clear,
+            session,
+            system_prompt,
+            truncation,
+            summarize,
+            summary_tokens,
+            retrieval,
+            embedding_model,
+            embedding_tokenizer,
+            retrieval_top_k,
         } => {
-            let model = resolve_model_path(model, model_url, &backend)?;
             let parsed_input_ids = parse_input_ids(input_ids)?;
-            let tokenizer_path = resolve_tokenizer_path(
-                tokenizer,
-                tokenizer_url,
-                &backend,
-                parsed_input_ids.is_none(),
-            )?;
+            let resolved = model.resolve(parsed_input_ids.is_none())?;
+            let profile = &resolved.profile;
+            let config = &resolved.config;
+            let profile_name = profile.name.clone();
             let original_prompt = prompt.clone();
-            let memory_path = if memory || memory_clear {
-                Some(memory_file.unwrap_or(default_memory_path()?))
+            let memory = memory || session.is_some() || system_prompt.is_some();
+            let memory_session = if memory || memory_clear {
+                Some(memory_session(memory_file, session.as_deref())?)
             } else {
                 None
             };
             if memory_clear {
-                if let Some(path) = memory_path.as_ref() {
-                    let _ = fs::remove_file(path);
+                if let Some(session) = memory_session.as_ref() {
+                    session.delete()?;
                 }
             }
-            let mut memory_state = if memory {
-                if let Some(path) = memory_path.as_ref() {
-                    load_memory(path)?
-                } else {
-                    MemoryState::default()
-                }
-            } else {
-                MemoryState::default()
+            let mut memory_state = match memory_session.as_ref() {
+                Some(session) if memory => match system_prompt {
+                    Some(system_prompt) => session.update(|state| {
+                        state.system_prompt = Some(system_prompt);
+                        state.clone()
+                    })?,
+                    None => session.load()?,
+                },
+                _ => MemoryState::default(),
             };
+            let mut backend = resolved.load()?;
+            let context_length = resolved.context_length(backend.as_ref());
+            let request = sampling.request(&resolved, context_length);
+            let max_tokens = request.max_tokens;
+            let mut retrieval_memory: Option<RetrievalMemory> = None;
+            let mut retrieved = Vec::new();
             let prompt = if memory {
-                apply_memory(&original_prompt, &memory_state)
+                let counter = resolved.token_counter()?;
+                let template_tokens = match config.chat_template.as_deref() {
+                    Some(template) => counter.count_tokens(&apply_chat_template(template, ""))?,
+                    None => 0,
+                };
+                let summarize = summarize || profile.summarize.unwrap_or(false);
+                let mut budget = ContextBudget {
+                    context_length: context_length.unwrap_or(DEFAULT_CONTEXT_LENGTH),
+                    reserved_tokens: max_tokens + template_tokens,
+                    strategy: truncation.or(profile.truncation).unwrap_or_default(),
+                };
+                if summarize {
+                    budget.strategy = TruncationStrategy::DropOldest;
+                }
+                let retrieval = retrieval || profile.retrieval.unwrap_or(false);
+                if let Some(session) = memory_session.as_ref().filter(|_| retrieval) {
+                    let model = embedding_model
+                        .or(profile.embedding_model.clone())
+                        .context("--retrieval requires --embedding-model (or a profile embedding_model)")?;
+                    let tokenizer = embedding_tokenizer
+                        .or(profile.embedding_tokenizer.clone())
+                        .context("--retrieval requires --embedding-tokenizer (or a profile embedding_tokenizer)")?;
+                    let pooling = profile.embedding_pooling.unwrap_or_default();
+                    let mut store = RetrievalMemory::open(session, &model, &tokenizer, pooling, &config.session)?;
+                    store.sync(&memory_state)?;
+                    let top_k = retrieval_top_k
+                        .or(profile.retrieval_top_k)
+                        .unwrap_or(DEFAULT_RETRIEVAL_TOP_K);
+                    retrieved = store.query(&original_prompt, top_k)?;
+                    retrieval_memory = Some(store);
+                }
+                let mut plan = plan_memory(
+                    &original_prompt,
+                    &memory_state,
+                    &retrieved,
+                    &budget,
+                    counter.as_ref(),
+                )?;
+                if let Some(session) = memory_session.as_ref().filter(|_| summarize) {
+                    if !plan.evicted.is_empty() {
+                        let summary_tokens = summary_tokens
+                            .or(profile.summary_tokens)
+                            .unwrap_or(DEFAULT_SUMMARY_TOKENS);
+                        let summary_budget = ContextBudget {
+                            reserved_tokens: summary_tokens + template_tokens,
+                            ..budget
+                        };
+                        let mut interrupted = false;
+                        let summarized = summarize_history(
+                            &memory_state,
+                            plan.evicted.end,
+                            &summary_budget,
+                            counter.as_ref(),
+                            &mut |text| {
+                                let prompt = match config.chat_template.as_deref() {
+                                    Some(template) => apply_chat_template(template, text),
+                                    None => text.to_string(),
+                                };
+                                let response = backend.run(&InferenceRequest {
+                                    prompt,
+                                    max_tokens: summary_tokens,
+                                    max_time: Some(SUMMARY_MAX_TIME),
+                                    deadline: None,
+                                    ..request.clone()
+                                })?;
+                                if matches!(
+                                    response.finish_reason,
+                                    Some(FinishReason::Cancelled | FinishReason::Timeout)
+                                ) {
+                                    // A cut-off summary would stand in for
+                                    // turns it never covered.
+                                    interrupted = true;
+                                    bail!("Summary interrupted");
+                                }
+                                Ok(clean_answer(text, &response.text))
+                            },
+                        );
+                        match summarized {
+                            Ok((summary, covered)) => {
+                                memory_state = session.update(|state| {
+                                    if covered > state.summarized_turns {
+                                        state.summary = summary;
+                                        state.summarized_turns = covered;
+                                    }
+                                    state.clone()
+                                })?;
+                                plan = plan_memory(
+                                    &original_prompt,
+                                    &memory_state,
+                                    &retrieved,
+                                    &budget,
+                                    counter.as_ref(),
+                                )?;
+                            }
+                            Err(_) if interrupted => {
+                                eprintln!("(summary interrupted; older turns are left out until the next summary)");
+                            }
+                            Err(err) => return Err(err),
+                        }
+                    }
+                }
+                plan.prompt
             } else {
                 original_prompt.clone()
             };
-            let config = ModelConfig {
-                name: model
-                    .file_name()
-                    .and_then(|name| name.to_str())
-                    .unwrap_or("unknown")
-                    .to_string(),
-                path: model.to_string_lossy().to_string(),
-                npu_backend: backend,
+            let prompt = match config.chat_template.as_deref() {
+                Some(template) => apply_chat_template(template, &prompt),
+                None => prompt,
             };
-            let mut backend = load_model(&config)?;
+            if request.is_cancelled() {
+                eprintln!("(generation interrupted)");
+                return Ok(());
+            }
             let response = backend.run(&InferenceRequest {
                 prompt,
-                max_tokens,
                 input_ids: parsed_input_ids,
-                input_name,
-                output_name,
-                tokenizer_path: tokenizer_path.map(|path| path.to_string_lossy().to_string()),
-                eos_token_id,
-                temperature,
-                top_k: Some(top_k),
-                top_p: Some(top_p),
-                repetition_penalty,
-                seed,
+                ..request
             })?;
             let answer = clean_answer(&original_prompt, &response.text);
             println!("Q: {}", original_prompt);
             println!("A:\n{}", answer);
-            if memory {
-                memory_state.last_prompt = Some(original_prompt.clone());
-                memory_state.last_response = Some(answer.clone());
-                memory_state.conversation_history.push(MemoryEntry {
-                    prompt: original_prompt,
-                    response: answer.clone(),
-                });
-                if let Some(path) = memory_path.as_ref() {
-                    save_memory(path, &memory_state)?;
+            let finished = match response.finish_reason {
+                Some(FinishReason::Cancelled) => {
+                    eprintln!("(generation interrupted)");
+                    false
+                }
+                Some(FinishReason::Timeout) => {
+                    eprintln!("(generation stopped at --max-time)");
+                    false
+                }
+                _ => true,
+            };
+            // A cut-off or empty answer would be replayed as if the model
+            // had said it.
+            let remember = memory && finished && !answer.trim().is_empty();
+            if memory && !remember {
+                eprintln!("(answer not saved to memory)");
+            }
+            if let Some(session) = memory_session.as_ref().filter(|_| remember) {
+                let mut entry = MemoryEntry::new(original_prompt, answer);
+                entry.model = Some(config.name.clone());
+                entry.backend = Some(backend.name().to_string());
+                entry.profile = profile_name;
+                let state = session.update(|state| {
+                    state.conversation_history.push(entry);
+                    state.clone()
+                })?;
+                if let Some(store) = retrieval_memory.as_mut() {
+                    store.sync(&state)?;
                 }
             }
         }
-        Commands::Info { model, model_url, backend } => {
-            let model = resolve_model_path(model, model_url, &backend)?;
-            let config = ModelConfig {
-                name: model
-                    .file_name()
-                    .and_then(|name| name.to_str())
-                    .unwrap_or("unknown")
-                    .to_string(),
-                path: model.to_string_lossy().to_string(),
-                npu_backend: backend,
-            };
-            let backend = load_model(&config)?;
-            let metadata = fs::metadata(model)?;
-            println!("Model: {}", config.name);
+        Commands::Info { model } => {
+            let resolved = model.resolve(false)?;
+            let backend = resolved.load()?;
+            let files = model_files(&resolved.model)?;
+            println!("Model: {}", resolved.config.name);
             println!("Backend: {}", backend.name());
-            println!("Size: {} bytes", metadata.len());
+            if files.len() > 1 {
+                println!("Files: {}", files.len());
+            }
+            println!("Size: {} bytes", total_size(&resolved.model)?);
+            if let Some(info) = runtime::loaded() {
+                print_runtime(&info);
+            }
+        }
+        Commands::Doctor => doctor(),
+        Commands::Backends { format } => {
+            let summaries: Vec<BackendSummary> = backends()
+                .into_iter()
+                .map(|entry| BackendSummary {
+                    available: entry.create().is_available(),
+                    name: entry.name,
+                    description: entry.description,
+                    capabilities: entry.capabilities,
+                })
+                .collect();
+            match format {
+                ReportFormat::Table => {
+                    let width = summaries
+                        .iter()
+                        .map(|summary| summary.name.len())
+                        .max()
+                        .unwrap_or(0);
+                    for summary in &summaries {
+                        let status = if summary.available { "available" } else { "unavailable" };
+                        println!("{:<width$}  {status:<11}  {}", summary.name, summary.description);
+                        if !summary.capabilities.is_empty() {
+                            let capabilities: Vec<&str> =
+                                summary.capabilities.iter().map(|c| c.as_str()).collect();
+                            println!("{:<width$}  capabilities: {}", "", capabilities.join(", "));
+                        }
+                    }
+                }
+                ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&summaries)?),
+            }
         }
+        Commands::Embed {
+            model,
+            tokenizer,
+            profile,
+            input,
+            input_format,
+            text_field,
+            output,
+            output_format,
+            pooling,
+            no_normalize,
+            max_length,
+            session,
+        } => {
+            let profile = Config::load()?.profile(profile.as_deref())?;
+            let session = session.options(&profile)?;
+            let model = model
+                .or(profile.embedding_model.clone())
+                .context("embed requires --model (or a profile embedding_model)")?;
+            let tokenizer = tokenizer
+                .or(profile.embedding_tokenizer.clone())
+                .context("embed requires --tokenizer (or a profile embedding_tokenizer)")?;
+            let has_extension = |path: &Option<PathBuf>, ext: &str| {
+                path.as_deref()
+                    .and_then(|path| path.extension())
+                    .is_some_and(|found| found.eq_ignore_ascii_case(ext))
+            };
+            let input_format = input_format.unwrap_or(if has_extension(&input, "jsonl") {
+                EmbedInputFormat::Jsonl
+            } else {
+                EmbedInputFormat::Lines
+            });
+            let output_format = output_format.unwrap_or(if has_extension(&output, "npy") {
+                EmbedOutputFormat::Npy
+            } else {
+                EmbedOutputFormat::Jsonl
+            });
+            if output_format == EmbedOutputFormat::Npy && output.is_none() {
+                bail!("--output-format npy requires --output");
+            }
+
+            let defaults = EmbeddingOptions::default();
+            let options = EmbeddingOptions {
+                pooling: pooling.or(profile.embedding_pooling).unwrap_or(defaults.pooling),
+                normalize: !no_normalize,
+                max_length: max_length.unwrap_or(defaults.max_length),
+            };
+            let model_path = resolve_local_or_url(&model, FileKind::Model, "embedding model")?;
+            let tokenizer_path = resolve_local_or_url(&tokenizer, FileKind::Tokenizer, "embedding tokenizer")?;
+            let mut embedder = OnnxEmbedder::load_with_session(&model_path, &tokenizer_path, options, &session)?;
+
+            let inputs = read_embed_inputs(input.as_deref(), input_format, &text_field)?;
+            let mut vectors = Vec::with_capacity(inputs.len());
+            for chunk in inputs.chunks(EMBED_CHUNK_SIZE) {
+                let texts: Vec<&str> = chunk.iter().map(|input| input.text.as_str()).collect();
+                vectors.extend(embedder.embed(&texts)?);
+            }
+
+            let mut writer: Box<dyn Write> = match output.as_deref() {
+                Some(path) => Box::new(BufWriter::new(
+                    fs::File::create(path)
+                        .with_context(|| format!("Failed to create {}", path.display()))?,
+                )),
+                None => Box::new(BufWriter::new(std::io::stdout().lock())),
+            };
+            match output_format {
+                EmbedOutputFormat::Jsonl => write_embeddings_jsonl(&mut writer, inputs, vectors)?,
+                EmbedOutputFormat::Npy => write_npy(&mut writer, &vectors)?,
+            }
+            writer.flush()?;
+        }
+        Commands::Index(args) => index_documents(args)?,
+        Commands::Batch {
+            model,
+            sampling,
+            input,
+            output,
+            resume,
+            workers,
+            batch_size,
+        } => {
+            if output.exists() && !resume {
+                bail!(
+                    "{} already exists; pass --resume to continue it or remove it first",
+                    output.display()
+                );
+            }
+            let file = fs::File::open(&input)
+                .with_context(|| format!("Failed to open {}", input.display()))?;
+            let items = read_batch_items(BufReader::new(file))?;
+            let progress = read_progress(&output)?;
+            let completed = &progress.completed;
+            let pending: Vec<(usize, BatchItem)> = items
+                .into_iter()
+                .enumerate()
+                .filter(|(index, _)| !completed.contains(index))
+                .collect();
+            let total = pending.len();
+            if !completed.is_empty() {
+                eprintln!("Resuming: {} already done, {total} remaining", completed.len());
+            }
+            if total == 0 {
+                return Ok(());
+            }
+
+            let resolved = model.resolve(true)?;
+            let queue: VecDeque<BatchJob> = pending
+                .chunks(batch_size.max(1))
+                .map(|chunk| chunk.to_vec())
+                .collect();
+            let queue = Mutex::new(queue);
+            if progress.has_partial_line() {
+                // Drop the partial line an interrupted run left so appending
+                // resumes on a clean line.
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(&output)
+                    .and_then(|file| file.set_len(progress.complete_len))
+                    .with_context(|| format!("Failed to truncate {}", output.display()))?;
+            }
+            let mut writer = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&output)
+                .with_context(|| format!("Failed to open {}", output.display()))?;
+
+            let start = Instant::now();
+            let (sender, receiver) = mpsc::channel();
+            let (written, failed) = std::thread::scope(|scope| -> Result<(usize, usize)> {
+                let handles: Vec<_> = (0..workers.max(1))
+                    .map(|_| {
+                        let sender = sender.clone();
+                        let sampling = sampling.clone();
+                        let (resolved, queue) = (&resolved, &queue);
+                        scope.spawn(move || batch_worker(resolved, sampling, queue, sender))
+                    })
+                    .collect();
+                drop(sender);
+
+                let (mut written, mut failed) = (0, 0);
+                for result in receiver {
+                    serde_json::to_writer(&mut writer, &result)?;
+                    writer.write_all(b"\n")?;
+                    writer.flush()?;
+                    written += 1;
+                    if let Some(error) = result.error.as_deref() {
+                        failed += 1;
+                        eprintln!("[{written}/{total}] item {} failed: {error}", result.index);
+                    } else {
+                        eprintln!("[{written}/{total}] item {} done in {} ms", result.index, result.elapsed_ms);
+                    }
+                }
+                for handle in handles {
+                    handle
+                        .join()
+                        .map_err(|_| anyhow::anyhow!("Batch worker panicked"))??;
+                }
+                Ok((written, failed))
+            })?;
+            println!(
+                "Wrote {written} results ({failed} failed) to {} in {:.1}s",
+                output.display(),
+                start.elapsed().as_secs_f64()
+            );
+        }
+        Commands::Bench {
+            model,
+            sampling,
+            prompt_lengths,
+            gen_lengths,
+            threads,
+            repetitions,
+            warmup,
+            format,
+        } => {
+            let resolved = model.resolve(true)?;
+            let filler_id = match resolved.tokenizer()? {
+                Some(tokenizer) => encode_ids(&tokenizer, " the", false)?.first().copied(),
+                None => None,
+            };
+            let thread_counts: Vec<Option<usize>> = if threads.is_empty() {
+                vec![resolved.config.session.intra_threads]
+            } else {
+                threads.into_iter().map(Some).collect()
+            };
+
+            let mut rows = Vec::new();
+            for threads in thread_counts {
+                let mut config = resolved.config.clone();
+                config.session.intra_threads = threads;
+                // Repeated prompts would be served from the prefix cache.
+                config.session.prefix_cache_mb = Some(0);
+                let load_start = Instant::now();
+                let mut backend = load_model(&config)?;
+                let load_ms = load_start.elapsed().as_secs_f64() * 1000.0;
+                let context_length = resolved.context_length(backend.as_ref());
+                let base = InferenceRequest {
+                    eos_token_id: None,
+                    stop: Vec::new(),
+                    ..sampling.clone().request(&resolved, context_length)
+                };
+
+                for &prompt_len in &prompt_lengths {
+                    for &gen_len in &gen_lengths {
+                        // A repeated single token gives an exact prompt length;
+                        // without a tokenizer the word count stands in for it.
+                        let request = InferenceRequest {
+                            prompt: vec!["the"; prompt_len].join(" "),
+                            input_ids: filler_id.map(|id| vec![id; prompt_len]),
+                            max_tokens: gen_len,
+                            ..base.clone()
+                        };
+                        for _ in 0..warmup {
+                            backend.run(&request)?;
+                        }
+                        let mut prefill = Vec::new();
+                        let mut decode = Vec::new();
+                        for _ in 0..repetitions.max(1) {
+                            let sample = bench_sample(backend.as_mut(), &request)?;
+                            prefill.extend(sample.prefill_tokens_per_sec);
+                            decode.extend(sample.decode_tokens_per_sec);
+                        }
+                        rows.push(BenchRow {
+                            backend: backend.name().to_string(),
+                            model: config.name.clone(),
+                            threads,
+                            prompt_tokens: prompt_len,
+                            gen_tokens: gen_len,
+                            repetitions: repetitions.max(1),
+                            load_ms,
+                            prefill_tokens_per_sec: Stats::from_samples(&prefill),
+                            decode_tokens_per_sec: Stats::from_samples(&decode),
+                            memory: process_memory(),
+                        });
+                    }
+                }
+            }
+
+            match format {
+                ReportFormat::Table => print!("{}", render_table(&rows)),
+                ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&rows)?),
+            }
+        }
+        Commands::Perplexity {
+            model,
+            file,
+            window,
+            stride,
+            input_name,
+            output_name,
+            json,
+        } => {
+            let resolved = model.resolve(true)?;
+            let tokenizer = resolved.require_tokenizer("perplexity")?;
+            let text = fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read {}", file.display()))?;
+            let ids = encode_ids(&tokenizer, &text, true)?;
+
+            let mut backend = resolved.load()?;
+            let context_length = resolved.context_length(backend.as_ref());
+            let window = window
+                .or(context_length)
+                .unwrap_or(DEFAULT_PERPLEXITY_WINDOW)
+                .min(context_length.unwrap_or(usize::MAX));
+            let stride = stride.unwrap_or((window / 2).max(1));
+            let sampling = SamplingArgs {
+                input_name,
+                output_name,
+                ..SamplingArgs::default()
+            };
+            let base = sampling.request(&resolved, context_length);
+            let report = perplexity(backend.as_mut(), &base, &ids, text.len(), window, stride)?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("Tokens scored: {}", report.tokens);
+                println!("Windows: {} (window {}, stride {})", report.windows, report.window, report.stride);
+                println!("Perplexity: {:.4}", report.perplexity);
+                println!("Bits per byte: {:.4}", report.bits_per_byte);
+            }
+        }
+        Commands::Score {
+            model,
+            prompt,
+            completion,
+            input,
+            output,
+            input_name,
+            output_name,
+            json,
+        } => {
+            let items = match (input, prompt) {
+                (Some(path), None) => {
+                    let file = fs::File::open(&path)
+                        .with_context(|| format!("Failed to open {}", path.display()))?;
+                    let mut items = Vec::new();
+                    for (number, line) in BufReader::new(file).lines().enumerate() {
+                        let line = line?;
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        let item: ScoreItem = serde_json::from_str(&line)
+                            .with_context(|| format!("Invalid score item on line {}", number + 1))?;
+                        items.push(item);
+                    }
+                    items
+                }
+                (None, Some(prompt)) => {
+                    if completion.is_empty() {
+                        bail!("score requires at least one --completion");
+                    }
+                    vec![ScoreItem {
+                        id: None,
+                        prompt,
+                        completions: completion,
+                        answer: None,
+                    }]
+                }
+                _ => bail!("score requires either --prompt with --completion, or --input"),
+            };
+
+            let resolved = model.resolve(true)?;
+            let tokenizer = resolved.require_tokenizer("score")?;
+            let mut backend = resolved.load()?;
+            let context_length = resolved.context_length(backend.as_ref());
+            let sampling = SamplingArgs {
+                input_name,
+                output_name,
+                ..SamplingArgs::default()
+            };
+            let base = sampling.request(&resolved, context_length);
+
+            let mut writer: Option<Box<dyn Write>> = match output.as_deref() {
+                Some(path) => Some(Box::new(BufWriter::new(
+                    fs::File::create(path)
+                        .with_context(|| format!("Failed to create {}", path.display()))?,
+                ))),
+                None if json || items.len() > 1 => Some(Box::new(std::io::stdout().lock())),
+                None => None,
+            };
+            let (mut graded, mut correct, mut correct_normalized) = (0, 0, 0);
+            for (index, item) in items.iter().enumerate() {
+                let prompt_ids = encode_ids(&tokenizer, &item.prompt, true)?;
+                let mut scores = Vec::with_capacity(item.completions.len());
+                for completion in &item.completions {
+                    let completion_ids = encode_ids(&tokenizer, completion, false)?;
+                    scores.push(score_completion(
+                        backend.as_mut(),
+                        &base,
+                        &prompt_ids,
+                        completion,
+                        &completion_ids,
+                    )?);
+                }
+                let result = ScoreResult::new(index, item, scores);
+                if let Some(answer_correct) = result.correct {
+                    graded += 1;
+                    correct += usize::from(answer_correct);
+                    correct_normalized += usize::from(result.correct_normalized == Some(true));
+                }
+
+                match writer.as_mut() {
+                    Some(writer) => {
+                        serde_json::to_writer(&mut *writer, &result)?;
+                        writer.write_all(b"\n")?;
+                    }
+                    None => {
+                        for (position, score) in result.scores.iter().enumerate() {
+                            let marker = if position == result.best { "*" } else { " " };
+                            println!(
+                                "{marker} {:>10.4}  {:>4} tokens  {:>8.4}/token  {}",
+                                score.logprob, score.tokens, score.mean_logprob, score.completion
+                            );
+                        }
+                    }
+                }
+            }
+            if let Some(writer) = writer.as_mut() {
+                writer.flush()?;
+            }
+            if graded > 0 {
+                eprintln!(
+                    "Accuracy: {:.2}% ({correct}/{graded}), length-normalized {:.2}% ({correct_normalized}/{graded})",
+                    100.0 * correct as f64 / graded as f64,
+                    100.0 * correct_normalized as f64 / graded as f64
+                );
+            }
+        }
+        Commands::Tokenize {
+            text,
+            file,
+            tokenizer,
+            no_special_tokens,
+            format,
+            context_length,
+        } => {
+            let (tokenizer, profile) = tokenizer.load()?;
+            let add_special_tokens = !no_special_tokens;
+
+            if !file.is_empty() {
+                if text.is_some() {
+                    bail!("Pass either text or --file, not both");
+                }
+                let context_length = context_length.or(profile.context_length);
+                let mut total = 0;
+                for path in &file {
+                    let data = fs::read_to_string(path)
+                        .with_context(|| format!("Failed to read {}", path.display()))?;
+                    let count = encode_ids(&tokenizer, &data, add_special_tokens)?.len();
+                    total += count;
+                    match context_length {
+                        Some(limit) => println!(
+                            "{}\t{count} tokens\t{:.1}% of {limit}{}",
+                            path.display(),
+                            100.0 * count as f64 / limit as f64,
+                            if count > limit { "\t(exceeds context)" } else { "" }
+                        ),
+                        None => println!("{}\t{count} tokens", path.display()),
+                    }
+                }
+                if file.len() > 1 {
+                    println!("total\t{total} tokens");
+                }
+                return Ok(());
+            }
+
+            let text = match text {
+                Some(text) => text,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let encoding = tokenizer
+                .encode(text.as_str(), add_special_tokens)
+                .map_err(|e| anyhow::anyhow!("Failed to tokenize text: {e}"))?;
+            match format {
+                TokenizeFormat::Ids => println!(
+                    "{}",
+                    encoding
+                        .get_ids()
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+                TokenizeFormat::Json => {
+                    let value = serde_json::json!({
+                        "count": encoding.len(),
+                        "ids": encoding.get_ids(),
+                        "tokens": encoding.get_tokens(),
+                        "offsets": encoding.get_offsets(),
+                    });
+                    println!("{}", serde_json::to_string_pretty(&value)?);
+                }
+                TokenizeFormat::Table => {
+                    for (index, ((id, token), (start, end))) in encoding
+                        .get_ids()
+                        .iter()
+                        .zip(encoding.get_tokens())
+                        .zip(encoding.get_offsets())
+                        .enumerate()
+                    {
+                        let piece = text.get(*start..*end).unwrap_or("");
+                        let range = format!("{start}..{end}");
+                        println!("{index:>5}  {id:>8}  {token:<20}  {range:<12}  {piece:?}");
+                    }
+                    println!("{} tokens", encoding.len());
+                }
+            }
+        }
+        Commands::Detokenize {
+            ids,
+            tokenizer,
+            skip_special_tokens,
+            tokens,
+        } => {
+            let (tokenizer, _) = tokenizer.load()?;
+            let ids: Vec<u32> = parse_input_ids(Some(ids))?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|id| u32::try_from(id).with_context(|| format!("Invalid token id {id}")))
+                .collect::<Result<_>>()?;
+            if tokens {
+                for id in &ids {
+                    let token = tokenizer.id_to_token(*id).unwrap_or_else(|| "<unknown>".to_string());
+                    println!("{id:>8}  {token}");
+                }
+            }
+            let text = tokenizer
+                .decode(&ids, skip_special_tokens)
+                .map_err(|e| anyhow::anyhow!("Failed to decode tokens: {e}"))?;
+            println!("{text}");
+        }
+        Commands::Ask(args) => ask(args)?,
+        Commands::Memory { command } => match command {
+            MemoryCommands::List => {
+                let store = memory_store()?;
+                for name in store.list()? {
+                    let state = store.session(&name)?.load()?;
+                    let updated = state
+                        .updated_at
+                        .map(|ts| ts.to_string())
+                        .unwrap_or_else(|| "-".to_string());
+                    println!(
+                        "{}\t{} turns\tupdated {}",
+                        name,
+                        state.conversation_history.len(),
+                        updated
+                    );
+                }
+            }
+            MemoryCommands::Delete { session } => {
+                let session_file = memory_store()?.session(&session)?;
+                if !session_file.exists() {
+                    bail!("Memory session '{session}' does not exist");
+                }
+                session_file.delete()?;
+                println!("Deleted memory session '{session}'");
+            }
+            MemoryCommands::Export {
+                session,
+                format,
+                output,
+            } => {
+                let session_file = memory_store()?.session(&session)?;
+                if !session_file.exists() {
+                    bail!("Memory session '{session}' does not exist");
+                }
+                let data = session_file.export(format.into())?;
+                match output {
+                    Some(path) => fs::write(&path, data)?,
+                    None => println!("{data}"),
+                }
+            }
+        },
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_flags_override_the_profile() {
+        let cli = Cli::try_parse_from(["llm-toy", "info", "--intra-threads", "8"]).unwrap();
+        let Commands::Info { model } = cli.command else {
+            panic!("expected the info command");
+        };
+        let profile = Profile {
+            intra_threads: Some(2),
+            inter_threads: Some(3),
+            ..Profile::default()
+        };
+        let options = model.session.options(&profile).unwrap();
+        assert_eq!(options.intra_threads, Some(8));
+        assert_eq!(options.inter_threads, Some(3));
+    }
+
+    #[test]
+    fn downloads_are_cached_per_remote_directory() {
+        let dir = |url: &str| url_cache_dir(url).unwrap();
+        let model = "https://example.com/org/a/resolve/main/onnx/model.onnx?download=true";
+        assert_eq!(
+            dir(model),
+            dir("https://example.com/org/a/resolve/main/onnx/model.onnx_data")
+        );
+        assert_ne!(
+            dir(model),
+            dir("https://example.com/org/b/resolve/main/onnx/model.onnx?download=true")
+        );
+        assert_eq!(
+            dir("https://example.com/m/tiny-00001-of-00002.gguf"),
+            dir("https://example.com/m/tiny-00002-of-00002.gguf")
+        );
+    }
+}
@@ -0,0 +1,906 @@
+use crate::embedding::{text_hash, Embedder, VectorIndex};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokenizers::Tokenizer;
+
+pub const DEFAULT_SESSION: &str = "default";
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct MemoryEntry {
+    pub prompt: String,
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+impl MemoryEntry {
+    pub fn new(prompt: impl Into<String>, response: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            response: response.into(),
+            timestamp: Some(unix_timestamp()),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct MemoryState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+    #[serde(default)]
+    pub conversation_history: Vec<MemoryEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub summarized_turns: usize,
+    #[serde(default, skip_serializing)]
+    last_prompt: Option<String>,
+    #[serde(default, skip_serializing)]
+    last_response: Option<String>,
+}
+
+impl MemoryState {
+    // Older memory files stored the latest turn both in `last_prompt` /
+    // `last_response` and in the history; only keep it when the history
+    // doesn't already have it.
+    fn upgrade_legacy(&mut self) {
+        let last_prompt = self.last_prompt.take();
+        let last_response = self.last_response.take();
+        if !self.conversation_history.is_empty() {
+            return;
+        }
+        if last_prompt.is_some() || last_response.is_some() {
+            self.conversation_history.push(MemoryEntry {
+                prompt: last_prompt.unwrap_or_default(),
+                response: last_response.unwrap_or_default(),
+                ..MemoryEntry::default()
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+}
+
+pub struct MemorySession {
+    path: PathBuf,
+}
+
+impl MemorySession {
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn vector_index_path(&self) -> PathBuf {
+        self.path.with_extension("vectors.bin")
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".lock");
+        self.path.with_file_name(name)
+    }
+
+    fn open_lock(&self) -> Result<fs::File> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let lock_path = self.lock_path();
+        fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open memory lock {}", lock_path.display()))
+    }
+
+    fn read_unlocked(&self) -> Result<MemoryState> {
+        if !self.path.exists() {
+            return Ok(MemoryState::default());
+        }
+        let data = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read memory file {}", self.path.display()))?;
+        let mut state: MemoryState = serde_json::from_str(&data).with_context(|| {
+            format!(
+                "Memory file {} is corrupt; fix or delete it to continue",
+                self.path.display()
+            )
+        })?;
+        state.upgrade_legacy();
+        Ok(state)
+    }
+
+    fn write_unlocked(&self, state: &MemoryState) -> Result<()> {
+        let data = serde_json::to_string_pretty(state)?;
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = self.path.with_file_name(tmp_name);
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to write memory file {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Reads the session without creating anything, so it works on
+    /// read-only directories. Writes replace the file atomically, so the
+    /// shared lock is only taken when a writer has created the lock file.
+    pub fn load(&self) -> Result<MemoryState> {
+        if !self.path.exists() {
+            return Ok(MemoryState::default());
+        }
+        let lock = fs::File::open(self.lock_path()).ok();
+        if let Some(lock) = lock.as_ref() {
+            lock.lock_shared()?;
+        }
+        self.read_unlocked()
+    }
+
+    /// Re-reads the session under an exclusive lock, applies `f` and writes
+    /// the result back, so concurrent runs append instead of overwriting
+    /// each other.
+    pub fn update<T>(&self, f: impl FnOnce(&mut MemoryState) -> T) -> Result<T> {
+        let lock = self.open_lock()?;
+        lock.lock()?;
+        let mut state = self.read_unlocked()?;
+        let out = f(&mut state);
+        let now = unix_timestamp();
+        state.created_at.get_or_insert(now);
+        state.updated_at = Some(now);
+        self.write_unlocked(&state)?;
+        Ok(out)
+    }
+
+    /// Removes the memory file and its vector index. The lock file stays:
+    /// a process already waiting on it and one creating a new one could
+    /// otherwise both hold the lock.
+    pub fn delete(&self) -> Result<()> {
+        let lock = self.open_lock()?;
+        lock.lock()?;
+        if self.path.exists() {
+            fs::remove_file(&self.path)
+                .with_context(|| format!("Failed to delete memory file {}", self.path.display()))?;
+        }
+        let _ = fs::remove_file(self.vector_index_path());
+        Ok(())
+    }
+
+    pub fn export(&self, format: ExportFormat) -> Result<String> {
+        let state = self.load()?;
+        match format {
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(&state)?),
+            ExportFormat::Markdown => Ok(export_markdown(&state)),
+        }
+    }
+}
+
+pub struct MemoryStore {
+    dir: PathBuf,
+}
+
+impl MemoryStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn session(&self, name: &str) -> Result<MemorySession> {
+        validate_session_name(name)?;
+        Ok(MemorySession::at(self.dir.join(format!("{name}.json"))))
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+fn validate_session_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        bail!("Invalid session name '{name}': use letters, digits, '-', '_' or '.'");
+    }
+    Ok(())
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn export_markdown(state: &MemoryState) -> String {
+    let mut out = String::new();
+    if let Some(system) = state.system_prompt.as_ref() {
+        out.push_str("## System\n\n");
+        out.push_str(system);
+        out.push_str("\n\n");
+    }
+    if let Some(summary) = state.summary.as_ref() {
+        out.push_str(&format!(
+            "## Summary (first {} turns)\n\n",
+            state.summarized_turns
+        ));
+        out.push_str(summary);
+        out.push_str("\n\n");
+    }
+    for entry in &state.conversation_history {
+        out.push_str("## User");
+        if let Some(ts) = entry.timestamp {
+            out.push_str(&format!(" ({ts})"));
+        }
+        out.push_str("\n\n");
+        out.push_str(&entry.prompt);
+        out.push_str("\n\n## Assistant");
+        if let Some(model) = entry.model.as_ref() {
+            out.push_str(&format!(" ({model})"));
+        }
+        out.push_str("\n\n");
+        out.push_str(&entry.response);
+        out.push_str("\n\n");
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TruncationStrategy {
+    #[default]
+    DropOldest,
+    TruncateMiddle,
+    Error,
+}
+
+impl FromStr for TruncationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "truncate-middle" => Ok(Self::TruncateMiddle),
+            "error" => Ok(Self::Error),
+            _ => bail!("Unknown truncation strategy '{s}' (expected drop-oldest, truncate-middle or error)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ContextBudget {
+    pub context_length: usize,
+    pub reserved_tokens: usize,
+    pub strategy: TruncationStrategy,
+}
+
+pub trait TokenCounter {
+    fn count_tokens(&self, text: &str) -> Result<usize>;
+}
+
+impl TokenCounter for Tokenizer {
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        let encoding = self
+            .encode(text, false)
+            .map_err(|e| anyhow::anyhow!("Failed to tokenize memory: {e}"))?;
+        Ok(encoding.len())
+    }
+}
+
+/// Rough estimate for backends that run without a tokenizer.
+pub struct ApproxTokenCounter;
+
+impl TokenCounter for ApproxTokenCounter {
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(text.chars().count().div_ceil(4))
+    }
+}
+
+const ELIDED_MARKER: &str = "[... turns omitted ...]\n\n";
+
+fn format_turn(entry: &MemoryEntry) -> String {
+    let normalize = |text: &str| text.replace("\r\n", "\n").replace('\r', "\n");
+    format!(
+        "User:\n{}\n\nAssistant:\n{}\n\n",
+        normalize(&entry.prompt),
+        normalize(&entry.response)
+    )
+}
+
+fn render_prompt(
+    prompt: &str,
+    system: Option<&str>,
+    summary: Option<&str>,
+    relevant: &[String],
+    turns: &[String],
+) -> String {
+    let mut combined = String::new();
+    if let Some(system) = system {
+        combined.push_str("### System\n");
+        combined.push_str(system);
+        combined.push_str("\n\n");
+    }
+    if let Some(summary) = summary {
+        combined.push_str("### Summary\n");
+        combined.push_str(summary);
+        combined.push_str("\n\n");
+    }
+    if !relevant.is_empty() {
+        combined.push_str("### Relevant\n");
+        for turn in relevant {
+            combined.push_str(turn);
+        }
+    }
+    if !turns.is_empty() {
+        combined.push_str("### Previous\n");
+        for turn in turns {
+            combined.push_str(turn);
+        }
+    }
+    combined.push_str("### Current\nUser:\n");
+    combined.push_str(prompt);
+    combined.push_str("\n\nAssistant:");
+    combined
+}
+
+#[derive(Debug)]
+pub struct MemoryPlan {
+    pub prompt: String,
+    /// History turns that didn't fit and aren't covered by the summary yet.
+    pub evicted: Range<usize>,
+}
+
+/// Builds the prompt with as much of the session history as fits in the
+/// context window after reserving `budget.reserved_tokens` for generation.
+/// Turns already folded into the running summary are replaced by it, and
+/// `retrieved` turns (most relevant first) are placed before recent history.
+pub fn plan_memory(
+    prompt: &str,
+    memory: &MemoryState,
+    retrieved: &[usize],
+    budget: &ContextBudget,
+    counter: &dyn TokenCounter,
+) -> Result<MemoryPlan> {
+    let system = memory.system_prompt.as_deref();
+    let summary = memory.summary.as_deref();
+    let base = render_prompt(prompt, system, summary, &[], &[]);
+    let base_tokens = counter.count_tokens(&base)?;
+    let needed = base_tokens + budget.reserved_tokens;
+    if needed > budget.context_length {
+        bail!(
+            "Prompt needs {base_tokens} tokens plus {} reserved for generation, which exceeds the context length of {}",
+            budget.reserved_tokens,
+            budget.context_length
+        );
+    }
+    let mut available = budget.context_length - needed;
+
+    let mut relevant_ids = Vec::new();
+    let relevant_header = counter.count_tokens("### Relevant\n")?;
+    let mut relevant_used = 0;
+    for &idx in retrieved {
+        let Some(entry) = memory.conversation_history.get(idx) else {
+            continue;
+        };
+        let cost = counter.count_tokens(&format_turn(entry))?;
+        if relevant_header + relevant_used + cost > available {
+            continue;
+        }
+        relevant_used += cost;
+        relevant_ids.push(idx);
+    }
+    if !relevant_ids.is_empty() {
+        available -= relevant_header + relevant_used;
+    }
+    relevant_ids.sort_unstable();
+
+    let offset = memory.summarized_turns.min(memory.conversation_history.len());
+    let turns: Vec<String> = memory.conversation_history[offset..]
+        .iter()
+        .map(format_turn)
+        .collect();
+    let mut turn_tokens = Vec::with_capacity(turns.len());
+    for turn in &turns {
+        turn_tokens.push(counter.count_tokens(turn)?);
+    }
+    // The "### Previous" header is only paid for when history is included.
+    let header_tokens = counter.count_tokens("### Previous\n")?;
+    let total: usize = turn_tokens.iter().sum::<usize>() + header_tokens;
+
+    if turns.is_empty() || total <= available {
+        let relevant = render_relevant(memory, &relevant_ids, offset, &(offset..offset));
+        return Ok(MemoryPlan {
+            prompt: render_prompt(prompt, system, summary, &relevant, &turns),
+            evicted: offset..offset,
+        });
+    }
+
+    let (selected, evicted) = match budget.strategy {
+        TruncationStrategy::Error => bail!(
+            "Conversation history needs {total} tokens but only {available} fit in the context window"
+        ),
+        TruncationStrategy::DropOldest => {
+            let start = fit_newest(&turn_tokens, 0, available.saturating_sub(header_tokens));
+            (turns[start..].to_vec(), offset..offset + start)
+        }
+        TruncationStrategy::TruncateMiddle => {
+            let marker_tokens = counter.count_tokens(ELIDED_MARKER)?;
+            let head = turn_tokens[0] + marker_tokens + header_tokens;
+            if head <= available {
+                let start = fit_newest(&turn_tokens, 1, available - head);
+                let mut selected = vec![turns[0].clone()];
+                if start > 1 {
+                    selected.push(ELIDED_MARKER.to_string());
+                }
+                selected.extend_from_slice(&turns[start..]);
+                (selected, offset + 1..offset + start.max(1))
+            } else {
+                let start =
+                    fit_newest(&turn_tokens, 0, available.saturating_sub(header_tokens));
+                (turns[start..].to_vec(), offset..offset + start)
+            }
+        }
+    };
+    let relevant = render_relevant(memory, &relevant_ids, offset, &evicted);
+    Ok(MemoryPlan {
+        prompt: render_prompt(prompt, system, summary, &relevant, &selected),
+        evicted,
+    })
+}
+
+// Retrieved turns that also made it into the recent window are only
+// rendered once, under "Previous".
+fn render_relevant(
+    memory: &MemoryState,
+    ids: &[usize],
+    offset: usize,
+    evicted: &Range<usize>,
+) -> Vec<String> {
+    ids.iter()
+        .filter(|&&idx| idx < offset || evicted.contains(&idx))
+        .map(|&idx| format_turn(&memory.conversation_history[idx]))
+        .collect()
+}
+
+pub fn entry_text(entry: &MemoryEntry) -> String {
+    format!("User: {}\nAssistant: {}", entry.prompt, entry.response)
+}
+
+/// Embeds history entries that are missing from `index` (or whose text
+/// changed) and drops vectors for entries that no longer exist. Returns
+/// whether the index was modified.
+pub fn sync_vector_index(
+    memory: &MemoryState,
+    index: &mut VectorIndex,
+    embedder: &mut dyn Embedder,
+) -> Result<bool> {
+    let len = memory.conversation_history.len() as u64;
+    let before = index.entries.len();
+    index.retain(|entry| entry.id < len);
+    let mut changed = index.entries.len() != before;
+
+    let mut pending = Vec::new();
+    for (idx, entry) in memory.conversation_history.iter().enumerate() {
+        let text = entry_text(entry);
+        let hash = text_hash(&text);
+        if index.get(idx as u64).is_some_and(|v| v.text_hash == hash) {
+            continue;
+        }
+        pending.push((idx as u64, hash, text));
+    }
+
+    for chunk in pending.chunks(16) {
+        let texts: Vec<&str> = chunk.iter().map(|(_, _, text)| text.as_str()).collect();
+        let vectors = embedder.embed(&texts)?;
+        for ((id, hash, _), vector) in chunk.iter().zip(vectors) {
+            index.insert(*id, *hash, vector)?;
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// Returns the history positions of the `k` entries most similar to `query`,
+/// most similar first.
+pub fn retrieve(index: &VectorIndex, query: &[f32], k: usize) -> Vec<usize> {
+    index
+        .search(query, k)
+        .into_iter()
+        .map(|(id, _)| id as usize)
+        .collect()
+}
+
+const SUMMARY_INSTRUCTIONS: &str = "You maintain a running summary of a conversation. \
+Merge the existing summary with the new turns into one concise summary that keeps names, \
+facts, preferences, decisions and open questions. Reply with the summary only.";
+
+fn summary_prompt(summary: Option<&str>, turns: &[String]) -> String {
+    let mut combined = String::new();
+    combined.push_str("### System\n");
+    combined.push_str(SUMMARY_INSTRUCTIONS);
+    combined.push_str("\n\n");
+    if let Some(summary) = summary {
+        combined.push_str("### Existing summary\n");
+        combined.push_str(summary);
+        combined.push_str("\n\n");
+    }
+    combined.push_str("### New turns\n");
+    for turn in turns {
+        combined.push_str(turn);
+    }
+    combined.push_str("### Current\nUser:\nWrite the updated summary.\n\nAssistant:");
+    combined
+}
+
+fn truncate_to_tokens(text: &str, tokens: usize, max_tokens: usize) -> String {
+    if tokens <= max_tokens || tokens == 0 {
+        return text.to_string();
+    }
+    let keep = text.chars().count() * max_tokens / tokens;
+    let mut out: String = text.chars().take(keep).collect();
+    out.push_str("\n[...]\n\n");
+    out
+}
+
+/// Folds the history turns in `memory.summarized_turns..up_to` into the
+/// running summary, packing as many turns into each `generate` call as the
+/// context allows. Returns the new summary and the number of turns it covers.
+pub fn summarize_history(
+    memory: &MemoryState,
+    up_to: usize,
+    budget: &ContextBudget,
+    counter: &dyn TokenCounter,
+    generate: &mut dyn FnMut(&str) -> Result<String>,
+) -> Result<(Option<String>, usize)> {
+    let up_to = up_to.min(memory.conversation_history.len());
+    let mut summary = memory.summary.clone();
+    let mut next = memory.summarized_turns.min(up_to);
+
+    while next < up_to {
+        let base = summary_prompt(summary.as_deref(), &[]);
+        let needed = counter.count_tokens(&base)? + budget.reserved_tokens;
+        if needed >= budget.context_length {
+            bail!(
+                "Running summary no longer fits in the context length of {}",
+                budget.context_length
+            );
+        }
+        let available = budget.context_length - needed;
+
+        let mut batch = Vec::new();
+        let mut used = 0;
+        while next < up_to {
+            let turn = format_turn(&memory.conversation_history[next]);
+            let tokens = counter.count_tokens(&turn)?;
+            if batch.is_empty() && tokens > available {
+                batch.push(truncate_to_tokens(&turn, tokens, available));
+                next += 1;
+                break;
+            }
+            if used + tokens > available {
+                break;
+            }
+            used += tokens;
+            batch.push(turn);
+            next += 1;
+        }
+
+        let text = generate(&summary_prompt(summary.as_deref(), &batch))?;
+        let text = text.trim();
+        if !text.is_empty() {
+            summary = Some(text.to_string());
+        }
+    }
+
+    Ok((summary, next))
+}
+
+// Returns the index of the oldest turn (not before `min_start`) such that
+// every turn from there to the newest fits within `available` tokens.
+fn fit_newest(turn_tokens: &[usize], min_start: usize, available: usize) -> usize {
+    let mut used = 0;
+    let mut start = turn_tokens.len();
+    while start > min_start {
+        let cost = turn_tokens[start - 1];
+        if used + cost > available {
+            break;
+        }
+        used += cost;
+        start -= 1;
+    }
+    start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One token per whitespace-separated word, so budgets are easy to
+    /// count by hand: every turn below costs 4 and each header 2.
+    struct Words;
+
+    impl TokenCounter for Words {
+        fn count_tokens(&self, text: &str) -> Result<usize> {
+            Ok(text.split_whitespace().count())
+        }
+    }
+
+    const TURN_TOKENS: usize = 4;
+    const HEADER_TOKENS: usize = 2;
+
+    fn memory(turns: usize) -> MemoryState {
+        MemoryState {
+            conversation_history: (0..turns)
+                .map(|turn| MemoryEntry {
+                    prompt: format!("question{turn}"),
+                    response: format!("answer{turn}"),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// A budget leaving `history` tokens for the history section.
+    fn budget(memory: &MemoryState, history: usize, strategy: TruncationStrategy) -> ContextBudget {
+        let base = render_prompt("now", None, memory.summary.as_deref(), &[], &[]);
+        ContextBudget {
+            context_length: Words.count_tokens(&base).unwrap() + 10 + history,
+            reserved_tokens: 10,
+            strategy,
+        }
+    }
+
+    fn included(plan: &MemoryPlan, turns: usize) -> Vec<usize> {
+        (0..turns)
+            .filter(|turn| plan.prompt.contains(&format!("question{turn}\n")))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_all_history_that_fits() {
+        let memory = memory(5);
+        let budget = budget(&memory, HEADER_TOKENS + 5 * TURN_TOKENS, TruncationStrategy::Error);
+        let plan = plan_memory("now", &memory, &[], &budget, &Words).unwrap();
+        assert_eq!(included(&plan, 5), [0, 1, 2, 3, 4]);
+        assert!(plan.evicted.is_empty());
+        assert!(plan.prompt.ends_with("### Current\nUser:\nnow\n\nAssistant:"));
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_turns_that_fit() {
+        let memory = memory(5);
+        let budget = budget(&memory, HEADER_TOKENS + 3 * TURN_TOKENS + 3, TruncationStrategy::DropOldest);
+        let plan = plan_memory("now", &memory, &[], &budget, &Words).unwrap();
+        assert_eq!(included(&plan, 5), [2, 3, 4]);
+        assert_eq!(plan.evicted, 0..2);
+    }
+
+    #[test]
+    fn truncate_middle_keeps_the_first_turn() {
+        let memory = memory(5);
+        let marker = Words.count_tokens(ELIDED_MARKER).unwrap();
+        let budget = budget(
+            &memory,
+            HEADER_TOKENS + marker + 3 * TURN_TOKENS,
+            TruncationStrategy::TruncateMiddle,
+        );
+        let plan = plan_memory("now", &memory, &[], &budget, &Words).unwrap();
+        assert_eq!(included(&plan, 5), [0, 3, 4]);
+        assert!(plan.prompt.contains(ELIDED_MARKER));
+        assert_eq!(plan.evicted, 1..3);
+    }
+
+    #[test]
+    fn truncate_middle_drops_the_first_turn_when_it_cannot_fit() {
+        let memory = memory(5);
+        let budget = budget(&memory, HEADER_TOKENS + TURN_TOKENS, TruncationStrategy::TruncateMiddle);
+        let plan = plan_memory("now", &memory, &[], &budget, &Words).unwrap();
+        assert_eq!(included(&plan, 5), [4]);
+        assert!(!plan.prompt.contains(ELIDED_MARKER));
+        assert_eq!(plan.evicted, 0..4);
+    }
+
+    #[test]
+    fn error_strategy_refuses_to_drop_history() {
+        let memory = memory(5);
+        let budget = budget(&memory, HEADER_TOKENS + 5 * TURN_TOKENS - 1, TruncationStrategy::Error);
+        let err = plan_memory("now", &memory, &[], &budget, &Words).unwrap_err();
+        assert!(err.to_string().contains("needs 22 tokens but only 21 fit"), "{err}");
+    }
+
+    #[test]
+    fn prompt_and_reserve_must_fit_on_their_own() {
+        let memory = memory(1);
+        let budget = ContextBudget {
+            context_length: 12,
+            reserved_tokens: 10,
+            strategy: TruncationStrategy::DropOldest,
+        };
+        let err = plan_memory("now", &memory, &[], &budget, &Words).unwrap_err();
+        assert!(err.to_string().contains("exceeds the context length of 12"), "{err}");
+    }
+
+    #[test]
+    fn summarized_turns_are_replaced_by_the_summary() {
+        let mut memory = memory(5);
+        memory.summary = Some("earlier turns".to_string());
+        memory.summarized_turns = 2;
+        let budget = budget(&memory, HEADER_TOKENS + 2 * TURN_TOKENS, TruncationStrategy::DropOldest);
+        let plan = plan_memory("now", &memory, &[], &budget, &Words).unwrap();
+        assert!(plan.prompt.starts_with("### Summary\nearlier turns\n\n"));
+        assert_eq!(included(&plan, 5), [3, 4]);
+        assert_eq!(plan.evicted, 2..3);
+    }
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "llm-toy-memory-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn session(&self) -> MemorySession {
+            MemorySession::at(self.0.join("chat.json"))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn concurrent_updates_all_land() {
+        let dir = TempDir::new();
+        let path = dir.session().path().to_path_buf();
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let session = MemorySession::at(path.clone());
+                std::thread::spawn(move || {
+                    for turn in 0..10 {
+                        session
+                            .update(|state| {
+                                let entry = MemoryEntry::new(format!("{writer}/{turn}"), "");
+                                state.conversation_history.push(entry);
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let state = dir.session().load().unwrap();
+        assert_eq!(state.conversation_history.len(), 80);
+        assert!(state.created_at.is_some() && state.updated_at.is_some());
+    }
+
+    #[test]
+    fn delete_keeps_the_lock_file() {
+        let dir = TempDir::new();
+        let session = dir.session();
+        session.update(|state| state.summary = Some("kept".into())).unwrap();
+        fs::write(session.vector_index_path(), b"vectors").unwrap();
+
+        session.delete().unwrap();
+        assert!(!session.exists());
+        assert!(!session.vector_index_path().exists());
+        assert!(session.lock_path().exists());
+        assert!(session.load().unwrap().summary.is_none());
+    }
+
+    #[test]
+    fn loading_creates_nothing() {
+        let dir = TempDir::new();
+        let session = MemorySession::at(dir.0.join("missing/chat.json"));
+        assert!(session.load().unwrap().conversation_history.is_empty());
+        assert!(!dir.0.join("missing").exists());
+
+        // An existing file without a lock file is read as is.
+        let session = dir.session();
+        fs::write(session.path(), r#"{"summary": "old"}"#).unwrap();
+        assert_eq!(session.load().unwrap().summary.as_deref(), Some("old"));
+        assert!(!session.lock_path().exists());
+    }
+
+    #[test]
+    fn upgrades_legacy_files() {
+        let dir = TempDir::new();
+        let session = dir.session();
+        fs::write(session.path(), r#"{"last_prompt": "hi", "last_response": "hello"}"#).unwrap();
+        let history = session.load().unwrap().conversation_history;
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].prompt.as_str(), history[0].response.as_str()), ("hi", "hello"));
+
+        // The legacy fields are dropped on the next write.
+        session.update(|_| ()).unwrap();
+        let data = fs::read_to_string(session.path()).unwrap();
+        assert!(!data.contains("last_prompt"));
+        assert_eq!(session.load().unwrap().conversation_history.len(), 1);
+
+        // A file that also has history already holds the latest turn.
+        fs::write(
+            session.path(),
+            r#"{"conversation_history": [{"prompt": "hi", "response": "hello"}],
+                "last_prompt": "hi", "last_response": "hello"}"#,
+        )
+        .unwrap();
+        assert_eq!(session.load().unwrap().conversation_history.len(), 1);
+    }
+
+    #[test]
+    fn corrupt_files_are_reported_and_left_alone() {
+        let dir = TempDir::new();
+        let session = dir.session();
+        fs::write(session.path(), "{\"conversation_history\": [").unwrap();
+
+        let err = session.load().unwrap_err();
+        assert!(format!("{err:#}").contains("is corrupt; fix or delete it"), "{err:#}");
+        assert!(session.update(|state| state.summary = None).is_err());
+        assert_eq!(
+            fs::read_to_string(session.path()).unwrap(),
+            "{\"conversation_history\": ["
+        );
+    }
+}
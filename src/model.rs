@@ -0,0 +1,95 @@
+//! `Model` bundles a loaded backend with what the CLI otherwise wires up by
+//! hand: the tokenizer path, chat template, context length and sampling
+//! defaults.
+
+use crate::config::{apply_chat_template, clean_answer};
+use crate::{
+    load_model, model_files, GenerationConfig, InferenceRequest, InferenceResponse, ModelConfig,
+    NpuBackend, Result,
+};
+use std::path::Path;
+
+pub struct Model {
+    backend: Box<dyn NpuBackend>,
+    config: ModelConfig,
+    generation: GenerationConfig,
+}
+
+impl Model {
+    /// Loads `config.path` with `config.npu_backend` (or `auto`), using the
+    /// model's `generation_config.json` for sampling defaults if it has one.
+    pub fn load(config: ModelConfig) -> Result<Self> {
+        let generation = GenerationConfig::for_model(Path::new(&config.path))?;
+        let backend = load_model(&config)?;
+        Ok(Self {
+            backend,
+            config,
+            generation,
+        })
+    }
+
+    /// Wraps a backend that already has the model loaded.
+    pub fn from_backend(backend: Box<dyn NpuBackend>, config: ModelConfig) -> Self {
+        Self {
+            backend,
+            config,
+            generation: GenerationConfig::default(),
+        }
+    }
+
+    pub fn with_generation_config(mut self, generation: GenerationConfig) -> Self {
+        self.generation = generation;
+        self
+    }
+
+    pub fn config(&self) -> &ModelConfig {
+        &self.config
+    }
+
+    pub fn generation_config(&self) -> &GenerationConfig {
+        &self.generation
+    }
+
+    pub fn backend(&self) -> &dyn NpuBackend {
+        self.backend.as_ref()
+    }
+
+    pub fn backend_mut(&mut self) -> &mut dyn NpuBackend {
+        self.backend.as_mut()
+    }
+
+    pub fn context_length(&self) -> Option<usize> {
+        self.config
+            .context_length
+            .or_else(|| self.backend.context_length())
+            .or_else(|| model_files::context_length(Path::new(&self.config.path)))
+    }
+
+    /// The request `generate` runs: `prompt` in the chat template, with the
+    /// model's tokenizer, context length and sampling defaults.
+    pub fn request(&self, prompt: &str) -> InferenceRequest {
+        let prompt = match self.config.chat_template.as_deref() {
+            Some(template) => apply_chat_template(template, prompt),
+            None => prompt.to_string(),
+        };
+        InferenceRequest {
+            tokenizer_path: self.config.tokenizer_path.clone(),
+            context_length: self.context_length(),
+            ..self.generation.request(prompt)
+        }
+    }
+
+    /// Generates a reply to `prompt`. The response text holds only the
+    /// completion, without the prompt or template.
+    pub fn generate(&mut self, prompt: &str) -> Result<InferenceResponse> {
+        let request = self.request(prompt);
+        self.run(prompt, &request)
+    }
+
+    /// Like `generate`, for a request customised from `request(prompt)`.
+    pub fn run(&mut self, prompt: &str, request: &InferenceRequest) -> Result<InferenceResponse> {
+        let mut response = self.backend.run(request)?;
+        response.text = clean_answer(prompt, &response.text);
+        Ok(response)
+    }
+}
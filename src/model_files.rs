@@ -0,0 +1,642 @@
+use crate::Error;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+const GGUF_EXTENSION: &str = ".gguf";
+
+// ONNX protobuf field numbers (onnx.proto3).
+const MODEL_GRAPH: u32 = 7;
+const GRAPH_INITIALIZER: u32 = 5;
+const TENSOR_EXTERNAL_DATA: u32 = 13;
+const ENTRY_KEY: u32 = 1;
+const ENTRY_VALUE: u32 = 2;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+// Longest metadata key or external-data location accepted; lengths come
+// from the file, so anything larger is treated as corruption.
+const MAX_STRING_LEN: u64 = 1 << 20;
+const CONTEXT_LENGTH_KEYS: [&str; 4] = [
+    "max_position_embeddings",
+    "n_positions",
+    "max_sequence_length",
+    "seq_length",
+];
+
+pub struct GgufSplit {
+    pub prefix: String,
+    pub index: usize,
+    pub count: usize,
+}
+
+impl GgufSplit {
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let stem = file_name.strip_suffix(GGUF_EXTENSION)?;
+        let (rest, count) = stem.rsplit_once("-of-")?;
+        let (prefix, index) = rest.rsplit_once('-')?;
+        if index.len() != 5 || count.len() != 5 {
+            return None;
+        }
+        let index: usize = index.parse().ok()?;
+        let count: usize = count.parse().ok()?;
+        if index == 0 || count == 0 || index > count {
+            return None;
+        }
+        Some(Self {
+            prefix: prefix.to_string(),
+            index,
+            count,
+        })
+    }
+
+    pub fn shard_name(&self, index: usize) -> String {
+        format!(
+            "{}-{:05}-of-{:05}{}",
+            self.prefix, index, self.count, GGUF_EXTENSION
+        )
+    }
+
+    pub fn shard_names(&self) -> Vec<String> {
+        (1..=self.count).map(|i| self.shard_name(i)).collect()
+    }
+}
+
+pub fn is_gguf(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gguf"))
+}
+
+pub fn is_onnx(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("onnx"))
+}
+
+/// Returns the files a model needs besides `model_path` itself, relative to
+/// its directory: external data referenced by an ONNX graph, or the sibling
+/// shards of a split GGUF.
+pub fn companion_files(model_path: &Path) -> Result<Vec<String>> {
+    let file_name = model_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    if let Some(split) = GgufSplit::parse(file_name) {
+        return Ok(split
+            .shard_names()
+            .into_iter()
+            .filter(|name| name != file_name)
+            .collect());
+    }
+
+    if is_onnx(model_path) {
+        return onnx_external_data_files(model_path);
+    }
+
+    Ok(Vec::new())
+}
+
+/// Returns every file that makes up the model, starting with `model_path`
+/// (or the first shard of a split GGUF).
+pub fn model_files(model_path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = model_path.parent().unwrap_or_else(|| Path::new(""));
+    let file_name = model_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    if let Some(split) = GgufSplit::parse(file_name) {
+        return Ok(split
+            .shard_names()
+            .into_iter()
+            .map(|name| dir.join(name))
+            .collect());
+    }
+
+    let mut files = vec![model_path.to_path_buf()];
+    for name in companion_files(model_path)? {
+        files.push(dir.join(name));
+    }
+    Ok(files)
+}
+
+pub fn check_model_files(model_path: &Path) -> crate::Result<()> {
+    if !model_path.exists() {
+        return Err(Error::ModelNotFound {
+            path: model_path.to_path_buf(),
+        });
+    }
+
+    let missing: Vec<PathBuf> = model_files(model_path)?
+        .into_iter()
+        .filter(|path| !path.exists())
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error::MissingModelFiles {
+            path: model_path.to_path_buf(),
+            missing,
+        });
+    }
+    Ok(())
+}
+
+pub fn total_size(model_path: &Path) -> Result<u64> {
+    let mut total = 0;
+    for path in model_files(model_path)? {
+        total += fs::metadata(&path)
+            .with_context(|| format!("Failed to stat {}", path.display()))?
+            .len();
+    }
+    Ok(total)
+}
+
+/// Lists the external data locations referenced by the initializers of an
+/// ONNX model. Tensor payloads are skipped rather than read, so this stays
+/// cheap for single-file models with embedded weights.
+pub fn onnx_external_data_files(model_path: &Path) -> Result<Vec<String>> {
+    let file = fs::File::open(model_path)
+        .with_context(|| format!("Failed to open {}", model_path.display()))?;
+    let len = file.metadata()?.len();
+    let mut reader = ProtoReader::new(BufReader::new(file));
+
+    let mut locations = Vec::new();
+    reader
+        .walk_model(len, &mut locations)
+        .with_context(|| format!("Failed to parse ONNX model {}", model_path.display()))?;
+
+    let mut files = Vec::new();
+    for location in locations {
+        if !is_safe_relative(&location) {
+            bail!("ONNX external data location escapes the model directory: {location}");
+        }
+        if !files.contains(&location) {
+            files.push(location);
+        }
+    }
+    Ok(files)
+}
+
+fn is_safe_relative(location: &str) -> bool {
+    let path = Path::new(location);
+    !location.is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+struct ProtoReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read + Seek> ProtoReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.inner.read_exact(&mut buf)?;
+        self.pos += 1;
+        Ok(buf[0])
+    }
+
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Malformed varint")
+    }
+
+    fn read_key(&mut self) -> Result<(u32, u8)> {
+        let key = self.read_varint()?;
+        Ok(((key >> 3) as u32, (key & 0x7) as u8))
+    }
+
+    fn read_string(&mut self, len: u64) -> Result<String> {
+        let text = read_string(&mut self.inner, len)?;
+        self.pos += len;
+        Ok(text)
+    }
+
+    fn skip_bytes(&mut self, len: u64) -> Result<()> {
+        self.pos = self.advance(len)?;
+        self.inner.seek(SeekFrom::Current(len as i64))?;
+        Ok(())
+    }
+
+    fn advance(&self, len: u64) -> Result<u64> {
+        self.pos
+            .checked_add(len)
+            .filter(|end| i64::try_from(*end).is_ok())
+            .context("Field length runs past any possible file size")
+    }
+
+    fn skip_field(&mut self, wire: u8) -> Result<()> {
+        match wire {
+            WIRE_VARINT => {
+                self.read_varint()?;
+            }
+            WIRE_FIXED64 => self.skip_bytes(8)?,
+            WIRE_LEN => {
+                let len = self.read_varint()?;
+                self.skip_bytes(len)?;
+            }
+            WIRE_FIXED32 => self.skip_bytes(4)?,
+            _ => bail!("Unsupported protobuf wire type {wire}"),
+        }
+        Ok(())
+    }
+
+    fn message_end(&mut self) -> Result<u64> {
+        let len = self.read_varint()?;
+        self.advance(len)
+    }
+
+    fn walk_model(&mut self, end: u64, locations: &mut Vec<String>) -> Result<()> {
+        while self.pos < end {
+            let (field, wire) = self.read_key()?;
+            if field == MODEL_GRAPH && wire == WIRE_LEN {
+                let graph_end = self.message_end()?;
+                self.walk_graph(graph_end, locations)?;
+            } else {
+                self.skip_field(wire)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn walk_graph(&mut self, end: u64, locations: &mut Vec<String>) -> Result<()> {
+        while self.pos < end {
+            let (field, wire) = self.read_key()?;
+            if field == GRAPH_INITIALIZER && wire == WIRE_LEN {
+                let tensor_end = self.message_end()?;
+                self.walk_tensor(tensor_end, locations)?;
+            } else {
+                self.skip_field(wire)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn walk_tensor(&mut self, end: u64, locations: &mut Vec<String>) -> Result<()> {
+        while self.pos < end {
+            let (field, wire) = self.read_key()?;
+            if field == TENSOR_EXTERNAL_DATA && wire == WIRE_LEN {
+                let entry_end = self.message_end()?;
+                if let Some(location) = self.read_location_entry(entry_end)? {
+                    locations.push(location);
+                }
+            } else {
+                self.skip_field(wire)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_location_entry(&mut self, end: u64) -> Result<Option<String>> {
+        let mut key = None;
+        let mut value = None;
+        while self.pos < end {
+            let (field, wire) = self.read_key()?;
+            match (field, wire) {
+                (ENTRY_KEY, WIRE_LEN) => {
+                    let len = self.read_varint()?;
+                    key = Some(self.read_string(len)?);
+                }
+                (ENTRY_VALUE, WIRE_LEN) => {
+                    let len = self.read_varint()?;
+                    value = Some(self.read_string(len)?);
+                }
+                _ => self.skip_field(wire)?,
+            }
+        }
+        Ok(match key.as_deref() {
+            Some("location") => value,
+            _ => None,
+        })
+    }
+}
+
+/// Looks up the model's context length from GGUF metadata or a
+/// `config.json` next to (or one level above) the model file.
+pub fn context_length(model_path: &Path) -> Option<usize> {
+    let file_name = model_path.file_name()?.to_str()?;
+    if file_name.ends_with(GGUF_EXTENSION) {
+        let first_shard = model_files(model_path).ok()?.into_iter().next()?;
+        return gguf_context_length(&first_shard).ok().flatten();
+    }
+
+    let dir = model_path.parent()?;
+    [Some(dir), dir.parent()]
+        .into_iter()
+        .flatten()
+        .find_map(|dir| config_json_context_length(&dir.join("config.json")))
+}
+
+fn config_json_context_length(path: &Path) -> Option<usize> {
+    let data = fs::read_to_string(path).ok()?;
+    let value: serde_json::Value = serde_json::from_str(&data).ok()?;
+    let config = value.get("text_config").unwrap_or(&value);
+    CONTEXT_LENGTH_KEYS
+        .iter()
+        .find_map(|key| config.get(*key).and_then(|v| v.as_u64()))
+        .map(|v| v as usize)
+}
+
+pub fn gguf_context_length(path: &Path) -> Result<Option<usize>> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != GGUF_MAGIC {
+        bail!("{} is not a GGUF file", path.display());
+    }
+    let version = read_u32(&mut reader)?;
+    if version < 2 {
+        bail!("Unsupported GGUF version {version}");
+    }
+    let _tensor_count = read_u64(&mut reader)?;
+    let kv_count = read_u64(&mut reader)?;
+
+    for _ in 0..kv_count {
+        let key = read_gguf_string(&mut reader)?;
+        let ty = read_u32(&mut reader)?;
+        if key.ends_with(".context_length") {
+            return Ok(read_gguf_uint(&mut reader, ty)?.map(|v| v as usize));
+        }
+        skip_gguf_value(&mut reader, ty)?;
+    }
+    Ok(None)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_gguf_string(reader: &mut impl Read) -> Result<String> {
+    let len = read_u64(reader)?;
+    read_string(reader, len)
+}
+
+/// Reads `len` bytes without allocating them up front, so a corrupt length
+/// fails on the short read instead of exhausting memory.
+fn read_string(reader: &mut impl Read, len: u64) -> Result<String> {
+    if len > MAX_STRING_LEN {
+        bail!("String of {len} bytes is longer than any name or path; the file is corrupt");
+    }
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        bail!("Unexpected end of file");
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn seek_forward(reader: &mut impl Seek, len: u64) -> Result<()> {
+    let offset = i64::try_from(len).context("Value length runs past any possible file size")?;
+    reader.seek(SeekFrom::Current(offset))?;
+    Ok(())
+}
+
+fn gguf_scalar_size(ty: u32) -> Option<u64> {
+    match ty {
+        0 | 1 | 7 => Some(1),
+        2 | 3 => Some(2),
+        4..=6 => Some(4),
+        10..=12 => Some(8),
+        _ => None,
+    }
+}
+
+fn read_gguf_uint(reader: &mut (impl Read + Seek), ty: u32) -> Result<Option<u64>> {
+    let value = match ty {
+        4 | 5 => u64::from(read_u32(reader)?),
+        10 | 11 => read_u64(reader)?,
+        _ => {
+            skip_gguf_value(reader, ty)?;
+            return Ok(None);
+        }
+    };
+    Ok(Some(value))
+}
+
+fn skip_gguf_value(reader: &mut (impl Read + Seek), ty: u32) -> Result<()> {
+    if let Some(size) = gguf_scalar_size(ty) {
+        return seek_forward(reader, size);
+    }
+    match ty {
+        8 => {
+            let len = read_u64(reader)?;
+            seek_forward(reader, len)?;
+        }
+        9 => {
+            let elem_ty = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            match gguf_scalar_size(elem_ty) {
+                Some(size) => {
+                    let bytes = size.checked_mul(len).context("GGUF array is too large")?;
+                    seek_forward(reader, bytes)?;
+                }
+                None => {
+                    for _ in 0..len {
+                        skip_gguf_value(reader, elem_ty)?;
+                    }
+                }
+            }
+        }
+        _ => bail!("Unsupported GGUF metadata type {ty}"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parses_split_gguf_names() {
+        let split = GgufSplit::parse("llama-7b-q4-00002-of-00003.gguf").unwrap();
+        assert_eq!(split.prefix, "llama-7b-q4");
+        assert_eq!((split.index, split.count), (2, 3));
+        assert_eq!(
+            split.shard_names(),
+            [
+                "llama-7b-q4-00001-of-00003.gguf",
+                "llama-7b-q4-00002-of-00003.gguf",
+                "llama-7b-q4-00003-of-00003.gguf",
+            ]
+        );
+
+        // Only the last "-of-" separates the count.
+        let split = GgufSplit::parse("mix-of-experts-00001-of-00002.gguf").unwrap();
+        assert_eq!(split.prefix, "mix-of-experts");
+        assert_eq!(split.shard_name(2), "mix-of-experts-00002-of-00002.gguf");
+    }
+
+    #[test]
+    fn rejects_other_gguf_names() {
+        for name in [
+            "model.gguf",
+            "model-00001-of-00002.bin",
+            "model-00001-of-00002.gguf.part",
+            "model-1-of-2.gguf",
+            "model-000001-of-000002.gguf",
+            "model-00000-of-00002.gguf",
+            "model-00003-of-00002.gguf",
+            "model-00001-of-00000.gguf",
+            "model-0000a-of-00002.gguf",
+            "00001-of-00002.gguf",
+        ] {
+            assert!(GgufSplit::parse(name).is_none(), "{name}");
+        }
+    }
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn key(field: u32, wire: u8, out: &mut Vec<u8>) {
+        varint((u64::from(field) << 3) | u64::from(wire), out);
+    }
+
+    fn len_field(field: u32, bytes: &[u8], out: &mut Vec<u8>) {
+        key(field, WIRE_LEN, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn entry(name: &str, value: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        len_field(ENTRY_KEY, name.as_bytes(), &mut out);
+        len_field(ENTRY_VALUE, value.as_bytes(), &mut out);
+        out
+    }
+
+    /// A ModelProto whose graph has one embedded initializer and one per
+    /// external location, with fields of every wire type to skip.
+    fn model(locations: &[&str]) -> Vec<u8> {
+        let mut embedded = Vec::new();
+        len_field(8, b"embedded", &mut embedded);
+        len_field(9, &[0u8; 64], &mut embedded);
+
+        let mut graph = Vec::new();
+        len_field(1, b"node", &mut graph);
+        len_field(GRAPH_INITIALIZER, &embedded, &mut graph);
+        for location in locations {
+            let mut tensor = Vec::new();
+            key(1, WIRE_VARINT, &mut tensor);
+            varint(300, &mut tensor);
+            len_field(TENSOR_EXTERNAL_DATA, &entry("offset", "4096"), &mut tensor);
+            len_field(TENSOR_EXTERNAL_DATA, &entry("location", location), &mut tensor);
+            key(14, WIRE_VARINT, &mut tensor);
+            varint(1, &mut tensor);
+            len_field(GRAPH_INITIALIZER, &tensor, &mut graph);
+        }
+
+        let mut model = Vec::new();
+        key(1, WIRE_VARINT, &mut model);
+        varint(8, &mut model);
+        key(2, WIRE_FIXED64, &mut model);
+        model.extend_from_slice(&[0; 8]);
+        key(3, WIRE_FIXED32, &mut model);
+        model.extend_from_slice(&[0; 4]);
+        len_field(MODEL_GRAPH, &graph, &mut model);
+        model
+    }
+
+    fn locations(bytes: Vec<u8>) -> Result<Vec<String>> {
+        let len = bytes.len() as u64;
+        let mut locations = Vec::new();
+        ProtoReader::new(Cursor::new(bytes)).walk_model(len, &mut locations)?;
+        Ok(locations)
+    }
+
+    #[test]
+    fn finds_external_data_locations() {
+        assert_eq!(
+            locations(model(&["weights.bin", "more/weights.bin"])).unwrap(),
+            ["weights.bin", "more/weights.bin"]
+        );
+        assert!(locations(model(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_messages() {
+        let mut bytes = model(&["weights.bin"]);
+        bytes.truncate(bytes.len() - 3);
+        assert!(locations(bytes).is_err());
+
+        let mut bytes = Vec::new();
+        key(MODEL_GRAPH, WIRE_LEN, &mut bytes);
+        varint(u64::MAX, &mut bytes);
+        assert!(locations(bytes).is_err());
+
+        let mut bytes = Vec::new();
+        key(MODEL_GRAPH, 3, &mut bytes);
+        assert!(locations(bytes).is_err());
+    }
+
+    #[test]
+    fn split_gguf_needs_every_shard() {
+        let dir = std::env::temp_dir().join(format!("llm-toy-shards-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let shard = |index| dir.join(format!("model-0000{index}-of-00003.gguf"));
+        fs::write(shard(1), b"GGUF").unwrap();
+        fs::write(shard(3), b"GGUF").unwrap();
+
+        // Any shard names the whole model.
+        assert_eq!(model_files(&shard(3)).unwrap(), [shard(1), shard(2), shard(3)]);
+        let result = check_model_files(&shard(1));
+        fs::write(shard(2), b"GGUF").unwrap();
+        let complete = check_model_files(&shard(2));
+        fs::remove_dir_all(&dir).unwrap();
+
+        match result {
+            Err(Error::MissingModelFiles { missing, .. }) => assert_eq!(missing, [shard(2)]),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(complete.is_ok());
+    }
+
+    #[test]
+    fn external_data_must_stay_in_the_model_directory() {
+        let path = std::env::temp_dir().join(format!("llm-toy-escape-{}.onnx", std::process::id()));
+        fs::write(&path, model(&["../secret.bin", "weights.bin"])).unwrap();
+        let result = onnx_external_data_files(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().to_string().contains("escapes the model directory"));
+
+        assert!(is_safe_relative("weights/part-1.bin"));
+        assert!(is_safe_relative("./weights.bin"));
+        assert!(!is_safe_relative(""));
+        assert!(!is_safe_relative("/etc/passwd"));
+        assert!(!is_safe_relative("a/../../b"));
+    }
+}
@@ -0,0 +1,195 @@
+//! Sharing loaded models between threads and async tasks. A `ModelPool`
+//! owns one `Model` per worker thread and feeds them from a single queue;
+//! handles are cheap to clone, and each submission returns a
+//! `PendingResponse` that can be awaited from any executor or waited on
+//! from plain threads.
+
+use crate::queue::JobQueue;
+use crate::{Error, InferenceRequest, InferenceResponse, Model, ModelConfig, Result};
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+#[derive(Clone)]
+pub struct ModelPool {
+    handle: Arc<PoolHandle>,
+}
+
+// Closes the queue once the last `ModelPool` clone is dropped, so the
+// workers finish what is queued and exit.
+struct PoolHandle {
+    queue: Arc<JobQueue<Job>>,
+    workers: usize,
+}
+
+impl Drop for PoolHandle {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+struct Job {
+    work: Work,
+    slot: Arc<Slot>,
+}
+
+enum Work {
+    Generate(String),
+    Run(Box<InferenceRequest>),
+}
+
+impl ModelPool {
+    /// Loads `workers` copies of the model, one per worker thread. Each copy
+    /// holds its own weights, so memory grows with the worker count.
+    pub fn load(config: ModelConfig, workers: usize) -> Result<Self> {
+        let models = (0..workers.max(1))
+            .map(|_| Model::load(config.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::from_models(models))
+    }
+
+    /// Starts one worker thread per model.
+    pub fn from_models(models: Vec<Model>) -> Self {
+        let queue = Arc::new(JobQueue::new());
+        let workers = models.len();
+        for (index, model) in models.into_iter().enumerate() {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("llm-toy-worker-{index}"))
+                .spawn(move || worker(&queue, model))
+                .expect("failed to spawn a model worker thread");
+        }
+        Self {
+            handle: Arc::new(PoolHandle { queue, workers }),
+        }
+    }
+
+    /// Rejects submissions with `Error::QueueFull` while `capacity` requests
+    /// are already waiting for a worker.
+    pub fn with_queue_capacity(self, capacity: usize) -> Self {
+        self.handle.queue.set_capacity(capacity);
+        self
+    }
+
+    pub fn workers(&self) -> usize {
+        self.handle.workers
+    }
+
+    /// Requests waiting for a worker, not counting those being generated.
+    pub fn queued(&self) -> usize {
+        self.handle.queue.len()
+    }
+
+    /// Generates a reply to `prompt` with `Model::generate`.
+    pub fn generate(&self, prompt: impl Into<String>) -> PendingResponse {
+        self.submit(Work::Generate(prompt.into()))
+    }
+
+    /// Runs `request` on the backend as is.
+    pub fn run(&self, request: InferenceRequest) -> PendingResponse {
+        self.submit(Work::Run(Box::new(request)))
+    }
+
+    /// Stops accepting requests. Queued requests still run; later
+    /// submissions fail with `Error::PoolClosed`.
+    pub fn shutdown(&self) {
+        self.handle.queue.close();
+    }
+
+    fn submit(&self, work: Work) -> PendingResponse {
+        self.handle.queue.submit(|slot| Job { work, slot })
+    }
+}
+
+fn worker(queue: &JobQueue<Job>, mut model: Model) {
+    while let Some(job) = queue.next() {
+        let result = catch_unwind(AssertUnwindSafe(|| match &job.work {
+            Work::Generate(prompt) => model.generate(prompt),
+            Work::Run(request) => model.backend_mut().run(request),
+        }))
+        .unwrap_or_else(|_| {
+            Err(Error::BackendPanicked {
+                backend: model.backend().name().to_string(),
+            })
+        });
+        job.slot.fill(result);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Slot {
+    state: Mutex<SlotState>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct SlotState {
+    result: Option<Result<InferenceResponse>>,
+    waker: Option<Waker>,
+}
+
+impl Slot {
+    fn lock(&self) -> MutexGuard<'_, SlotState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn fill(&self, result: Result<InferenceResponse>) {
+        let waker = {
+            let mut state = self.lock();
+            state.result = Some(result);
+            state.waker.take()
+        };
+        self.done.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The result of a pool submission. Await it, or call `wait` outside async
+/// code.
+pub struct PendingResponse {
+    slot: Arc<Slot>,
+}
+
+impl PendingResponse {
+    /// A response that `slot` will be filled with.
+    pub(crate) fn new(slot: Arc<Slot>) -> Self {
+        Self { slot }
+    }
+
+    pub(crate) fn ready(result: Result<InferenceResponse>) -> Self {
+        let slot = Arc::new(Slot::default());
+        slot.fill(result);
+        Self { slot }
+    }
+
+    /// Blocks the current thread until the response is ready.
+    pub fn wait(self) -> Result<InferenceResponse> {
+        let mut state = self.slot.lock();
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = self.slot.done.wait(state).unwrap_or_else(|err| err.into_inner());
+        }
+    }
+}
+
+impl Future for PendingResponse {
+    type Output = Result<InferenceResponse>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.lock();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
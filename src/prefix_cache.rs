@@ -0,0 +1,221 @@
+//! Reusing attention state across requests. Chat turns and batch jobs often
+//! start with the same tokens (a system prompt, the memory block), so the
+//! KV caches of finished prompts are kept, keyed by their token ids, and a
+//! new request starts from the longest cached prefix of its prompt instead
+//! of running the prefill over it again.
+
+use crate::KvCache;
+
+pub const DEFAULT_PREFIX_CACHE_MB: usize = 256;
+
+/// Cached prefixes, evicted least recently used first once their tensors
+/// exceed the memory cap.
+#[derive(Debug, Default)]
+pub struct PrefixCache {
+    capacity_bytes: usize,
+    size_bytes: usize,
+    entries: Vec<Entry>,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    ids: Vec<i64>,
+    cache: KvCache,
+    last_used: u64,
+}
+
+impl PrefixCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        Self {
+            capacity_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// The cache `--prefix-cache-mb` asks for: `None` when it is 0, and
+    /// `DEFAULT_PREFIX_CACHE_MB` when unset.
+    pub fn with_limit_mb(limit_mb: Option<usize>) -> Option<Self> {
+        match limit_mb.unwrap_or(DEFAULT_PREFIX_CACHE_MB) {
+            0 => None,
+            limit_mb => Some(Self::new(limit_mb << 20)),
+        }
+    }
+
+    pub fn capacity_bytes(&self) -> usize {
+        self.capacity_bytes
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.size_bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The cache for the longest prefix of `ids` that any entry shares. The
+    /// last token is never covered: it has to be fed to get the logits the
+    /// next token is sampled from.
+    pub fn lookup(&mut self, ids: &[i64]) -> Option<KvCache> {
+        let limit = ids.len().saturating_sub(1);
+        let (index, len) = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (index, common_prefix(&entry.ids, ids).min(limit)))
+            .max_by_key(|&(_, len)| len)
+            .filter(|&(_, len)| len > 0)?;
+        self.clock += 1;
+        let entry = &mut self.entries[index];
+        entry.last_used = self.clock;
+        Some(entry.cache.prefix(len))
+    }
+
+    /// Stores `cache`, which covers the first `cache.len()` tokens of `ids`.
+    /// Entries it extends are replaced, and nothing is stored when an entry
+    /// already covers it.
+    pub fn insert(&mut self, ids: &[i64], cache: &KvCache) {
+        let Some(ids) = ids.get(..cache.len()).filter(|ids| !ids.is_empty()) else {
+            return;
+        };
+        self.clock += 1;
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.ids.starts_with(ids)) {
+            entry.last_used = self.clock;
+            return;
+        }
+        let size = cache.size_bytes();
+        if size > self.capacity_bytes {
+            return;
+        }
+        self.entries.retain(|entry| !ids.starts_with(&entry.ids));
+        self.entries.push(Entry {
+            ids: ids.to_vec(),
+            cache: cache.clone(),
+            last_used: self.clock,
+        });
+        self.size_bytes = self.entries.iter().map(|entry| entry.cache.size_bytes()).sum();
+        while self.size_bytes > self.capacity_bytes {
+            let oldest = self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(index, _)| index)
+                .expect("a cache over its cap has entries");
+            let entry = self.entries.swap_remove(oldest);
+            self.size_bytes -= entry.cache.size_bytes();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size_bytes = 0;
+    }
+}
+
+fn common_prefix(a: &[i64], b: &[i64]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequence::KvTensor;
+
+    /// A two-head cache for `len` tokens whose values are their positions
+    /// (plus 100 for the second head), 8 bytes per token.
+    fn cache(len: usize) -> KvCache {
+        let head = |offset: f32| (0..len).map(move |position| position as f32 + offset);
+        KvCache {
+            len,
+            tensors: vec![KvTensor {
+                heads: 2,
+                head_dim: 1,
+                data: head(0.0).chain(head(100.0)).collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn restores_the_longest_shared_prefix() {
+        let mut prefix_cache = PrefixCache::new(1 << 20);
+        prefix_cache.insert(&[1, 2, 9, 9], &cache(4));
+        prefix_cache.insert(&[1, 2, 3, 4, 5, 6], &cache(5));
+
+        let restored = prefix_cache.lookup(&[1, 2, 3, 4, 7]).unwrap();
+        assert_eq!(restored.len(), 4);
+        assert_eq!(restored.tensors[0].data, [0.0, 1.0, 2.0, 3.0, 100.0, 101.0, 102.0, 103.0]);
+
+        assert_eq!(prefix_cache.lookup(&[1, 2, 9, 9, 9]).unwrap().len(), 4);
+        assert!(prefix_cache.lookup(&[7, 1, 2]).is_none());
+    }
+
+    #[test]
+    fn leaves_the_last_prompt_token_to_feed() {
+        let mut prefix_cache = PrefixCache::new(1 << 20);
+        prefix_cache.insert(&[1, 2, 3, 4], &cache(4));
+        assert_eq!(prefix_cache.lookup(&[1, 2, 3, 4]).unwrap().len(), 3);
+        assert_eq!(prefix_cache.lookup(&[1, 2]).unwrap().len(), 1);
+        assert!(prefix_cache.lookup(&[1]).is_none());
+    }
+
+    #[test]
+    fn only_stores_tokens_the_cache_covers() {
+        let mut prefix_cache = PrefixCache::new(1 << 20);
+        // The last sampled token has no attention state yet.
+        prefix_cache.insert(&[1, 2, 3, 4], &cache(3));
+        assert_eq!(prefix_cache.lookup(&[1, 2, 3, 4, 5]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn longer_entries_replace_their_prefixes() {
+        let mut prefix_cache = PrefixCache::new(1 << 20);
+        prefix_cache.insert(&[1, 2], &cache(2));
+        prefix_cache.insert(&[1, 2, 3, 4], &cache(4));
+        assert_eq!(prefix_cache.len(), 1);
+        assert_eq!(prefix_cache.size_bytes(), 32);
+
+        // Already covered by the longer entry.
+        prefix_cache.insert(&[1, 2, 3], &cache(3));
+        assert_eq!(prefix_cache.len(), 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries_over_the_cap() {
+        // Room for 6 tokens.
+        let mut prefix_cache = PrefixCache::new(48);
+        prefix_cache.insert(&[1, 1], &cache(2));
+        prefix_cache.insert(&[2, 2], &cache(2));
+        prefix_cache.insert(&[3, 3], &cache(2));
+        assert_eq!(prefix_cache.size_bytes(), 48);
+
+        // Using [1, 1] makes [2, 2] the oldest.
+        assert!(prefix_cache.lookup(&[1, 1, 0]).is_some());
+        prefix_cache.insert(&[4, 4], &cache(2));
+        assert!(prefix_cache.lookup(&[2, 2, 0]).is_none());
+        assert!(prefix_cache.lookup(&[1, 1, 0]).is_some());
+        assert!(prefix_cache.lookup(&[3, 3, 0]).is_some());
+        assert!(prefix_cache.lookup(&[4, 4, 0]).is_some());
+        assert_eq!(prefix_cache.size_bytes(), 48);
+
+        // One entry larger than the cap evicts nothing and is not stored.
+        prefix_cache.insert(&[5; 7], &cache(7));
+        assert_eq!(prefix_cache.len(), 3);
+        assert!(prefix_cache.lookup(&[5; 8]).is_none());
+    }
+
+    #[test]
+    fn zero_megabytes_disables_the_cache() {
+        assert!(PrefixCache::with_limit_mb(Some(0)).is_none());
+        assert_eq!(PrefixCache::with_limit_mb(Some(1)).unwrap().capacity_bytes(), 1 << 20);
+        assert_eq!(
+            PrefixCache::with_limit_mb(None).unwrap().capacity_bytes(),
+            DEFAULT_PREFIX_CACHE_MB << 20
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! The bounded job queue behind `ModelPool` and `Scheduler`: submissions
+//! get a `PendingResponse` or fail with `Error::QueueFull`/`PoolClosed`, and
+//! workers block until there is work or the queue is closed and drained.
+
+use crate::pool::{PendingResponse, Slot};
+use crate::Error;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+pub(crate) struct JobQueue<J> {
+    state: Mutex<State<J>>,
+    ready: Condvar,
+}
+
+struct State<J> {
+    jobs: VecDeque<J>,
+    capacity: Option<usize>,
+    closed: bool,
+}
+
+impl<J> JobQueue<J> {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                capacity: None,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<J>> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Rejects submissions while `capacity` jobs are waiting.
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.lock().capacity = Some(capacity);
+    }
+
+    /// Jobs waiting for a worker.
+    pub(crate) fn len(&self) -> usize {
+        self.lock().jobs.len()
+    }
+
+    /// Refuses new jobs; workers still drain the ones queued.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+
+    /// Queues the job `make` builds around the slot its result goes to.
+    pub(crate) fn submit(&self, make: impl FnOnce(Arc<Slot>) -> J) -> PendingResponse {
+        let mut state = self.lock();
+        if state.closed {
+            return PendingResponse::ready(Err(Error::PoolClosed));
+        }
+        if let Some(capacity) = state.capacity.filter(|&capacity| state.jobs.len() >= capacity) {
+            return PendingResponse::ready(Err(Error::QueueFull { capacity }));
+        }
+        let slot = Arc::new(Slot::default());
+        state.jobs.push_back(make(Arc::clone(&slot)));
+        drop(state);
+        self.ready.notify_one();
+        PendingResponse::new(slot)
+    }
+
+    /// Blocks until a job is queued; `None` once closed and drained.
+    pub(crate) fn next(&self) -> Option<J> {
+        self.take(true, 1, |_, _| true)?.pop()
+    }
+
+    /// Takes up to `room` jobs from the front, in order, stopping at the
+    /// first that `fits` rejects given the ones already taken. With `block`
+    /// it waits for a job first and returns `None` once closed and drained;
+    /// without it, it returns what is there, possibly nothing.
+    pub(crate) fn take(
+        &self,
+        block: bool,
+        room: usize,
+        fits: impl Fn(&J, &[J]) -> bool,
+    ) -> Option<Vec<J>> {
+        let mut state = self.lock();
+        while block && state.jobs.is_empty() {
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap_or_else(|err| err.into_inner());
+        }
+        let mut taken = Vec::new();
+        while taken.len() < room {
+            match state.jobs.front() {
+                Some(job) if fits(job, &taken) => taken.extend(state.jobs.pop_front()),
+                _ => break,
+            }
+        }
+        Some(taken)
+    }
+}
@@ -0,0 +1,429 @@
+use crate::embedding::{text_hash, Embedder, Pooling, VectorIndex};
+use crate::memory::{unix_timestamp, TokenCounter};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DOCUMENT_EXTENSIONS: [&str; 4] = ["txt", "md", "markdown", "rst"];
+pub const DEFAULT_CHUNK_SIZE: usize = 1200;
+
+const MANIFEST_FILE: &str = "index.json";
+const VECTORS_FILE: &str = "vectors.bin";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentChunk {
+    pub source: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+}
+
+impl DocumentChunk {
+    pub fn citation(&self) -> String {
+        if self.start_line == self.end_line {
+            format!("{}:{}", self.source, self.start_line)
+        } else {
+            format!("{}:{}-{}", self.source, self.start_line, self.end_line)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexManifest {
+    pub root: String,
+    pub embedding_model: String,
+    pub embedding_tokenizer: String,
+    #[serde(default)]
+    pub pooling: Pooling,
+    pub chunk_size: usize,
+    pub created_at: u64,
+    pub chunks: Vec<DocumentChunk>,
+}
+
+/// A directory holding `index.json` (chunk text and metadata) and
+/// `vectors.bin` (one vector per chunk, keyed by chunk position).
+pub struct DocumentIndex {
+    pub manifest: IndexManifest,
+    pub vectors: VectorIndex,
+}
+
+impl DocumentIndex {
+    pub fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST_FILE).exists()
+    }
+
+    pub fn load(dir: &Path) -> Result<Self> {
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let data = fs::read_to_string(&manifest_path).with_context(|| {
+            format!("Failed to read document index {}", manifest_path.display())
+        })?;
+        let manifest: IndexManifest = serde_json::from_str(&data)
+            .with_context(|| format!("Document index {} is corrupt", manifest_path.display()))?;
+        let vectors = VectorIndex::load(&dir.join(VECTORS_FILE))?
+            .with_context(|| format!("Document index {} has no vectors", dir.display()))?;
+        if vectors.entries.len() != manifest.chunks.len() {
+            bail!(
+                "Document index {} is inconsistent: {} chunks but {} vectors",
+                dir.display(),
+                manifest.chunks.len(),
+                vectors.entries.len()
+            );
+        }
+        Ok(Self { manifest, vectors })
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        self.vectors.save(&dir.join(VECTORS_FILE))?;
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let tmp_path = dir.join(format!("{MANIFEST_FILE}.tmp"));
+        fs::write(&tmp_path, serde_json::to_string_pretty(&self.manifest)?)?;
+        fs::rename(&tmp_path, &manifest_path).with_context(|| {
+            format!("Failed to write document index {}", manifest_path.display())
+        })?;
+        Ok(())
+    }
+
+    /// Chunks and embeds every document under `manifest.root`. Chunks whose
+    /// text is unchanged since `previous` reuse their stored vectors.
+    pub fn build(
+        mut manifest: IndexManifest,
+        previous: Option<&DocumentIndex>,
+        embedder: &mut dyn Embedder,
+    ) -> Result<Self> {
+        let root = PathBuf::from(&manifest.root);
+        let mut chunks = Vec::new();
+        for path in collect_documents(&root)? {
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let source = path
+                .strip_prefix(&root)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .replace('\\', "/");
+            for (start_line, end_line, text) in chunk_text(&text, manifest.chunk_size) {
+                chunks.push(DocumentChunk {
+                    source: source.clone(),
+                    start_line,
+                    end_line,
+                    text,
+                });
+            }
+        }
+
+        let reusable: HashMap<u64, &[f32]> = previous
+            .filter(|index| {
+                index.vectors.model == manifest.embedding_model
+                    && index.manifest.pooling == manifest.pooling
+            })
+            .map(|index| {
+                index
+                    .vectors
+                    .entries
+                    .iter()
+                    .map(|entry| (entry.text_hash, entry.vector.as_slice()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut vectors = VectorIndex::new(manifest.embedding_model.clone());
+        let mut pending = Vec::new();
+        for (id, chunk) in chunks.iter().enumerate() {
+            let hash = text_hash(&chunk.text);
+            match reusable.get(&hash) {
+                Some(vector) => vectors.insert(id as u64, hash, vector.to_vec())?,
+                None => pending.push((id, hash)),
+            }
+        }
+        for batch in pending.chunks(32) {
+            let texts: Vec<&str> = batch
+                .iter()
+                .map(|(id, _)| chunks[*id].text.as_str())
+                .collect();
+            let embedded = embedder.embed(&texts)?;
+            for ((id, hash), vector) in batch.iter().zip(embedded) {
+                vectors.insert(*id as u64, *hash, vector)?;
+            }
+        }
+        vectors.entries.sort_by_key(|entry| entry.id);
+
+        manifest.chunks = chunks;
+        manifest.created_at = unix_timestamp();
+        Ok(Self { manifest, vectors })
+    }
+
+    /// Returns up to `k` chunks most similar to `query`, best first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(&DocumentChunk, f32)> {
+        self.vectors
+            .search(query, k)
+            .into_iter()
+            .filter_map(|(id, score)| {
+                self.manifest
+                    .chunks
+                    .get(id as usize)
+                    .map(|chunk| (chunk, score))
+            })
+            .collect()
+    }
+}
+
+/// Text and markdown files under `root`, sorted, skipping hidden entries.
+pub fn collect_documents(root: &Path) -> Result<Vec<PathBuf>> {
+    if root.is_file() {
+        return Ok(vec![root.to_path_buf()]);
+    }
+    let mut documents = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with('.'));
+            if hidden {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path);
+            } else if path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| DOCUMENT_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+            {
+                documents.push(path);
+            }
+        }
+    }
+    documents.sort();
+    Ok(documents)
+}
+
+/// Splits text into chunks of at most `chunk_size` bytes, packing whole
+/// paragraphs where possible and starting a new chunk at markdown headings.
+/// A line longer than `chunk_size` is split, at whitespace where it can be.
+/// Returns `(start_line, end_line, text)` with 1-based inclusive lines.
+pub fn chunk_text(text: &str, chunk_size: usize) -> Vec<(usize, usize, String)> {
+    let chunk_size = chunk_size.max(1);
+    let mut chunks = Vec::new();
+    let mut current: Option<(usize, usize, String)> = None;
+
+    let mut flush = |current: &mut Option<(usize, usize, String)>| {
+        if let Some((start, end, text)) = current.take() {
+            let text = text.trim().to_string();
+            if !text.is_empty() {
+                chunks.push((start, end, text));
+            }
+        }
+    };
+
+    let pieces = text.lines().enumerate().flat_map(|(index, line)| {
+        split_line(line, chunk_size)
+            .into_iter()
+            .enumerate()
+            .map(move |(piece, text)| (index + 1, piece == 0, text))
+    });
+    for (number, first_piece, line) in pieces {
+        let is_heading = first_piece && line.starts_with('#');
+        let is_blank = line.trim().is_empty();
+        if let Some((_, _, chunk)) = current.as_ref() {
+            let too_long = chunk.len() + line.len() > chunk_size;
+            if is_heading || too_long {
+                flush(&mut current);
+            }
+        }
+        if is_blank {
+            if let Some((_, _, chunk)) = current.as_mut() {
+                if !chunk.ends_with("\n\n") {
+                    chunk.push('\n');
+                }
+            }
+            continue;
+        }
+        let (_, end, chunk) = current.get_or_insert_with(|| (number, number, String::new()));
+        chunk.push_str(line);
+        chunk.push('\n');
+        *end = number;
+    }
+    flush(&mut current);
+    chunks
+}
+
+/// Splits `line` into pieces of at most `max` bytes, breaking after the last
+/// whitespace in a piece when there is one.
+fn split_line(line: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = line;
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        if let Some(space) = rest[..end].rfind(char::is_whitespace).filter(|&pos| pos > 0) {
+            end = space;
+        }
+        pieces.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    if !rest.is_empty() || pieces.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
+/// Builds a prompt asking the model to answer from the numbered sources
+/// only. Sources are added in order until `max_tokens` would be exceeded.
+pub fn grounded_prompt(
+    question: &str,
+    hits: &[(&DocumentChunk, f32)],
+    max_tokens: usize,
+    counter: &dyn TokenCounter,
+) -> Result<(String, usize)> {
+    let header = "Answer the question using only the numbered sources below. \
+        Cite the sources you use as [1], [2], and so on. \
+        If the sources do not contain the answer, say so.\n\n";
+    let heading = "### Sources\n";
+    let footer = format!("### Question\n{question}");
+    let mut used = counter.count_tokens(header)?
+        + counter.count_tokens(heading)?
+        + counter.count_tokens(&footer)?;
+
+    let mut sources = String::new();
+    let mut included = 0;
+    for (chunk, _) in hits {
+        let section = format!(
+            "[{}] {}\n{}\n\n",
+            included + 1,
+            chunk.citation(),
+            chunk.text
+        );
+        let tokens = counter.count_tokens(&section)?;
+        if used + tokens > max_tokens {
+            break;
+        }
+        used += tokens;
+        sources.push_str(&section);
+        included += 1;
+    }
+
+    let prompt = if included == 0 {
+        format!("{header}{heading}(none)\n\n{footer}")
+    } else {
+        format!("{header}{heading}{sources}{footer}")
+    };
+    Ok((prompt, included))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Words;
+
+    impl TokenCounter for Words {
+        fn count_tokens(&self, text: &str) -> Result<usize> {
+            Ok(text.split_whitespace().count())
+        }
+    }
+
+    fn chunk(text: &str) -> DocumentChunk {
+        DocumentChunk {
+            source: "notes.md".to_string(),
+            start_line: 1,
+            end_line: 2,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn packs_paragraphs_that_fit() {
+        let chunks = chunk_text("one\n\ntwo\nthree\n", 100);
+        assert_eq!(chunks, vec![(1, 4, "one\n\ntwo\nthree".to_string())]);
+    }
+
+    #[test]
+    fn headings_start_a_new_chunk() {
+        let chunks = chunk_text("intro\n# Usage\nrun it\n## Flags\n", 100);
+        assert_eq!(
+            chunks,
+            vec![
+                (1, 1, "intro".to_string()),
+                (2, 3, "# Usage\nrun it".to_string()),
+                (4, 4, "## Flags".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_at_paragraphs_when_too_long() {
+        let text = "aaaa aaaa\n\nbbbb bbbb\n\ncccc\n";
+        let chunks = chunk_text(text, 14);
+        assert_eq!(
+            chunks,
+            vec![
+                (1, 1, "aaaa aaaa".to_string()),
+                (3, 3, "bbbb bbbb".to_string()),
+                (5, 5, "cccc".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_split_at_whitespace() {
+        let line = "word ".repeat(50);
+        let chunks = chunk_text(&format!("{line}\nnext\n"), 32);
+        assert!(chunks.len() > 1);
+        for (start, _, text) in &chunks[..chunks.len() - 1] {
+            assert_eq!(*start, 1);
+            assert!(text.len() <= 32, "{} bytes: {text:?}", text.len());
+            assert!(text.split_whitespace().all(|word| word == "word"));
+        }
+        let words: usize = chunks
+            .iter()
+            .map(|(_, _, text)| text.split_whitespace().filter(|word| *word == "word").count())
+            .sum();
+        assert_eq!(words, 50);
+    }
+
+    #[test]
+    fn long_lines_without_whitespace_split_on_char_boundaries() {
+        let line = "é".repeat(20);
+        let chunks = chunk_text(&line, 7);
+        assert!(chunks.iter().all(|(_, _, text)| text.len() <= 7));
+        let joined: String = chunks.into_iter().map(|(_, _, text)| text).collect();
+        assert_eq!(joined, line);
+    }
+
+    #[test]
+    fn grounded_prompt_stops_at_the_token_budget() {
+        let (first, second) = (chunk("alpha beta gamma"), chunk("delta epsilon zeta"));
+        let hits = [(&first, 0.9), (&second, 0.8)];
+        let (all, included) = grounded_prompt("why?", &hits, 1000, &Words).unwrap();
+        assert_eq!(included, 2);
+        let full = Words.count_tokens(&all).unwrap();
+
+        let (prompt, included) = grounded_prompt("why?", &hits, full - 1, &Words).unwrap();
+        assert_eq!(included, 1);
+        assert!(Words.count_tokens(&prompt).unwrap() < full);
+        let (exact, included) = grounded_prompt("why?", &hits, full, &Words).unwrap();
+        assert_eq!((exact, included), (all, 2));
+        assert!(prompt.contains("[1] notes.md:1-2\nalpha beta gamma"));
+        assert!(!prompt.contains("delta"));
+    }
+
+    #[test]
+    fn grounded_prompt_without_room_has_no_sources() {
+        let first = chunk("alpha beta gamma");
+        let (prompt, included) = grounded_prompt("why?", &[(&first, 0.9)], 0, &Words).unwrap();
+        assert_eq!(included, 0);
+        assert!(prompt.contains("### Sources\n(none)"));
+        assert!(prompt.ends_with("### Question\nwhy?"));
+    }
+}
@@ -0,0 +1,205 @@
+//! Named backends that `load_backend` can construct. The built-in backends
+//! are registered on first use; applications can add their own with
+//! `register_backend`.
+
+use crate::{AmdXdnaBackend, CpuBackend, Error, NpuBackend, PlaceholderNpuBackend};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, OnceLock, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    /// Emits tokens while generating.
+    Streaming,
+    /// Reuses attention state between decode steps.
+    KvCache,
+    Embeddings,
+    /// Runs several requests per forward pass in `run_batch`.
+    Batching,
+    /// Implements the step-wise methods `scheduler::Scheduler` uses.
+    ContinuousBatching,
+    /// Implements `token_logprobs`.
+    Scoring,
+    /// Needs a tokenizer unless the request carries `input_ids`.
+    TokenizerRequired,
+    /// Echoes the prompt instead of running the model; `auto` skips it.
+    Placeholder,
+}
+
+impl Capability {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Streaming => "streaming",
+            Self::KvCache => "kv-cache",
+            Self::Embeddings => "embeddings",
+            Self::Batching => "batching",
+            Self::ContinuousBatching => "continuous-batching",
+            Self::Scoring => "scoring",
+            Self::TokenizerRequired => "tokenizer-required",
+            Self::Placeholder => "placeholder",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+type BackendFactory = Arc<dyn Fn() -> Box<dyn NpuBackend> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct BackendEntry {
+    pub name: String,
+    pub description: String,
+    pub capabilities: Vec<Capability>,
+    factory: BackendFactory,
+}
+
+impl BackendEntry {
+    pub fn new(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        capabilities: &[Capability],
+        factory: impl Fn() -> Box<dyn NpuBackend> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            capabilities: capabilities.to_vec(),
+            factory: Arc::new(factory),
+        }
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    pub fn create(&self) -> Box<dyn NpuBackend> {
+        (self.factory)()
+    }
+}
+
+impl fmt::Debug for BackendEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackendEntry")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("capabilities", &self.capabilities)
+            .finish_non_exhaustive()
+    }
+}
+
+static REGISTRY: OnceLock<RwLock<Vec<BackendEntry>>> = OnceLock::new();
+
+fn registry() -> &'static RwLock<Vec<BackendEntry>> {
+    REGISTRY.get_or_init(|| RwLock::new(builtin_backends()))
+}
+
+fn builtin_backends() -> Vec<BackendEntry> {
+    let mut entries = vec![BackendEntry::new(
+        "cpu",
+        "ONNX Runtime on the CPU (requires the cpu feature)",
+        &[
+            Capability::KvCache,
+            Capability::Batching,
+            Capability::ContinuousBatching,
+            Capability::Scoring,
+            Capability::TokenizerRequired,
+        ],
+        || Box::new(CpuBackend::new()),
+    )];
+    #[cfg(all(windows, feature = "ryzen-ai"))]
+    entries.push(BackendEntry::new(
+        "ryzen-ai",
+        "ONNX Runtime with the Ryzen AI (Vitis AI) execution provider",
+        &[],
+        || Box::new(crate::RyzenAiBackend::new()),
+    ));
+    entries.push(BackendEntry::new(
+        "amd-xdna",
+        "AMD XDNA NPU (placeholder: echoes the prompt)",
+        &[Capability::Placeholder],
+        || Box::new(AmdXdnaBackend::new()),
+    ));
+    entries.push(BackendEntry::new(
+        "placeholder",
+        "Echoes the prompt; for trying the CLI without a model runtime",
+        &[Capability::Placeholder],
+        || Box::new(PlaceholderNpuBackend::new("placeholder")),
+    ));
+    entries
+}
+
+/// Adds a backend under `entry.name`. Names are unique, so registering a
+/// built-in name again is an error.
+pub fn register_backend(entry: BackendEntry) -> Result<()> {
+    let mut entries = registry().write().unwrap_or_else(|err| err.into_inner());
+    if entries.iter().any(|existing| existing.name == entry.name) {
+        bail!("A backend named '{}' is already registered", entry.name);
+    }
+    entries.push(entry);
+    Ok(())
+}
+
+/// Every registered backend, built-ins first.
+pub fn backends() -> Vec<BackendEntry> {
+    registry()
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+pub fn find_backend(name: &str) -> crate::Result<BackendEntry> {
+    let entries = backends();
+    if let Some(entry) = entries.iter().find(|entry| entry.name == name) {
+        return Ok(entry.clone());
+    }
+    if name == "ryzen-ai" {
+        return Err(Error::BackendUnavailable {
+            backend: name.to_string(),
+            reasons: vec![
+                "it is not built in; it needs Windows and the ryzen-ai feature".to_string(),
+            ],
+        });
+    }
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    Err(Error::UnknownBackend {
+        name: name.to_string(),
+        suggestions: suggestions(name, &names)
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        available: names.into_iter().map(str::to_string).collect(),
+    })
+}
+
+// Names within a small edit distance of `name`, or sharing its prefix.
+fn suggestions<'a>(name: &str, names: &[&'a str]) -> Vec<&'a str> {
+    let limit = (name.len() / 3).max(2);
+    names
+        .iter()
+        .copied()
+        .filter(|candidate| {
+            edit_distance(name, candidate) <= limit
+                || (name.len() >= 2 && candidate.starts_with(name))
+        })
+        .collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
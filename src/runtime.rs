@@ -0,0 +1,308 @@
+//! ONNX Runtime loading shared by the ORT-based backends and the embedder.
+//! The runtime is a process-wide dynamic library, so it is loaded once and
+//! every later caller gets the same library back. A load that fails is not
+//! remembered; the next caller tries again.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+pub const CPU_ORT_DLL: &str = "CPU_ORT_DLL";
+pub const RYZEN_AI_ORT_DLL: &str = "RYZEN_AI_ORT_DLL";
+/// ort's own override, checked when the backend-specific variable is unset.
+pub const ORT_DYLIB_PATH: &str = "ORT_DYLIB_PATH";
+
+#[cfg(windows)]
+pub const DEFAULT_LIBRARY: &str = "onnxruntime.dll";
+#[cfg(target_os = "macos")]
+pub const DEFAULT_LIBRARY: &str = "libonnxruntime.dylib";
+#[cfg(not(any(windows, target_os = "macos")))]
+pub const DEFAULT_LIBRARY: &str = "libonnxruntime.so";
+
+// Where the loader looks for a bare library name, besides the executable's
+// directory. It also consults caches (ld.so.cache) that are not read here.
+#[cfg(windows)]
+const SEARCH_PATH_VAR: &str = "PATH";
+#[cfg(target_os = "macos")]
+const SEARCH_PATH_VAR: &str = "DYLD_LIBRARY_PATH";
+#[cfg(not(any(windows, target_os = "macos")))]
+const SEARCH_PATH_VAR: &str = "LD_LIBRARY_PATH";
+
+#[cfg(windows)]
+const SYSTEM_DIRS: &[&str] = &[];
+#[cfg(target_os = "macos")]
+const SYSTEM_DIRS: &[&str] = &["/usr/local/lib", "/opt/homebrew/lib", "/usr/lib"];
+#[cfg(not(any(windows, target_os = "macos")))]
+const SYSTEM_DIRS: &[&str] = &[
+    "/usr/local/lib",
+    "/usr/lib",
+    "/usr/lib64",
+    "/lib",
+    "/usr/lib/x86_64-linux-gnu",
+    "/usr/lib/aarch64-linux-gnu",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RuntimeLibrary {
+    pub path: PathBuf,
+    /// Environment variable the path came from; `None` means the system
+    /// library search path.
+    pub source: Option<String>,
+}
+
+impl RuntimeLibrary {
+    pub fn describe(&self) -> String {
+        match &self.source {
+            Some(var) => format!("{} (from {var})", self.path.display()),
+            None => format!("{} (system library search path)", self.path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuntimeInfo {
+    pub library: RuntimeLibrary,
+    /// "unknown" when the build info does not name a release.
+    pub version: String,
+    pub build_info: String,
+    /// Execution providers compiled into the loaded library.
+    pub execution_providers: Vec<String>,
+}
+
+/// Resolves the library `env_var` (or `ORT_DYLIB_PATH`) points to. A
+/// directory is searched for the platform library name; a path that does
+/// not exist is an error rather than a failure deep inside the loader.
+pub fn resolve_library(env_var: &str) -> Result<RuntimeLibrary> {
+    let configured = [env_var, ORT_DYLIB_PATH].into_iter().find_map(|var| {
+        std::env::var_os(var)
+            .filter(|value| !value.is_empty())
+            .map(|value| (var, PathBuf::from(value)))
+    });
+    let Some((var, mut path)) = configured else {
+        return Ok(RuntimeLibrary {
+            path: PathBuf::from(DEFAULT_LIBRARY),
+            source: None,
+        });
+    };
+    if path.is_dir() {
+        path = path.join(DEFAULT_LIBRARY);
+    }
+    if !path.is_file() {
+        bail!(
+            "{var} points to {}, which does not exist. Set it to the ONNX Runtime library \
+             ({DEFAULT_LIBRARY}) or the directory containing it",
+            path.display()
+        );
+    }
+    Ok(RuntimeLibrary {
+        path,
+        source: Some(var.to_string()),
+    })
+}
+
+/// The file `library` would be loaded from, found without loading it. A
+/// configured path is used as is; the bare library name is looked up next
+/// to the executable, in the library search path variable and the usual
+/// system directories.
+pub fn find_library(library: &RuntimeLibrary) -> Option<PathBuf> {
+    if library.source.is_some() {
+        return library.path.is_file().then(|| library.path.clone());
+    }
+    let exe_dir = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf));
+    let search_path = std::env::var_os(SEARCH_PATH_VAR)
+        .map(|paths| std::env::split_paths(&paths).collect::<Vec<_>>())
+        .unwrap_or_default();
+    exe_dir
+        .into_iter()
+        .chain(search_path)
+        .chain(SYSTEM_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(&library.path))
+        .find(|path| path.is_file())
+}
+
+/// Why `find_library` came up empty, for availability reports.
+pub fn not_found_reason(library: &RuntimeLibrary, env_var: &str) -> String {
+    format!(
+        "{} was not found next to the executable, in {SEARCH_PATH_VAR} or in the system \
+         library directories; set {env_var} to its path",
+        library.path.display()
+    )
+}
+
+// The release named in ONNX Runtime's build info, e.g. "1.23.0" from
+// "git-branch=rel-1.23.0", or else the first dotted version number in it.
+#[cfg_attr(not(any(feature = "cpu", feature = "ryzen-ai")), allow(dead_code))]
+fn parse_version(build_info: &str) -> String {
+    let is_version = |field: &str| {
+        let parts: Vec<&str> = field.split('.').collect();
+        parts.len() >= 2 && parts.iter().all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let fields = || {
+        build_info
+            .split(|c: char| c == ',' || c == '=' || c.is_whitespace())
+            .filter(|field| !field.is_empty())
+    };
+    fields()
+        .find_map(|field| field.strip_prefix("rel-").filter(|version| is_version(version)))
+        .or_else(|| fields().find(|field| is_version(field)))
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+#[cfg(any(feature = "cpu", feature = "ryzen-ai"))]
+mod ort_runtime {
+    use super::{parse_version, resolve_library, RuntimeInfo, RuntimeLibrary, DEFAULT_LIBRARY};
+    use crate::{SessionOptions, EXECUTION_PROVIDERS};
+    use anyhow::{bail, Context, Result};
+    use ort::ep::{self, ExecutionProvider, ExecutionProviderDispatch};
+    use std::sync::{Mutex, OnceLock};
+
+    static RUNTIME: OnceLock<RuntimeLibrary> = OnceLock::new();
+    // Serializes loading, so two threads never load different libraries.
+    static LOADING: Mutex<()> = Mutex::new(());
+
+    /// Loads ONNX Runtime on first use. Later calls return the library that
+    /// was loaded, and fail if `env_var` explicitly asks for a different one.
+    pub fn init(env_var: &str) -> Result<&'static RuntimeLibrary> {
+        let loaded = match RUNTIME.get() {
+            Some(loaded) => loaded,
+            None => {
+                let _loading = LOADING.lock().unwrap_or_else(|err| err.into_inner());
+                match RUNTIME.get() {
+                    Some(loaded) => loaded,
+                    None => {
+                        let library = load(env_var)?;
+                        RUNTIME.get_or_init(|| library)
+                    }
+                }
+            }
+        };
+        if let Ok(requested) = resolve_library(env_var) {
+            if requested.source.is_some() && requested.path != loaded.path {
+                bail!(
+                    "ONNX Runtime is already loaded from {}; {} cannot be loaded in the same process",
+                    loaded.describe(),
+                    requested.describe()
+                );
+            }
+        }
+        Ok(loaded)
+    }
+
+    fn load(env_var: &str) -> Result<RuntimeLibrary> {
+        let library = resolve_library(env_var)?;
+        ort::init_from(&library.path)
+            .with_context(|| {
+                format!(
+                    "Failed to load ONNX Runtime from {}. Install ONNX Runtime (>= 1.23) or set \
+                     {env_var} to the path of {DEFAULT_LIBRARY}",
+                    library.describe()
+                )
+            })?
+            .with_name("llm-toy")
+            .commit();
+        Ok(library)
+    }
+
+    pub fn loaded() -> Option<RuntimeInfo> {
+        let library = RUNTIME.get()?.clone();
+        let build_info = ort::info().to_string();
+        let version = parse_version(&build_info);
+        let execution_providers = EXECUTION_PROVIDERS
+            .iter()
+            .filter(|name| {
+                provider(name, &SessionOptions::default())
+                    .is_ok_and(|(available, _)| available)
+            })
+            .map(|name| name.to_string())
+            .collect();
+        Some(RuntimeInfo {
+            library,
+            version,
+            build_info,
+            execution_providers,
+        })
+    }
+
+    pub fn execution_provider(
+        name: &str,
+        options: &SessionOptions,
+    ) -> Result<ExecutionProviderDispatch> {
+        provider(name, options).map(|(_, dispatch)| dispatch)
+    }
+
+    // The provider for `name`, and whether the loaded library supports it.
+    fn provider(name: &str, options: &SessionOptions) -> Result<(bool, ExecutionProviderDispatch)> {
+        fn check<E>(provider: E) -> Result<(bool, ExecutionProviderDispatch)>
+        where
+            E: ExecutionProvider + Into<ExecutionProviderDispatch>,
+        {
+            let available = provider.supported_by_platform() && provider.is_available()?;
+            Ok((available, provider.into()))
+        }
+
+        match name {
+            "cpu" => {
+                let mut cpu = ep::CPU::default();
+                if let Some(arena) = options.cpu_arena {
+                    cpu = cpu.with_arena_allocator(arena);
+                }
+                check(cpu)
+            }
+            "cuda" => check(ep::CUDA::default()),
+            "tensorrt" => check(ep::TensorRT::default()),
+            "directml" => check(ep::DirectML::default()),
+            "rocm" => check(ep::ROCm::default()),
+            "openvino" => check(ep::OpenVINO::default()),
+            "coreml" => check(ep::CoreML::default()),
+            "xnnpack" => check(ep::XNNPACK::default()),
+            "vitis" => check(ep::Vitis::default()),
+            _ => bail!(
+                "Unknown execution provider '{name}' (expected one of: {})",
+                EXECUTION_PROVIDERS.join(", ")
+            ),
+        }
+    }
+}
+
+#[cfg(any(feature = "cpu", feature = "ryzen-ai"))]
+pub use ort_runtime::{execution_provider, init, loaded};
+
+/// Without an ORT feature there is never a runtime to report.
+#[cfg(not(any(feature = "cpu", feature = "ryzen-ai")))]
+pub fn loaded() -> Option<RuntimeInfo> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_release_from_build_info() {
+        assert_eq!(
+            parse_version("ORT Build Info: git-branch=rel-1.23.0, git-commit-id=26250ae, build type=Release"),
+            "1.23.0"
+        );
+        // No release branch: the first version number will do.
+        assert_eq!(parse_version("ORT Build Info: git-branch=main, version 1.24.1, build type=Debug"), "1.24.1");
+        assert_eq!(parse_version("git-branch=rel-next, git-commit-id=abc"), "unknown");
+        assert_eq!(parse_version(""), "unknown");
+    }
+
+    #[test]
+    fn configured_libraries_are_found_only_if_they_exist() {
+        let path = std::env::temp_dir().join(format!("llm-toy-ort-{}.so", std::process::id()));
+        let library = RuntimeLibrary {
+            path: path.clone(),
+            source: Some(ORT_DYLIB_PATH.to_string()),
+        };
+        assert_eq!(find_library(&library), None);
+        std::fs::write(&path, b"").unwrap();
+        let found = find_library(&library);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(found, Some(path));
+    }
+}
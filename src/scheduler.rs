@@ -0,0 +1,215 @@
+//! Continuous batching. A `Scheduler` runs one model on a worker thread and
+//! interleaves every request it is given into shared forward passes: new
+//! requests join the batch between decode steps and finished ones leave it
+//! at once, each keeping its own KV cache slot in a `Sequence`. Backends
+//! without step-wise decoding run the requests one at a time instead.
+
+use crate::config::{apply_chat_template, clean_answer};
+use crate::pool::{PendingResponse, Slot};
+use crate::queue::JobQueue;
+use crate::{Error, InferenceRequest, InferenceResponse, Model, ModelConfig, Result, Sequence};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+#[derive(Clone)]
+pub struct Scheduler {
+    handle: Arc<SchedulerHandle>,
+}
+
+// Closes the queue once the last `Scheduler` clone is dropped, so the worker
+// finishes what is queued and exits.
+struct SchedulerHandle {
+    queue: Arc<JobQueue<Job>>,
+    max_batch: usize,
+    /// `Model::request` for an empty prompt, and the chat template the
+    /// prompt goes into, so `generate` does not need the model.
+    defaults: InferenceRequest,
+    chat_template: Option<String>,
+}
+
+impl Drop for SchedulerHandle {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+struct Job {
+    request: InferenceRequest,
+    /// The prompt before templating, for `generate` submissions whose text
+    /// is cleaned like `Model::generate` does.
+    prompt: Option<String>,
+    slot: Arc<Slot>,
+}
+
+// A request in the running batch.
+struct Active {
+    sequence: Sequence,
+    prompt: Option<String>,
+    slot: Arc<Slot>,
+}
+
+impl Scheduler {
+    /// Loads the model and starts the worker. At most `max_batch` requests
+    /// share a forward pass; the rest wait in the queue.
+    pub fn load(config: ModelConfig, max_batch: usize) -> Result<Self> {
+        Ok(Self::new(Model::load(config)?, max_batch))
+    }
+
+    pub fn new(model: Model, max_batch: usize) -> Self {
+        let queue = Arc::new(JobQueue::new());
+        let max_batch = max_batch.max(1);
+        let defaults = model.request("");
+        let chat_template = model.config().chat_template.clone();
+        let worker_queue = Arc::clone(&queue);
+        thread::Builder::new()
+            .name("llm-toy-scheduler".to_string())
+            .spawn(move || worker(&worker_queue, model, max_batch))
+            .expect("failed to spawn the scheduler thread");
+        Self {
+            handle: Arc::new(SchedulerHandle {
+                queue,
+                max_batch,
+                defaults,
+                chat_template,
+            }),
+        }
+    }
+
+    /// Rejects submissions with `Error::QueueFull` while `capacity` requests
+    /// are already waiting to join the batch.
+    pub fn with_queue_capacity(self, capacity: usize) -> Self {
+        self.handle.queue.set_capacity(capacity);
+        self
+    }
+
+    pub fn max_batch(&self) -> usize {
+        self.handle.max_batch
+    }
+
+    /// Requests waiting to join the batch, not counting those in it.
+    pub fn queued(&self) -> usize {
+        self.handle.queue.len()
+    }
+
+    /// Generates a reply to `prompt` like `Model::generate`.
+    pub fn generate(&self, prompt: impl Into<String>) -> PendingResponse {
+        let prompt = prompt.into();
+        let request = InferenceRequest {
+            prompt: match self.handle.chat_template.as_deref() {
+                Some(template) => apply_chat_template(template, &prompt),
+                None => prompt.clone(),
+            },
+            ..self.handle.defaults.clone()
+        };
+        self.submit(request, Some(prompt))
+    }
+
+    /// Runs `request` on the backend as is.
+    pub fn run(&self, request: InferenceRequest) -> PendingResponse {
+        self.submit(request, None)
+    }
+
+    /// Stops accepting requests. Queued and running requests still finish;
+    /// later submissions fail with `Error::PoolClosed`.
+    pub fn shutdown(&self) {
+        self.handle.queue.close();
+    }
+
+    fn submit(&self, request: InferenceRequest, prompt: Option<String>) -> PendingResponse {
+        self.handle.queue.submit(|slot| Job {
+            request,
+            prompt,
+            slot,
+        })
+    }
+}
+
+// Requests that can join `batch`, in order, stopping at the first that
+// cannot so requests are not reordered. Blocks only while nothing runs.
+fn admit(queue: &JobQueue<Job>, batch: &[Active], room: usize) -> Option<Vec<Job>> {
+    queue.take(batch.is_empty(), room, |job, taken| match (batch.first(), taken.first()) {
+        (Some(active), _) => active.sequence.can_batch_with(&job.request),
+        (None, Some(first)) => first.request.can_batch_with(&job.request),
+        (None, None) => true,
+    })
+}
+
+fn worker(queue: &JobQueue<Job>, mut model: Model, max_batch: usize) {
+    if !model.backend().supports_steps() {
+        return serial_worker(queue, model);
+    }
+    let name = model.backend().name().to_string();
+    let backend = model.backend_mut();
+    let mut batch: Vec<Active> = Vec::new();
+    while let Some(jobs) = admit(queue, &batch, max_batch - batch.len()) {
+        for job in jobs {
+            match guarded(&name, || backend.start_sequence(&job.request)) {
+                Ok(sequence) => batch.push(Active {
+                    sequence,
+                    prompt: job.prompt,
+                    slot: job.slot,
+                }),
+                Err(err) => job.slot.fill(Err(err)),
+            }
+        }
+
+        let mut sequences: Vec<&mut Sequence> = batch
+            .iter_mut()
+            .map(|active| &mut active.sequence)
+            .filter(|sequence| !sequence.is_done())
+            .collect();
+        let step = if sequences.is_empty() {
+            Ok(())
+        } else {
+            guarded(&name, || backend.step(&mut sequences))
+        };
+        if let Err(err) = step {
+            // The batch state is unknown after a failed pass, so every
+            // request in it gets the error.
+            let message = format!("{err:#}");
+            for active in batch.drain(..) {
+                active.slot.fill(Err(Error::BatchFailed {
+                    message: message.clone(),
+                }));
+            }
+            continue;
+        }
+
+        let (done, running): (Vec<_>, Vec<_>) =
+            batch.drain(..).partition(|active| active.sequence.is_done());
+        batch = running;
+        for active in done {
+            let result = guarded(&name, || backend.finish_sequence(active.sequence));
+            active.slot.fill(clean(result, active.prompt.as_deref()));
+        }
+    }
+}
+
+// Backends without step-wise decoding: one request at a time, like a
+// single-worker `ModelPool`.
+fn serial_worker(queue: &JobQueue<Job>, mut model: Model) {
+    let name = model.backend().name().to_string();
+    let backend = model.backend_mut();
+    while let Some(job) = queue.next() {
+        let result = guarded(&name, || backend.run(&job.request));
+        job.slot.fill(clean(result, job.prompt.as_deref()));
+    }
+}
+
+fn guarded<T>(name: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| {
+            Err(Error::BackendPanicked {
+                backend: name.to_string(),
+            })
+        })
+}
+
+fn clean(result: Result<InferenceResponse>, prompt: Option<&str>) -> Result<InferenceResponse> {
+    let mut response = result?;
+    if let Some(prompt) = prompt {
+        response.text = clean_answer(prompt, &response.text);
+    }
+    Ok(response)
+}
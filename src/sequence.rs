@@ -0,0 +1,194 @@
+//! Per-request decoding state for step-wise generation. A `Sequence` is the
+//! slot a request occupies while `NpuBackend::step` advances it alongside
+//! others: its tokens, sampler state, stop conditions and, for models that
+//! export past key/values, its own KV cache.
+
+// Only the cpu backend decodes step by step so far.
+#![cfg_attr(not(feature = "cpu"), allow(dead_code))]
+
+use crate::{FinishReason, GenerationTimings, InferenceRequest};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::time::Instant;
+
+pub struct Sequence {
+    pub(crate) request: InferenceRequest,
+    pub(crate) ids: Vec<i64>,
+    pub(crate) prompt_len: usize,
+    pub(crate) context_length: Option<usize>,
+    pub(crate) rng: StdRng,
+    pub(crate) done: bool,
+    pub(crate) finish_reason: FinishReason,
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) timings: GenerationTimings,
+    pub(crate) steps: usize,
+    /// Shape and first value of the last output, reported when there is no
+    /// tokenizer to decode with.
+    pub(crate) last_output: Option<(String, f32)>,
+    pub(crate) cache: Option<KvCache>,
+    /// Prompt tokens restored from a prefix cache rather than computed.
+    pub(crate) cached_tokens: usize,
+}
+
+impl Sequence {
+    /// Starts `request` from the prompt tokens `ids`.
+    pub fn new(request: &InferenceRequest, ids: Vec<i64>, context_length: Option<usize>) -> Self {
+        let rng = match request.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            deadline: request.deadline_from(Instant::now()),
+            done: request.max_tokens == 0,
+            request: request.clone(),
+            prompt_len: ids.len(),
+            ids,
+            context_length,
+            rng,
+            finish_reason: FinishReason::Length,
+            timings: GenerationTimings::default(),
+            steps: 0,
+            last_output: None,
+            cache: None,
+            cached_tokens: 0,
+        }
+    }
+
+    pub fn request(&self) -> &InferenceRequest {
+        &self.request
+    }
+
+    /// Prompt and generated tokens so far.
+    pub fn ids(&self) -> &[i64] {
+        &self.ids
+    }
+
+    pub fn prompt_len(&self) -> usize {
+        self.prompt_len
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn finish_reason(&self) -> FinishReason {
+        self.finish_reason
+    }
+
+    /// Tokens whose attention state is cached, so the next step only has to
+    /// feed the ones after them.
+    pub fn cached_len(&self) -> usize {
+        self.cache.as_ref().map_or(0, |cache| cache.len)
+    }
+
+    /// Starts from `cache`, the attention state of a prefix of the prompt,
+    /// so the first step only feeds the tokens after it.
+    pub fn with_cache(mut self, cache: KvCache) -> Self {
+        if cache.len < self.prompt_len {
+            self.cached_tokens = cache.len;
+            self.cache = Some(cache);
+        }
+        self
+    }
+
+    /// Whether `request` can share forward passes with this sequence.
+    pub fn can_batch_with(&self, request: &InferenceRequest) -> bool {
+        self.request.can_batch_with(request)
+    }
+
+    /// Ends the sequence if its request was cancelled or ran out of time.
+    pub(crate) fn check_interrupted(&mut self, now: Instant) {
+        if self.done {
+            return;
+        }
+        if self.request.is_cancelled() {
+            self.done = true;
+            self.finish_reason = FinishReason::Cancelled;
+        } else if self.deadline.is_some_and(|deadline| now >= deadline) {
+            self.done = true;
+            self.finish_reason = FinishReason::Timeout;
+        }
+    }
+
+    /// Counts a forward pass: the first one is the prefill.
+    pub(crate) fn record_step(&mut self, elapsed_ms: f64) {
+        if self.steps == 0 {
+            self.timings.prefill_ms = elapsed_ms;
+        } else {
+            self.timings.decode_ms += elapsed_ms;
+        }
+        self.steps += 1;
+    }
+}
+
+/// Attention state for the first `len` tokens of a sequence: one tensor per
+/// past key/value input of the model, in the order the model lists them.
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    pub(crate) len: usize,
+    pub(crate) tensors: Vec<KvTensor>,
+}
+
+/// One layer's keys or values, laid out `[heads, len, head_dim]`.
+#[derive(Debug, Clone)]
+pub(crate) struct KvTensor {
+    pub(crate) heads: usize,
+    pub(crate) head_dim: usize,
+    pub(crate) data: Vec<f32>,
+}
+
+impl KvCache {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.tensors
+            .iter()
+            .map(|tensor| tensor.data.len() * std::mem::size_of::<f32>())
+            .sum()
+    }
+
+    /// The cache for the first `len` tokens only.
+    pub fn prefix(&self, len: usize) -> KvCache {
+        let len = len.min(self.len);
+        if len == self.len {
+            return self.clone();
+        }
+        let tensors = self
+            .tensors
+            .iter()
+            .map(|tensor| {
+                let head_len = self.len * tensor.head_dim;
+                KvTensor {
+                    heads: tensor.heads,
+                    head_dim: tensor.head_dim,
+                    data: (0..tensor.heads)
+                        .flat_map(|head| {
+                            let start = head * head_len;
+                            &tensor.data[start..start + len * tensor.head_dim]
+                        })
+                        .copied()
+                        .collect(),
+                }
+            })
+            .collect();
+        KvCache { len, tensors }
+    }
+}
+
+impl KvTensor {
+    /// Appends this tensor to `out` with each head left-padded with zeros
+    /// to `padded_len` positions, the layout of one row of a batched input.
+    pub(crate) fn write_padded(&self, len: usize, padded_len: usize, out: &mut Vec<f32>) {
+        let head_len = len * self.head_dim;
+        for head in 0..self.heads {
+            out.extend(std::iter::repeat_n(0.0, (padded_len - len) * self.head_dim));
+            out.extend_from_slice(&self.data[head * head_len..(head + 1) * head_len]);
+        }
+    }
+}
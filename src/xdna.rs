@@ -0,0 +1,407 @@
+//! Detection of AMD XDNA NPUs through the Linux `amdxdna` driver. Every path
+//! the probe reads comes from `ProbePaths`, so it can run against a fake
+//! sysfs tree as easily as the real one.
+
+use crate::Availability;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DRIVER_NAME: &str = "amdxdna";
+const AMD_VENDOR_ID: &str = "0x1022";
+/// PCI device ids of the NPU generations `amdxdna` supports.
+const NPU_DEVICE_IDS: [&str; 2] = ["0x1502", "0x17f0"];
+
+#[derive(Debug, Clone)]
+pub struct ProbePaths {
+    /// Directory holding the `accelN` device nodes.
+    pub dev_accel: PathBuf,
+    pub sysfs: PathBuf,
+    pub firmware: PathBuf,
+}
+
+impl Default for ProbePaths {
+    fn default() -> Self {
+        Self::with_root(Path::new("/"))
+    }
+}
+
+impl ProbePaths {
+    /// Paths under `root` laid out like a real system: `dev/accel`, `sys`
+    /// and `lib/firmware/amdnpu`.
+    pub fn with_root(root: &Path) -> Self {
+        Self {
+            dev_accel: root.join("dev/accel"),
+            sysfs: root.join("sys"),
+            firmware: root.join("lib/firmware/amdnpu"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct XdnaDevice {
+    pub node: PathBuf,
+    pub pci_address: Option<String>,
+    pub device_id: Option<String>,
+    pub driver: Option<String>,
+    pub name: Option<String>,
+    pub firmware_version: Option<String>,
+    pub accessible: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct XdnaReport {
+    pub available: bool,
+    pub driver_loaded: bool,
+    pub driver_version: Option<String>,
+    pub firmware_installed: bool,
+    pub devices: Vec<XdnaDevice>,
+    /// NPUs on the PCI bus with no `amdxdna` device node, by PCI address.
+    pub unbound_npus: Vec<String>,
+    /// Why the NPU cannot be used; empty when `available`.
+    pub reasons: Vec<String>,
+}
+
+impl XdnaReport {
+    pub fn availability(&self) -> Availability {
+        let mut details = Vec::new();
+        match (&self.driver_version, self.driver_loaded) {
+            (Some(version), _) => details.push(format!("{DRIVER_NAME} driver {version}")),
+            (None, true) => details.push(format!("{DRIVER_NAME} driver loaded")),
+            (None, false) => {}
+        }
+        for device in &self.devices {
+            let mut line = device.node.display().to_string();
+            if let Some(name) = &device.name {
+                line.push_str(&format!(" {name}"));
+            }
+            if let Some(address) = &device.pci_address {
+                line.push_str(&format!(" at {address}"));
+            }
+            if let Some(version) = &device.firmware_version {
+                line.push_str(&format!(", firmware {version}"));
+            }
+            details.push(line);
+        }
+        if self.driver_loaded && !self.firmware_installed {
+            details.push("no firmware directory found".to_string());
+        }
+        Availability {
+            available: self.available,
+            reasons: self.reasons.clone(),
+            details,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn probe(paths: &ProbePaths) -> XdnaReport {
+    let module = paths.sysfs.join("module").join(DRIVER_NAME);
+    let driver_loaded =
+        module.exists() || paths.sysfs.join("bus/pci/drivers").join(DRIVER_NAME).exists();
+    let driver_version = read_attribute(&module.join("version"));
+    let firmware_installed = paths.firmware.is_dir();
+
+    let mut devices = Vec::new();
+    for node in accel_nodes(&paths.dev_accel) {
+        let Some(node_name) = node.file_name() else {
+            continue;
+        };
+        let device_dir = paths.sysfs.join("class/accel").join(node_name).join("device");
+        let driver = fs::read_link(device_dir.join("driver"))
+            .ok()
+            .and_then(|target| target.file_name().map(|name| name.to_string_lossy().to_string()));
+        devices.push(XdnaDevice {
+            pci_address: fs::canonicalize(&device_dir)
+                .ok()
+                .and_then(|dir| dir.file_name().map(|name| name.to_string_lossy().to_string())),
+            device_id: read_attribute(&device_dir.join("device")),
+            name: read_attribute(&device_dir.join("vbnv")),
+            firmware_version: read_attribute(&device_dir.join("fw_version")),
+            accessible: fs::OpenOptions::new().read(true).write(true).open(&node).is_ok(),
+            node,
+            driver,
+        });
+    }
+    let npu_devices: Vec<&XdnaDevice> = devices
+        .iter()
+        .filter(|device| device.driver.as_deref() == Some(DRIVER_NAME))
+        .collect();
+
+    let bound: Vec<&str> = npu_devices
+        .iter()
+        .filter_map(|device| device.pci_address.as_deref())
+        .collect();
+    let unbound_npus: Vec<String> = pci_npus(&paths.sysfs)
+        .into_iter()
+        .filter(|address| !bound.contains(&address.as_str()))
+        .collect();
+
+    let mut reasons = Vec::new();
+    if !driver_loaded {
+        reasons.push(format!(
+            "the {DRIVER_NAME} kernel driver is not loaded (no {})",
+            module.display()
+        ));
+    }
+    if !unbound_npus.is_empty() && npu_devices.is_empty() {
+        reasons.push(format!(
+            "found an AMD NPU at {} but no {DRIVER_NAME} device node for it; \
+             it needs Linux 6.14+ or the out-of-tree {DRIVER_NAME} driver",
+            unbound_npus.join(", ")
+        ));
+    }
+    if npu_devices.is_empty() {
+        if devices.is_empty() {
+            reasons.push(format!("no accelerator device nodes in {}", paths.dev_accel.display()));
+        } else {
+            reasons.push(format!(
+                "none of the accelerator devices in {} use the {DRIVER_NAME} driver",
+                paths.dev_accel.display()
+            ));
+        }
+    } else if !npu_devices.iter().any(|device| device.accessible) {
+        reasons.push(format!(
+            "{} cannot be opened for reading and writing; add the user to the group that owns it \
+             (usually 'render')",
+            npu_devices[0].node.display()
+        ));
+    }
+
+    XdnaReport {
+        available: reasons.is_empty(),
+        driver_loaded,
+        driver_version,
+        firmware_installed,
+        devices,
+        unbound_npus,
+        reasons,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn probe(_paths: &ProbePaths) -> XdnaReport {
+    XdnaReport {
+        available: false,
+        driver_loaded: false,
+        driver_version: None,
+        firmware_installed: false,
+        devices: Vec::new(),
+        unbound_npus: Vec::new(),
+        reasons: vec![format!(
+            "XDNA probing uses the Linux {DRIVER_NAME} driver and is not supported on {}",
+            std::env::consts::OS
+        )],
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn accel_nodes(dir: &Path) -> Vec<PathBuf> {
+    let mut nodes: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("accel"))
+        })
+        .collect();
+    nodes.sort();
+    nodes
+}
+
+// PCI addresses of AMD NPUs, whether or not a driver has claimed them.
+#[cfg(target_os = "linux")]
+fn pci_npus(sysfs: &Path) -> Vec<String> {
+    let mut addresses: Vec<String> = fs::read_dir(sysfs.join("bus/pci/devices"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let path = entry.path();
+            read_attribute(&path.join("vendor")).as_deref() == Some(AMD_VENDOR_ID)
+                && read_attribute(&path.join("device"))
+                    .is_some_and(|id| NPU_DEVICE_IDS.contains(&id.as_str()))
+        })
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    addresses.sort();
+    addresses
+}
+
+#[cfg(target_os = "linux")]
+fn read_attribute(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PCI_ADDRESS: &str = "0000:c5:00.1";
+
+    /// A scratch root directory, removed when dropped.
+    struct FakeRoot(PathBuf);
+
+    impl FakeRoot {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let root = std::env::temp_dir().join(format!(
+                "llm-toy-xdna-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::SeqCst)
+            ));
+            fs::create_dir_all(&root).unwrap();
+            Self(root)
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        fn mkdir(&self, path: &str) {
+            fs::create_dir_all(self.0.join(path)).unwrap();
+        }
+
+        fn link(&self, link: &str, target: &str) {
+            let link = self.0.join(link);
+            fs::create_dir_all(link.parent().unwrap()).unwrap();
+            symlink(self.0.join(target), link).unwrap();
+        }
+
+        fn driver(&self) {
+            self.write("sys/module/amdxdna/version", "0.6\n");
+            self.mkdir("sys/bus/pci/drivers/amdxdna");
+        }
+
+        fn pci_npu(&self) {
+            let device = format!("sys/devices/pci0000:00/{PCI_ADDRESS}");
+            self.write(&format!("{device}/vendor"), "0x1022\n");
+            self.write(&format!("{device}/device"), "0x17f0\n");
+            self.write(&format!("{device}/vbnv"), "RyzenAI-npu5\n");
+            self.write(&format!("{device}/fw_version"), "1.5.2.380\n");
+            self.link(&format!("sys/bus/pci/devices/{PCI_ADDRESS}"), &device);
+        }
+
+        /// `accel0` backed by the PCI NPU and bound to `driver`.
+        fn accel_node(&self, driver: &str) {
+            self.write("dev/accel/accel0", "");
+            self.mkdir(&format!("sys/bus/pci/drivers/{driver}"));
+            let device = format!("sys/devices/pci0000:00/{PCI_ADDRESS}");
+            self.link(&format!("{device}/driver"), &format!("sys/bus/pci/drivers/{driver}"));
+            self.link("sys/class/accel/accel0/device", &device);
+        }
+
+        fn probe(&self) -> XdnaReport {
+            probe(&ProbePaths::with_root(&self.0))
+        }
+    }
+
+    impl Drop for FakeRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn has_reason(report: &XdnaReport, text: &str) -> bool {
+        report.reasons.iter().any(|reason| reason.contains(text))
+    }
+
+    #[test]
+    fn available_with_driver_device_and_firmware() {
+        let root = FakeRoot::new();
+        root.driver();
+        root.pci_npu();
+        root.accel_node(DRIVER_NAME);
+        root.mkdir("lib/firmware/amdnpu");
+
+        let report = root.probe();
+        assert!(report.available, "{:?}", report.reasons);
+        assert_eq!(report.driver_version.as_deref(), Some("0.6"));
+        assert!(report.firmware_installed);
+        assert!(report.unbound_npus.is_empty());
+        let device = &report.devices[0];
+        assert_eq!(device.pci_address.as_deref(), Some(PCI_ADDRESS));
+        assert_eq!(device.driver.as_deref(), Some(DRIVER_NAME));
+        assert_eq!(device.name.as_deref(), Some("RyzenAI-npu5"));
+        assert_eq!(device.firmware_version.as_deref(), Some("1.5.2.380"));
+        assert!(device.accessible);
+    }
+
+    #[test]
+    fn empty_system_has_no_driver_and_no_devices() {
+        let root = FakeRoot::new();
+        let report = root.probe();
+        assert!(!report.available);
+        assert!(has_reason(&report, "kernel driver is not loaded"));
+        assert!(has_reason(&report, "no accelerator device nodes"));
+    }
+
+    #[test]
+    fn npu_without_device_node_is_reported_unbound() {
+        let root = FakeRoot::new();
+        root.driver();
+        root.pci_npu();
+
+        let report = root.probe();
+        assert!(!report.available);
+        assert_eq!(report.unbound_npus, vec![PCI_ADDRESS.to_string()]);
+        assert!(has_reason(&report, &format!("found an AMD NPU at {PCI_ADDRESS}")));
+        assert!(!has_reason(&report, "kernel driver is not loaded"));
+    }
+
+    #[test]
+    fn accelerator_on_another_driver_is_not_used() {
+        let root = FakeRoot::new();
+        root.driver();
+        root.pci_npu();
+        root.accel_node("other");
+
+        let report = root.probe();
+        assert!(!report.available);
+        assert!(has_reason(&report, "use the amdxdna driver"));
+    }
+
+    #[test]
+    fn device_node_that_cannot_be_opened() {
+        let root = FakeRoot::new();
+        root.driver();
+        root.pci_npu();
+        root.accel_node(DRIVER_NAME);
+        // A directory cannot be opened for writing, even by root, which
+        // would ignore file permissions.
+        fs::remove_file(root.0.join("dev/accel/accel0")).unwrap();
+        root.mkdir("dev/accel/accel0");
+
+        let report = root.probe();
+        assert!(!report.available);
+        assert!(!report.devices[0].accessible);
+        assert!(has_reason(&report, "cannot be opened for reading and writing"));
+    }
+
+    #[test]
+    fn missing_firmware_is_a_detail_not_a_reason() {
+        let root = FakeRoot::new();
+        root.driver();
+        root.pci_npu();
+        root.accel_node(DRIVER_NAME);
+
+        let report = root.probe();
+        assert!(report.available);
+        assert!(!report.firmware_installed);
+        let availability = report.availability();
+        assert!(availability
+            .details
+            .iter()
+            .any(|detail| detail == "no firmware directory found"));
+    }
+}